@@ -71,7 +71,7 @@ where
             Some(Ok(event)) => {
                 debug!("[{}] EVENT {:?}", is_initiator, event);
                 match event {
-                    Event::Handshake(_) => {
+                    Event::Handshake(_, _) => {
                         protocol.open(key.clone()).await?;
                     }
                     Event::DiscoveryKey(_dkey) => {}
@@ -155,7 +155,7 @@ async fn on_channel_init(i: u64, mut channel: Channel) -> Result<u64> {
 fn msg_data(index: u64, value: Vec<u8>) -> Message {
     Message::Data(Data {
         index,
-        value: Some(value),
+        value: Some(value.into()),
         nodes: vec![],
         signature: None,
     })
@@ -1,5 +1,6 @@
 use async_std::net::{Shutdown, TcpListener, TcpStream};
 use async_std::task;
+use bytes::Bytes;
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use futures::future::Either;
 use futures::io::{AsyncRead, AsyncWrite};
@@ -7,6 +8,8 @@ use futures::stream::{FuturesUnordered, StreamExt};
 use hypercore_protocol::schema::*;
 use hypercore_protocol::{Channel, Event, Message, ProtocolBuilder};
 use log::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
 const PORT: usize = 11011;
@@ -14,6 +17,30 @@ const SIZE: u64 = 1000;
 const COUNT: u64 = 200;
 const CLIENTS: usize = 1;
 
+/// Counts the allocations of the client and the server.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
 fn bench_throughput(c: &mut Criterion) {
     env_logger::from_env(env_logger::Env::default().default_filter_or("error")).init();
     let address = format!("localhost:{}", PORT);
@@ -23,6 +50,9 @@ fn bench_throughput(c: &mut Criterion) {
 
     // let _server = task::block_on(start_server(&address));
 
+    let mut runs = 0;
+    let mut allocations = 0;
+
     group.sample_size(10);
     group.throughput(Throughput::Bytes(
         data.len() as u64 * COUNT * CLIENTS as u64,
@@ -41,7 +71,8 @@ fn bench_throughput(c: &mut Criterion) {
                 (server, streams)
             },
             |(server, streams)| {
-                task::block_on(async move {
+                let start = ALLOCATIONS.load(Ordering::Relaxed);
+                let result = task::block_on(async move {
                     let mut futures: FuturesUnordered<_> = streams
                         .into_iter()
                         .map(|s| async move {
@@ -51,12 +82,20 @@ fn bench_throughput(c: &mut Criterion) {
                         .collect();
                     while let Some(_res) = futures.next().await {}
                     server.send(())
-                })
+                });
+                allocations += ALLOCATIONS.load(Ordering::Relaxed) - start;
+                runs += 1;
+                result
             },
         );
     });
 
     group.finish();
+    println!(
+        "{} allocations per run, {:.1} per block",
+        allocations / runs,
+        allocations as f64 / (runs * COUNT as usize * CLIENTS) as f64
+    );
 }
 
 criterion_group!(server_benches, bench_throughput);
@@ -88,7 +127,7 @@ async fn start_server(address: &str) -> futures::channel::oneshot::Sender<()> {
     kill_tx
 }
 
-async fn onconnection<R, W>(reader: R, writer: W, is_initiator: bool) -> (R, W)
+async fn onconnection<R, W>(reader: R, writer: W, is_initiator: bool)
 where
     R: AsyncRead + Send + Unpin + 'static,
     W: AsyncWrite + Send + Unpin + 'static,
@@ -100,20 +139,17 @@ where
     while let Some(Ok(event)) = protocol.next().await {
         // eprintln!("RECV EVENT [{}] {:?}", protocol.is_initiator(), event);
         match event {
-            Event::Handshake(_) => {
+            Event::Handshake(_, _) => {
                 protocol.open(key.clone()).await.unwrap();
             }
             Event::DiscoveryKey(_) => {}
             Event::Channel(channel) => {
                 task::spawn(onchannel(channel, is_initiator));
             }
-            Event::Close(_dkey) => {
-                return protocol.release();
-            }
+            Event::Close(_dkey) => return,
             _ => {}
         }
     }
-    protocol.release()
 }
 
 async fn onchannel(mut channel: Channel, is_initiator: bool) {
@@ -139,7 +175,8 @@ async fn channel_server(channel: &mut Channel) {
 }
 
 async fn channel_client(channel: &mut Channel) {
-    let data = vec![0u8; SIZE as usize];
+    // Clones of the block share the same buffer.
+    let data = Bytes::from(vec![0u8; SIZE as usize]);
     let start = Instant::now();
     let message = Message::Data(Data {
         index: 0,
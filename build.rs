@@ -1,3 +1,7 @@
 fn main() {
-    prost_build::compile_protos(&["src/schema.proto"], &["src/"]).unwrap();
+    prost_build::Config::new()
+        // Block values are decoded as slices of the read buffer, without copying.
+        .bytes(&[".hypercore.schema.Data.value"])
+        .compile_protos(&["src/schema.proto"], &["src/"])
+        .unwrap();
 }
@@ -2,6 +2,7 @@ use anyhow::Result;
 use async_std::net::TcpStream;
 use async_std::sync::Arc;
 use async_std::task;
+use bytes::Bytes;
 use futures_lite::stream::StreamExt;
 use log::*;
 use std::collections::HashMap;
@@ -67,7 +68,7 @@ async fn onconnection(
         let event = event?;
         debug!("EVENT {:?}", event);
         match event {
-            Event::Handshake(_) => {
+            Event::Handshake(_, _) => {
                 if is_initiator {
                     for feed in feedstore.feeds.values() {
                         protocol.open(feed.key.clone()).await?;
@@ -189,7 +190,7 @@ async fn onmessage(_feed: &Feed, state: &mut FeedState, channel: &mut Channel, m
             channel
                 .send(Message::Data(Data {
                     index: msg.index,
-                    value: Some("Hello world".as_bytes().to_vec()),
+                    value: Some(Bytes::from_static(b"Hello world")),
                     nodes: vec![],
                     signature: None,
                 }))
@@ -205,7 +206,7 @@ async fn onmessage(_feed: &Feed, state: &mut FeedState, channel: &mut Channel, m
             );
 
             if let Some(value) = msg.value {
-                eprintln!("{} {}", msg.index, String::from_utf8(value).unwrap());
+                eprintln!("{} {}", msg.index, String::from_utf8_lossy(&value));
                 // let mut stdout = io::stdout();
                 // stdout.write_all(&value).await.unwrap();
                 // stdout.flush().await.unwrap();
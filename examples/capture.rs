@@ -0,0 +1,18 @@
+use anyhow::Result;
+use hypercore_protocol::capture::read_capture;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+
+/// Print the messages of a capture that was recorded with
+/// `ProtocolBuilder::set_capture`.
+fn main() -> Result<()> {
+    let path = env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("usage: capture <file>"))?;
+    let frames = read_capture(BufReader::new(File::open(path)?))?;
+    for frame in frames.iter() {
+        println!("{}", frame);
+    }
+    Ok(())
+}
@@ -2,6 +2,7 @@ use anyhow::Result;
 use async_std::net::TcpStream;
 use async_std::sync::{Arc, Mutex};
 use async_std::task;
+use async_trait::async_trait;
 use futures_lite::stream::StreamExt;
 use hypercore::{Feed, Node, NodeTrait, Proof, PublicKey, Signature, Storage};
 use log::*;
@@ -12,8 +13,8 @@ use std::convert::{TryFrom, TryInto};
 use std::env;
 use std::fmt::Debug;
 
-use hypercore_protocol::schema::*;
-use hypercore_protocol::{discovery_key, Channel, Event, Message, ProtocolBuilder};
+use hypercore_protocol::replication::{self, ReplicationOptions, Replicator};
+use hypercore_protocol::{discovery_key, Channel, Error, Event, ProtocolBuilder};
 
 mod util;
 use util::{tcp_client, tcp_server};
@@ -89,7 +90,7 @@ where
         let event = event?;
         debug!("protocol event {:?}", event);
         match event {
-            Event::Handshake(_) => {
+            Event::Handshake(_, _) => {
                 if is_initiator {
                     for feed in feedstore.feeds.values() {
                         protocol.open(feed.key().clone()).await?;
@@ -171,160 +172,96 @@ where
         &self.key
     }
 
-    pub fn onpeer(&self, mut channel: Channel) {
-        let mut state = PeerState::default();
-        let mut feed = self.feed.clone();
+    pub fn onpeer(&self, channel: Channel) {
+        let options = ReplicationOptions {
+            live: true,
+            ..Default::default()
+        };
+        let feed = FeedWrapper {
+            discovery_key: self.discovery_key,
+            key: self.key,
+            feed: self.feed.clone(),
+        };
+        let replicator = Replicator::with_options(channel, feed, options);
         task::spawn(async move {
-            let msg = Want {
-                start: 0,
-                length: None,
-            };
-            channel.send(Message::Want(msg)).await.unwrap();
-            while let Some(message) = channel.next().await {
-                let result = onmessage(&mut feed, &mut state, &mut channel, message).await;
-                if let Err(e) = result {
-                    error!("protocol error: {}", e);
-                    break;
-                }
+            if let Err(e) = replicator.run().await {
+                error!("replication error: {}", e);
             }
         });
     }
 }
 
-/// A PeerState stores the head seq of the remote.
-/// This would have a bitfield to support sparse sync in the actual impl.
-#[derive(Debug)]
-struct PeerState {
-    remote_head: Option<u64>,
-}
-impl Default for PeerState {
-    fn default() -> Self {
-        PeerState { remote_head: None }
-    }
+fn feed_error(e: impl Into<anyhow::Error>) -> Error {
+    let e: anyhow::Error = e.into();
+    Error::Feed(e.into())
 }
 
-async fn onmessage<T>(
-    feed: &mut Arc<Mutex<Feed<T>>>,
-    state: &mut PeerState,
-    channel: &mut Channel,
-    message: Message,
-) -> Result<()>
+#[async_trait]
+impl<T> replication::Feed for FeedWrapper<T>
 where
     T: RandomAccess<Error = Box<dyn std::error::Error + Send + Sync>> + Debug + Send,
 {
-    match message {
-        Message::Open(_) => {
-            let msg = Want {
-                start: 0,
-                length: None,
-            };
-            channel.send(Message::Want(msg)).await?;
-        }
-        Message::Want(msg) => {
-            let mut feed = feed.lock().await;
-            if feed.has(msg.start) {
-                channel
-                    .have(Have {
-                        start: msg.start,
-                        ack: None,
-                        bitfield: None,
-                        length: None,
-                    })
-                    .await?;
-            }
-        }
-        Message::Have(msg) => {
-            if state.remote_head == None {
-                state.remote_head = Some(msg.start);
-                let msg = Request {
-                    index: 0,
-                    bytes: None,
-                    hash: None,
-                    nodes: None,
-                };
-                channel.send(Message::Request(msg)).await?;
-            } else if let Some(remote_head) = state.remote_head {
-                if remote_head < msg.start {
-                    state.remote_head = Some(msg.start)
-                }
-            }
-        }
-        Message::Request(request) => {
-            let mut feed = feed.lock().await;
-            let index = request.index;
-            let value = feed.get(index).await?;
-            let proof = feed.proof(index, false).await?;
-            let nodes = proof
-                .nodes
-                .iter()
-                .map(|node| data::Node {
-                    index: NodeTrait::index(node),
-                    hash: NodeTrait::hash(node).to_vec(),
-                    size: NodeTrait::len(node),
-                })
-                .collect();
-            let message = Data {
-                index,
-                value: value.clone(),
-                nodes,
-                signature: proof.signature.map(|s| s.to_bytes().to_vec()),
-            };
-            channel.data(message).await?;
-        }
-        Message::Data(msg) => {
-            let mut feed = feed.lock().await;
-            let value: Option<&[u8]> = match msg.value.as_ref() {
-                None => None,
-                Some(value) => {
-                    // eprintln!(
-                    //     "recv idx {}: {:?}",
-                    //     msg.index,
-                    //     String::from_utf8(value.clone()).unwrap()
-                    // );
-                    Some(value)
-                }
-            };
+    async fn len(&mut self) -> u64 {
+        self.feed.lock().await.len()
+    }
 
-            let signature = match msg.signature {
-                Some(bytes) => Some(Signature::try_from(&bytes[..])?),
-                None => None,
-            };
-            let nodes = msg
-                .nodes
-                .iter()
-                .map(|n| Node::new(n.index, n.hash.clone(), n.size))
-                .collect();
-            let proof = Proof {
-                index: msg.index,
-                nodes,
-                signature,
-            };
+    async fn has(&mut self, index: u64) -> bool {
+        self.feed.lock().await.has(index)
+    }
 
-            feed.put(msg.index, value, proof.clone()).await?;
+    async fn get(&mut self, index: u64) -> hypercore_protocol::Result<Option<Vec<u8>>> {
+        self.feed.lock().await.get(index).await.map_err(feed_error)
+    }
 
-            let i = msg.index;
-            let node = feed.get(i).await?;
-            if let Some(value) = node {
-                println!("feed idx {}: {:?}", i, String::from_utf8(value).unwrap());
-            } else {
-                println!("feed idx {}: {:?}", i, "NONE");
-            }
+    async fn proof(&mut self, index: u64) -> hypercore_protocol::Result<replication::Proof> {
+        let proof = self
+            .feed
+            .lock()
+            .await
+            .proof(index, false)
+            .await
+            .map_err(feed_error)?;
+        let nodes = proof
+            .nodes
+            .iter()
+            .map(|node| replication::Node {
+                index: NodeTrait::index(node),
+                hash: NodeTrait::hash(node).to_vec(),
+                size: NodeTrait::len(node),
+            })
+            .collect();
+        Ok(replication::Proof {
+            nodes,
+            signature: proof.signature.map(|s| s.to_bytes().to_vec()),
+        })
+    }
 
-            let next = msg.index + 1;
-            if let Some(remote_head) = state.remote_head {
-                if remote_head >= next {
-                    // Request next data block.
-                    let msg = Request {
-                        index: next,
-                        bytes: None,
-                        hash: None,
-                        nodes: None,
-                    };
-                    channel.send(Message::Request(msg)).await?;
-                }
-            };
+    async fn put(
+        &mut self,
+        index: u64,
+        data: Option<&[u8]>,
+        proof: replication::Proof,
+    ) -> hypercore_protocol::Result<()> {
+        let signature = match proof.signature {
+            Some(bytes) => Some(Signature::try_from(&bytes[..]).map_err(feed_error)?),
+            None => None,
+        };
+        let nodes = proof
+            .nodes
+            .iter()
+            .map(|n| Node::new(n.index, n.hash.clone(), n.size))
+            .collect();
+        let proof = Proof {
+            index,
+            nodes,
+            signature,
+        };
+        let mut feed = self.feed.lock().await;
+        feed.put(index, data, proof).await.map_err(feed_error)?;
+        match data {
+            Some(value) => println!("feed idx {}: {:?}", index, String::from_utf8_lossy(value)),
+            None => println!("feed idx {}: {:?}", index, "NONE"),
         }
-        _ => {}
-    };
-    Ok(())
+        Ok(())
+    }
 }
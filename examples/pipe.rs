@@ -90,7 +90,7 @@ where
             Ok(event) => {
                 debug!("[init {}] EVENT {:?}", is_initiator, event);
                 match event {
-                    Event::Handshake(_) => {
+                    Event::Handshake(_, _) => {
                         protocol.open(key.clone()).await?;
                     }
                     Event::DiscoveryKey(_dkey) => {}
@@ -174,7 +174,7 @@ async fn on_channel_init(config: Config, i: u64, mut channel: Channel) -> Result
 fn msg_data(index: u64, value: Vec<u8>) -> Message {
     Message::Data(Data {
         index,
-        value: Some(value),
+        value: Some(value.into()),
         nodes: vec![],
         signature: None,
     })
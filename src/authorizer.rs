@@ -0,0 +1,41 @@
+use async_trait::async_trait;
+use std::fmt;
+
+use crate::protocol::RemotePublicKey;
+
+/// Decide whether to accept a remote peer after the Noise handshake.
+///
+/// The authorizer is called once the handshake is complete, before the
+/// [`Event::Handshake`] is emitted and before any channel can be opened. If
+/// it returns `false`, the protocol stream fails with [`Error::Unauthorized`].
+///
+/// The authorizer is only called if the handshake is enabled (see
+/// [`Options::noise`]).
+///
+/// ```
+/// use hypercore_protocol::{Authorizer, ProtocolBuilder};
+/// use std::collections::HashSet;
+///
+/// #[derive(Debug)]
+/// struct Allowlist(HashSet<[u8; 32]>);
+///
+/// #[async_trait::async_trait]
+/// impl Authorizer for Allowlist {
+///     async fn authorize(&self, remote_public_key: &[u8; 32], _payload: &[u8]) -> bool {
+///         self.0.contains(remote_public_key)
+///     }
+/// }
+///
+/// let builder = ProtocolBuilder::new(true).set_authorizer(Allowlist(HashSet::new()));
+/// ```
+///
+/// [`Event::Handshake`]: crate::Event::Handshake
+/// [`Error::Unauthorized`]: crate::Error::Unauthorized
+/// [`Options::noise`]: crate::Options::noise
+#[async_trait]
+pub trait Authorizer: fmt::Debug + Send + Sync {
+    /// Return `true` to accept the remote peer, or `false` to abort the connection.
+    ///
+    /// `payload` is the raw payload the remote sent with its last handshake message.
+    async fn authorize(&self, remote_public_key: &RemotePublicKey, payload: &[u8]) -> bool;
+}
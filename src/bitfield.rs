@@ -0,0 +1,388 @@
+//! Bitfields with the run-length encoding used in [`Have`] messages.
+
+use std::ops::Range;
+
+use crate::error::{Error, Result};
+use crate::schema::Have;
+
+/// Maximum size of a decoded bitfield (in bytes).
+pub const MAX_BITFIELD_SIZE: usize = 16 * 1024 * 1024;
+
+/// A bitfield that marks which blocks of a feed are available.
+///
+/// Bits are stored most significant bit first, so bit `0` is the highest bit
+/// of the first byte. This matches the layout used by hypercore.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    /// Create an empty bitfield.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a bitfield from its raw bytes.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    /// Get the raw bytes of the bitfield.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The number of bits in the bitfield (always a multiple of 8).
+    pub fn len(&self) -> u64 {
+        self.bytes.len() as u64 * 8
+    }
+
+    /// Check if the bitfield has no bits.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Get the bit at `index`.
+    pub fn get(&self, index: u64) -> bool {
+        match self.bytes.get((index / 8) as usize) {
+            Some(byte) => byte & mask(index) != 0,
+            None => false,
+        }
+    }
+
+    /// Set the bit at `index`. The bitfield grows as needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a bit is set at or above `MAX_BITFIELD_SIZE * 8`.
+    pub fn set(&mut self, index: u64, value: bool) {
+        let end = index.saturating_add(1);
+        if value {
+            assert_within_max(end);
+        }
+        self.set_range(index..end, value);
+    }
+
+    /// Set all bits in `range`. The bitfield grows as needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if bits are set at or above `MAX_BITFIELD_SIZE * 8`.
+    pub fn set_range(&mut self, range: Range<u64>, value: bool) {
+        let end = if value {
+            range.end
+        } else {
+            range.end.min(self.len())
+        };
+        if range.start >= end {
+            return;
+        }
+        assert_within_max(end);
+        let end_byte = ((end - 1) / 8 + 1) as usize;
+        if self.bytes.len() < end_byte {
+            self.bytes.resize(end_byte, 0);
+        }
+        let fill = if value { 0xff } else { 0 };
+        let mut index = range.start;
+        while index < end {
+            if bit_offset(index) == 0 && end - index >= 8 {
+                let start_byte = (index / 8) as usize;
+                let bytes = ((end - index) / 8) as usize;
+                for byte in &mut self.bytes[start_byte..start_byte + bytes] {
+                    *byte = fill;
+                }
+                index += bytes as u64 * 8;
+            } else {
+                let byte = &mut self.bytes[(index / 8) as usize];
+                if value {
+                    *byte |= mask(index);
+                } else {
+                    *byte &= !mask(index);
+                }
+                index += 1;
+            }
+        }
+    }
+
+    /// Iterate over the ranges of set bits.
+    pub fn ranges(&self) -> Ranges<'_> {
+        Ranges {
+            bitfield: self,
+            index: 0,
+        }
+    }
+
+    /// Set all bits that are set in `other`.
+    pub fn merge(&mut self, other: &Bitfield) {
+        if self.bytes.len() < other.bytes.len() {
+            self.bytes.resize(other.bytes.len(), 0);
+        }
+        for (byte, other) in self.bytes.iter_mut().zip(other.bytes.iter()) {
+            *byte |= other;
+        }
+    }
+
+    /// Encode the bitfield with the run-length encoding used by hypercore.
+    ///
+    /// The encoding is a sequence of varint headers. If the lowest bit of a
+    /// header is set, it is a run of `header >> 2` bytes that are all `0xff`
+    /// (if the second bit is set) or all `0x00`. Otherwise, `header >> 1`
+    /// literal bytes follow the header.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        let bytes = &self.bytes;
+        let mut literal_start = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            if byte != 0 && byte != 0xff {
+                i += 1;
+                continue;
+            }
+            let run = bytes[i..].iter().take_while(|b| **b == byte).count();
+            write_literal(&mut buf, &bytes[literal_start..i]);
+            let bit = u64::from(byte == 0xff);
+            write_varint(&mut buf, (run as u64) << 2 | bit << 1 | 1);
+            i += run;
+            literal_start = i;
+        }
+        write_literal(&mut buf, &bytes[literal_start..]);
+        buf
+    }
+
+    /// Decode a bitfield that was encoded with [`Bitfield::encode`].
+    ///
+    /// A few bytes can encode a run of up to [`MAX_BITFIELD_SIZE`] bytes, so
+    /// use [`Bitfield::decode_ranges`] for bitfields received from a remote.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut bytes = vec![];
+        let mut offset = 0;
+        while offset < buf.len() {
+            let header = read_varint(buf, &mut offset)?;
+            let repeat = header & 1 == 1;
+            let len = if repeat { header >> 2 } else { header >> 1 };
+            if bytes.len() as u64 + len > MAX_BITFIELD_SIZE as u64 {
+                return Err(Error::Decode("Bitfield is too large".into()));
+            }
+            let len = len as usize;
+            if repeat {
+                let fill = if header & 2 == 2 { 0xff } else { 0 };
+                bytes.resize(bytes.len() + len, fill);
+            } else {
+                let literal = buf
+                    .get(offset..offset + len)
+                    .ok_or_else(|| Error::Decode("Bitfield is truncated".into()))?;
+                bytes.extend_from_slice(literal);
+                offset += len;
+            }
+        }
+        Ok(Self { bytes })
+    }
+
+    /// Decode the ranges of set bits of an encoded bitfield, without
+    /// allocating the bitfield itself. Memory use grows with the length of
+    /// `buf`, not with the length of the runs it encodes.
+    pub fn decode_ranges(buf: &[u8]) -> Result<Vec<Range<u64>>> {
+        let mut ranges: Vec<Range<u64>> = vec![];
+        let mut push = |range: Range<u64>| match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        };
+        let mut size = 0u64;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let header = read_varint(buf, &mut offset)?;
+            let repeat = header & 1 == 1;
+            let len = if repeat { header >> 2 } else { header >> 1 };
+            if size + len > MAX_BITFIELD_SIZE as u64 {
+                return Err(Error::Decode("Bitfield is too large".into()));
+            }
+            let start = size * 8;
+            size += len;
+            if repeat {
+                if header & 2 == 2 && len > 0 {
+                    push(start..size * 8);
+                }
+                continue;
+            }
+            let literal = buf
+                .get(offset..offset + len as usize)
+                .ok_or_else(|| Error::Decode("Bitfield is truncated".into()))?;
+            offset += literal.len();
+            for (i, byte) in literal.iter().enumerate() {
+                let byte_start = start + i as u64 * 8;
+                for bit in 0..8 {
+                    if byte & mask(bit) != 0 {
+                        push(byte_start + bit..byte_start + bit + 1);
+                    }
+                }
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Get the blocks announced by a [`Have`] message. Bit `i` of the returned
+    /// bitfield marks the block at `msg.start + i`.
+    pub fn from_have(msg: &Have) -> Result<Self> {
+        match &msg.bitfield {
+            Some(buf) => Self::decode(buf),
+            None => {
+                // The length of a Have defaults to 1.
+                let length = msg.length.unwrap_or(1);
+                if length > MAX_BITFIELD_SIZE as u64 * 8 {
+                    return Err(Error::Decode("Bitfield is too large".into()));
+                }
+                let mut bitfield = Self::new();
+                bitfield.set_range(0..length, true);
+                Ok(bitfield)
+            }
+        }
+    }
+
+    /// Find the first bit from `index` on that equals `value`.
+    fn find(&self, mut index: u64, value: bool) -> Option<u64> {
+        let skip = if value { 0 } else { 0xff };
+        while index < self.len() {
+            if bit_offset(index) == 0 && self.bytes[(index / 8) as usize] == skip {
+                index += 8;
+            } else if self.get(index) == value {
+                return Some(index);
+            } else {
+                index += 1;
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the ranges of set bits in a [`Bitfield`].
+#[derive(Debug)]
+pub struct Ranges<'a> {
+    bitfield: &'a Bitfield,
+    index: u64,
+}
+
+impl<'a> Iterator for Ranges<'a> {
+    type Item = Range<u64>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.bitfield.find(self.index, true)?;
+        let end = self
+            .bitfield
+            .find(start, false)
+            .unwrap_or_else(|| self.bitfield.len());
+        self.index = end;
+        Some(start..end)
+    }
+}
+
+fn assert_within_max(end: u64) {
+    assert!(end <= MAX_BITFIELD_SIZE as u64 * 8, "Bitfield is too large");
+}
+
+fn bit_offset(index: u64) -> u64 {
+    index % 8
+}
+
+fn mask(index: u64) -> u8 {
+    128 >> bit_offset(index)
+}
+
+fn write_literal(buf: &mut Vec<u8>, literal: &[u8]) {
+    if !literal.is_empty() {
+        write_varint(buf, (literal.len() as u64) << 1);
+        buf.extend_from_slice(literal);
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    let offset = buf.len();
+    buf.resize(offset + varinteger::length(value), 0);
+    varinteger::encode(value, &mut buf[offset..]);
+}
+
+fn read_varint(buf: &[u8], offset: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *buf
+            .get(*offset)
+            .ok_or_else(|| Error::Decode("Bitfield is truncated".into()))?;
+        *offset += 1;
+        if shift > 63 {
+            return Err(Error::Decode("Invalid varint in bitfield".into()));
+        }
+        value |= u64::from(byte & 127) << shift;
+        if byte & 128 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let bitfield = Bitfield::from_bytes(vec![0xff, 0xff, 0, 0, 0, 1, 2]);
+        let encoded = bitfield.encode();
+        assert_eq!(encoded, vec![0x0b, 0x0d, 0x04, 0x01, 0x02]);
+        assert_eq!(Bitfield::decode(&encoded).unwrap(), bitfield);
+        assert!(Bitfield::decode(&[0x04, 0x01]).is_err());
+    }
+
+    #[test]
+    fn decode_ranges() {
+        let bitfield = Bitfield::from_bytes(vec![0xff, 0xff, 0, 0, 0, 1, 2, 0x80]);
+        let expected: Vec<_> = bitfield.ranges().collect();
+        assert_eq!(
+            Bitfield::decode_ranges(&bitfield.encode()).unwrap(),
+            expected
+        );
+        assert!(Bitfield::decode_ranges(&[0x04, 0x01]).is_err());
+
+        // A run of the maximum size is decoded from four bytes.
+        let mut buf = vec![];
+        write_varint(&mut buf, (MAX_BITFIELD_SIZE as u64) << 2 | 3);
+        let max = MAX_BITFIELD_SIZE as u64 * 8;
+        assert_eq!(Bitfield::decode_ranges(&buf).unwrap(), vec![0..max]);
+        buf.extend_from_slice(&[0x02, 0xff]);
+        assert!(Bitfield::decode_ranges(&buf).is_err());
+    }
+
+    #[test]
+    fn set_range_and_ranges() {
+        let mut bitfield = Bitfield::new();
+        bitfield.set_range(3..21, true);
+        bitfield.set(30, true);
+        bitfield.set_range(10..12, false);
+        let ranges: Vec<_> = bitfield.ranges().collect();
+        assert_eq!(ranges, vec![3..10, 12..21, 30..31]);
+
+        let mut other = Bitfield::new();
+        other.set_range(9..13, true);
+        bitfield.merge(&other);
+        let ranges: Vec<_> = bitfield.ranges().collect();
+        assert_eq!(ranges, vec![3..21, 30..31]);
+    }
+
+    #[test]
+    fn edge_indexes() {
+        let mut bitfield = Bitfield::new();
+        assert!(!bitfield.get(u64::MAX));
+        bitfield.set(u64::MAX, false);
+        bitfield.set_range(u64::MAX - 1..u64::MAX, false);
+        assert!(bitfield.is_empty());
+        let last = MAX_BITFIELD_SIZE as u64 * 8 - 1;
+        bitfield.set(last, true);
+        assert_eq!(bitfield.ranges().collect::<Vec<_>>(), vec![last..last + 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bitfield is too large")]
+    fn set_max_index() {
+        Bitfield::new().set(u64::MAX, true);
+    }
+}
@@ -1,6 +1,19 @@
+use crate::authorizer::Authorizer;
+use crate::capture::Capture;
+use crate::constants::{
+    DEFAULT_CHANNEL_CAPACITY, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_KEEPALIVE, DEFAULT_MAX_CHANNELS,
+    DEFAULT_MAX_EXTENSIONS, DEFAULT_MAX_PENDING_BYTES, DEFAULT_MAX_PENDING_OPENS,
+    DEFAULT_MAX_REMOTE_CHANNEL_ID, DEFAULT_TIMEOUT,
+};
 use crate::duplex::Duplex;
+use crate::noise::{Encryption, Keypair};
+use crate::resolver::Resolver;
+use crate::throttle::RateLimiter;
+use crate::version::Version;
 use crate::Protocol;
 use futures_lite::io::{AsyncRead, AsyncWrite};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Options for a Protocol instance.
 #[derive(Debug)]
@@ -11,8 +24,71 @@ pub struct Options {
     /// Disabling the handshake will also disable capabilitity verification.
     /// Don't disable this if you're not 100% sure you want this.
     pub noise: bool,
-    /// Enable or disable transport encryption.
+    /// Enable or disable transport encryption of [`Version::V1`]. Connections
+    /// of later versions are always encrypted.
     pub encrypted: bool,
+    /// The transport encryption mode of [`Version::V1`], if `encrypted` is true.
+    pub encryption: Encryption,
+    /// The wire protocol versions this peer accepts. The initiator speaks the
+    /// highest version, see [`Version`] for how the responder picks it. Must
+    /// not be empty, otherwise the protocol fails with
+    /// [`Error::InvalidOptions`](crate::Error::InvalidOptions).
+    pub versions: Vec<Version>,
+    /// Static Noise keypair used for the handshake.
+    /// If not set, a new keypair is generated for each protocol instance.
+    /// The handshake of `Version::V10` uses the secret key as the seed of an
+    /// Ed25519 keypair.
+    pub keypair: Option<Keypair>,
+    /// Authorizer that decides whether to accept the remote peer after the handshake.
+    /// If not set, all remote peers are accepted.
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// Resolver that looks up the keys of channels that the remote opens.
+    /// If not set, [`Event::DiscoveryKey`](crate::Event::DiscoveryKey) is
+    /// emitted for each channel that the remote opens first.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Time after which the connection is closed if the remote did not send anything.
+    /// `None` disables the timeout.
+    pub timeout: Option<Duration>,
+    /// Interval in which a keepalive message is sent to the remote. If the
+    /// remote answers keepalives, their round trip time is reported in
+    /// [`ProtocolStats::rtt`](crate::ProtocolStats::rtt).
+    /// `None` disables the keepalive. Should be shorter than the remote's timeout.
+    pub keepalive: Option<Duration>,
+    /// Time after which the connection is closed if the handshake did not complete.
+    /// `None` disables the timeout.
+    pub handshake_timeout: Option<Duration>,
+    /// Number of inbound messages that are buffered for each channel.
+    /// If a channel's buffer is full, the protocol stops reading from the
+    /// connection until the channel's messages are consumed. Must be at least 1.
+    pub channel_capacity: usize,
+    /// Maximum number of channels. If the remote opens a new channel while
+    /// this many channels exist, the protocol fails with
+    /// [`Error::LimitExceeded`](crate::Error::LimitExceeded).
+    pub max_channels: usize,
+    /// Maximum channel ID that the remote may use to open a channel.
+    pub max_remote_channel_id: u64,
+    /// Maximum number of extensions that the remote may announce in an
+    /// options message.
+    pub max_extensions: usize,
+    /// Maximum number of channels that the remote opened and that were not
+    /// yet opened locally or rejected.
+    pub max_pending_opens: usize,
+    /// Maximum number of bytes of messages that are buffered for channels
+    /// that the remote opened and that were not yet opened locally. While
+    /// this many bytes or `channel_capacity` messages of one channel are
+    /// buffered, the protocol stops reading from the connection until a
+    /// channel is opened or rejected.
+    pub max_pending_bytes: usize,
+    /// Maximum time that small outbound frames are held back to be written
+    /// together with later frames. `None` writes frames as soon as no more
+    /// messages are queued.
+    pub cork: Option<Duration>,
+    /// Rate limiters for the bytes that are written to the connection.
+    pub upload_limits: Vec<RateLimiter>,
+    /// Rate limiters for the bytes that are read from the connection.
+    pub download_limits: Vec<RateLimiter>,
+    /// Records all frames that are read or written, after decryption.
+    pub capture: Option<Capture>,
 }
 
 impl Options {
@@ -22,6 +98,24 @@ impl Options {
             is_initiator,
             noise: true,
             encrypted: true,
+            encryption: Encryption::default(),
+            versions: vec![Version::V1],
+            keypair: None,
+            authorizer: None,
+            resolver: None,
+            timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT as u64)),
+            keepalive: Some(Duration::from_secs(DEFAULT_KEEPALIVE as u64)),
+            handshake_timeout: Some(Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT as u64)),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            max_remote_channel_id: DEFAULT_MAX_REMOTE_CHANNEL_ID,
+            max_extensions: DEFAULT_MAX_EXTENSIONS,
+            max_pending_opens: DEFAULT_MAX_PENDING_OPENS,
+            max_pending_bytes: DEFAULT_MAX_PENDING_BYTES,
+            cork: None,
+            upload_limits: vec![],
+            download_limits: vec![],
+            capture: None,
         }
     }
 }
@@ -33,11 +127,7 @@ pub struct Builder(Options);
 impl Builder {
     /// Create a protocol builder.
     pub fn new(is_initiator: bool) -> Self {
-        Self(Options {
-            is_initiator,
-            noise: true,
-            encrypted: true,
-        })
+        Self(Options::new(is_initiator))
     }
 
     /// Default options for an initiating endpoint.
@@ -56,12 +146,163 @@ impl Builder {
         self
     }
 
+    /// Set the transport encryption mode.
+    ///
+    /// [`Encryption::Authenticated`] detects tampering with frames, but both
+    /// peers have to use it. The handshake fails with
+    /// [`Error::EncryptionMismatch`](crate::Error::EncryptionMismatch) otherwise.
+    pub fn set_encryption(mut self, encryption: Encryption) -> Self {
+        self.0.encryption = encryption;
+        self
+    }
+
+    /// Set the wire protocol versions this peer accepts.
+    ///
+    /// Accepting several versions allows a listener to serve peers that speak
+    /// different versions. The negotiated version is reported in
+    /// [`Event::Handshake`](crate::Event::Handshake).
+    ///
+    /// An initiator that enables `Version::V10` only reaches responders that
+    /// accept it, responders built without the `v10` feature cannot tell the
+    /// versions apart.
+    ///
+    /// The protocol fails with
+    /// [`Error::InvalidOptions`](crate::Error::InvalidOptions) if
+    /// `Version::V10` is enabled without the `v10-unstable` feature.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `versions` is empty.
+    pub fn set_versions(mut self, versions: &[Version]) -> Self {
+        assert!(!versions.is_empty(), "At least one version is required");
+        self.0.versions = versions.to_vec();
+        self
+    }
+
     /// Set handshake option.
+    ///
+    /// Without the handshake, the remote is not authenticated and the
+    /// channels it opens are accepted without verifying their capability.
+    /// This is meant for tests and for replaying captures, see
+    /// [`Replay`](crate::capture::Replay).
     pub fn set_noise(mut self, noise: bool) -> Self {
         self.0.noise = noise;
         self
     }
 
+    /// Set the static Noise keypair.
+    ///
+    /// Use this to keep a stable identity across connections.
+    pub fn set_keypair(mut self, keypair: Keypair) -> Self {
+        self.0.keypair = Some(keypair);
+        self
+    }
+
+    /// Set an authorizer to accept or reject remote peers after the handshake.
+    pub fn set_authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.0.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Set a resolver to open channels that the remote opens without
+    /// waiting for the application.
+    pub fn set_resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.0.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Set the read timeout. `None` disables the timeout.
+    pub fn set_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.0.timeout = timeout;
+        self
+    }
+
+    /// Set the keepalive interval. `None` disables the keepalive.
+    pub fn set_keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.0.keepalive = keepalive;
+        self
+    }
+
+    /// Set the handshake timeout. `None` disables the timeout.
+    pub fn set_handshake_timeout(mut self, handshake_timeout: Option<Duration>) -> Self {
+        self.0.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Set the number of inbound messages that are buffered for each channel.
+    pub fn set_channel_capacity(mut self, capacity: usize) -> Self {
+        self.0.channel_capacity = capacity;
+        self
+    }
+
+    /// Set the maximum number of channels.
+    pub fn set_max_channels(mut self, max_channels: usize) -> Self {
+        self.0.max_channels = max_channels;
+        self
+    }
+
+    /// Set the maximum channel ID that the remote may use.
+    pub fn set_max_remote_channel_id(mut self, max_remote_channel_id: u64) -> Self {
+        self.0.max_remote_channel_id = max_remote_channel_id;
+        self
+    }
+
+    /// Set the maximum number of extensions that the remote may announce.
+    pub fn set_max_extensions(mut self, max_extensions: usize) -> Self {
+        self.0.max_extensions = max_extensions;
+        self
+    }
+
+    /// Set the maximum number of channels that the remote opened and that
+    /// were not yet opened locally or rejected.
+    pub fn set_max_pending_opens(mut self, max_pending_opens: usize) -> Self {
+        self.0.max_pending_opens = max_pending_opens;
+        self
+    }
+
+    /// Set the maximum number of bytes of messages that are buffered for
+    /// channels that the remote opened and that were not yet opened locally.
+    pub fn set_max_pending_bytes(mut self, max_pending_bytes: usize) -> Self {
+        self.0.max_pending_bytes = max_pending_bytes;
+        self
+    }
+
+    /// Set the maximum time that small outbound frames are held back to be
+    /// coalesced with later frames. `None` disables corking.
+    ///
+    /// This trades latency for fewer writes, e.g. for bursts of small messages.
+    pub fn set_cork(mut self, cork: Option<Duration>) -> Self {
+        self.0.cork = cork;
+        self
+    }
+
+    /// Limit the rate at which bytes are written to the connection.
+    ///
+    /// Can be called several times, e.g. with a limiter for this connection
+    /// and one that is shared with other connections. Writes wait until all
+    /// limiters allow them.
+    pub fn add_upload_limit(mut self, limiter: RateLimiter) -> Self {
+        self.0.upload_limits.push(limiter);
+        self
+    }
+
+    /// Limit the rate at which bytes are read from the connection.
+    ///
+    /// Can be called several times, like [`Builder::add_upload_limit`]. While
+    /// reading is throttled, the remote has to wait to send more data.
+    pub fn add_download_limit(mut self, limiter: RateLimiter) -> Self {
+        self.0.download_limits.push(limiter);
+        self
+    }
+
+    /// Record all frames that are read or written, after decryption.
+    ///
+    /// See the [`capture`](crate::capture) module to print or replay a capture.
+    pub fn set_capture(mut self, capture: Capture) -> Self {
+        self.0.capture = Some(capture);
+        self
+    }
+
     /// Create the protocol from a stream that implements AsyncRead + AsyncWrite + Clone.
     pub fn connect<IO>(self, io: IO) -> Protocol<IO>
     where
@@ -0,0 +1,312 @@
+//! Record, print and replay the frames of a protocol stream.
+//!
+//! A [`Capture`] records every frame that a [`Protocol`](crate::Protocol)
+//! reads or writes after decryption, so it shows the messages that were
+//! exchanged even if the connection is encrypted. Each frame is written as a
+//! line of text with the time since the capture started, the direction, the
+//! kind of frame and the frame body in hex:
+//!
+//! ```text
+//! 0.001503 out handshake 00010a20...
+//! 0.012345 in message 1a0a2052...
+//! ```
+//!
+//! [`read_capture`] parses a capture into [`CapturedFrame`]s, which print
+//! their decoded message with `Display`. A [`Replay`] feeds the messages of a
+//! capture back into a protocol, e.g. to turn a capture of an interop problem
+//! into a regression test:
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! use hypercore_protocol::capture::{read_capture, Direction, Replay};
+//! use hypercore_protocol::ProtocolBuilder;
+//! use futures_lite::StreamExt;
+//!
+//! let file = std::fs::File::open("connection.capture").unwrap();
+//! let frames = read_capture(std::io::BufReader::new(file)).unwrap();
+//! for frame in frames.iter() {
+//!     println!("{}", frame);
+//! }
+//! // Replay the messages that the remote sent.
+//! let replay = Replay::new(&frames, Direction::Inbound);
+//! let mut protocol = ProtocolBuilder::new(true)
+//!     .set_noise(false)
+//!     .set_encrypted(false)
+//!     .connect(replay);
+//! while let Some(event) = protocol.next().await {
+//!     println!("{:?}", event);
+//! }
+//! # })
+//! ```
+
+use crate::error::{Error, Result};
+use crate::message::ChannelMessage;
+use crate::Message;
+use bytes::Bytes;
+use futures_lite::io::{AsyncRead, AsyncWrite};
+use instant::Instant;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, LineWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// The direction of a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame was read from the remote.
+    Inbound,
+    /// The frame was written to the remote.
+    Outbound,
+}
+
+/// The kind of a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// A message of the Noise handshake.
+    Handshake,
+    /// A [`Message`] on a channel.
+    Message,
+}
+
+/// Records the decrypted frames of one or more protocols.
+///
+/// Clones share the same output. Set it with
+/// [`ProtocolBuilder::set_capture`](crate::ProtocolBuilder::set_capture).
+#[derive(Clone)]
+pub struct Capture {
+    writer: Arc<Mutex<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl Capture {
+    /// Create a capture that writes to `writer`.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            start: Instant::now(),
+        }
+    }
+
+    /// Create a capture that writes to a new file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(LineWriter::new(file)))
+    }
+
+    /// Record a frame body. Empty frames (keepalives) are skipped. Write
+    /// errors are logged but do not affect the protocol.
+    pub(crate) fn record(&self, direction: Direction, kind: FrameKind, body: &[u8]) {
+        if body.is_empty() {
+            return;
+        }
+        let frame = CapturedFrame {
+            time: self.start.elapsed(),
+            direction,
+            kind,
+            body: Bytes::copy_from_slice(body),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", frame.to_line()) {
+            log::warn!("failed to write capture: {}", e);
+        }
+    }
+}
+
+/// A frame of a capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedFrame {
+    /// Time since the capture started.
+    pub time: Duration,
+    /// Whether the frame was read or written.
+    pub direction: Direction,
+    /// The kind of frame.
+    pub kind: FrameKind,
+    /// The decrypted frame body, without the length prefix.
+    pub body: Bytes,
+}
+
+impl CapturedFrame {
+    /// Decode the channel ID and message of a message frame.
+    pub fn decode(&self) -> Result<(u64, Message)> {
+        match self.kind {
+            FrameKind::Handshake => Err(Error::Decode(
+                "Handshake frames do not contain a message".into(),
+            )),
+            FrameKind::Message => Ok(ChannelMessage::decode(self.body.clone())?.into_split()),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let direction = match self.direction {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        };
+        let kind = match self.kind {
+            FrameKind::Handshake => "handshake",
+            FrameKind::Message => "message",
+        };
+        format!(
+            "{:.6} {} {} {}",
+            self.time.as_secs_f64(),
+            direction,
+            kind,
+            hex::encode(&self.body)
+        )
+    }
+}
+
+impl FromStr for CapturedFrame {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = || Error::Decode(format!("Invalid capture line: {}", line).into());
+        let mut parts = line.split_whitespace();
+        let time = parts
+            .next()
+            .and_then(|time| time.parse::<f64>().ok())
+            .ok_or_else(invalid)?;
+        let direction = match parts.next() {
+            Some("in") => Direction::Inbound,
+            Some("out") => Direction::Outbound,
+            _ => return Err(invalid()),
+        };
+        let kind = match parts.next() {
+            Some("handshake") => FrameKind::Handshake,
+            Some("message") => FrameKind::Message,
+            _ => return Err(invalid()),
+        };
+        let body = parts
+            .next()
+            .and_then(|body| hex::decode(body).ok())
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            time: Duration::from_secs_f64(time),
+            direction,
+            kind,
+            body: body.into(),
+        })
+    }
+}
+
+impl fmt::Display for CapturedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Inbound => "<-",
+            Direction::Outbound => "->",
+        };
+        write!(f, "{:>10.6} {} ", self.time.as_secs_f64(), arrow)?;
+        match self.kind {
+            FrameKind::Handshake => write!(f, "handshake <{}>", self.body.len()),
+            FrameKind::Message => match self.decode() {
+                Ok((channel, message)) => write!(f, "[{}] {}", channel, message),
+                Err(e) => write!(f, "invalid message: {}", e),
+            },
+        }
+    }
+}
+
+/// Read all frames of a capture.
+pub fn read_capture(reader: impl BufRead) -> Result<Vec<CapturedFrame>> {
+    let mut frames = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            frames.push(line.parse()?);
+        }
+    }
+    Ok(frames)
+}
+
+/// An IO stream that replays the message frames of a capture.
+///
+/// Reading returns the messages of one direction as an unencrypted stream,
+/// and ends after the last message. Like a remote peer, the replay only
+/// starts once the protocol wrote something, so channels that were opened
+/// before the protocol is polled are open when their messages arrive. Writes
+/// are discarded. The handshake cannot be replayed, so the protocol has to
+/// be created with [`set_noise(false)`](crate::ProtocolBuilder::set_noise)
+/// and [`set_encrypted(false)`](crate::ProtocolBuilder::set_encrypted).
+#[derive(Debug)]
+pub struct Replay {
+    buf: Vec<u8>,
+    pos: usize,
+    started: bool,
+    waker: Option<Waker>,
+}
+
+impl Replay {
+    /// Create a replay of the message frames of `frames` that were captured
+    /// in `direction`.
+    pub fn new(frames: &[CapturedFrame], direction: Direction) -> Self {
+        let mut buf = vec![];
+        let frames = frames
+            .iter()
+            .filter(|frame| frame.direction == direction && frame.kind == FrameKind::Message);
+        for frame in frames {
+            let mut header = vec![0u8; varinteger::length(frame.body.len() as u64)];
+            varinteger::encode(frame.body.len() as u64, &mut header);
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(&frame.body);
+        }
+        Self {
+            buf,
+            pos: 0,
+            started: false,
+            waker: None,
+        }
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.started {
+            self.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Replay {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !buf.is_empty() && !self.started {
+            self.started = true;
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
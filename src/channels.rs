@@ -1,34 +1,254 @@
-use crate::extension::{Extension, Extensions};
-use crate::message::ChannelMessage;
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::extension::{Extension, Extensions, RemoteUpdate};
+use crate::message::{ChannelMessage, Encoder, ExtensionMessage};
+use crate::request::{Get, Requester, Requests};
 use crate::schema::*;
+use crate::stats::ChannelStats;
+use crate::trace::{channel_span, trace_event, Span};
 use crate::util::{map_channel_err, pretty_hash};
+use crate::version::Version;
 use crate::Message;
 use crate::{discovery_key, DiscoveryKey, Key};
-use async_channel::{Receiver, Sender};
+use async_channel::{Receiver, Sender, TrySendError};
+#[cfg(feature = "v10")]
+use bytes::Bytes;
 use futures_lite::ready;
 use futures_lite::stream::Stream;
+use futures_sink::Sink;
 use std::collections::HashMap;
 use std::fmt;
-use std::io::{Error, ErrorKind, Result};
+use std::future::Future;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::task::Poll;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 /// A protocol channel.
 ///
-/// This is the handle that can be sent to other threads.
+/// This is the handle that can be sent to other threads. Use
+/// [`Channel::split`] to send and receive from different tasks.
 pub struct Channel {
-    inbound_rx: Option<Receiver<Message>>,
+    sender: ChannelSender,
+    receiver: ChannelReceiver,
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        self.sender == other.sender
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("discovery_key", &pretty_hash(self.discovery_key()))
+            .finish()
+    }
+}
+
+impl Channel {
+    /// Get the discovery key of this channel.
+    pub fn discovery_key(&self) -> &[u8; 32] {
+        self.sender.discovery_key()
+    }
+
+    /// Get the key of this channel.
+    pub fn key(&self) -> &[u8; 32] {
+        self.sender.key()
+    }
+
+    /// Get the local wire ID of this channel.
+    pub fn id(&self) -> usize {
+        self.sender.id()
+    }
+
+    /// Check if the channel is closed.
+    pub fn closed(&self) -> bool {
+        self.sender.closed()
+    }
+
+    /// Get the protocol version of the connection, which decides the
+    /// messages that the channel can send.
+    pub fn version(&self) -> Version {
+        self.sender.version()
+    }
+
+    /// Get a snapshot of the counters of this channel.
+    ///
+    /// Messages are counted when the protocol writes or reads them.
+    pub fn stats(&self) -> ChannelStats {
+        self.sender.stats()
+    }
+
+    /// Split the channel into a sender and a receiver.
+    ///
+    /// The sender can be cloned to send from several tasks. If the receiver
+    /// is dropped, the messages that the remote sends on the channel are
+    /// discarded.
+    pub fn split(self) -> (ChannelSender, ChannelReceiver) {
+        (self.sender, self.receiver)
+    }
+
+    /// Send a message over the channel.
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.sender.send(message).await
+    }
+
+    /// Request the block at `index` and wait for its `Data` message.
+    ///
+    /// `Data` messages that answer a pending `get` are not emitted on the
+    /// channel's stream. See [`Get`] for timeouts and retries on other channels.
+    pub fn get(&self, index: u64) -> Get {
+        self.sender.get(index)
+    }
+
+    /// Register a protocol extension.
+    pub async fn register_extension(&mut self, name: impl ToString) -> Extension {
+        self.sender.register_extension(name).await
+    }
+
+    /// Take the receiving part out of the channel.
+    ///
+    /// After taking the receiver, this Channel will not emit messages when
+    /// polled as a stream. The returned receiver will.
+    #[deprecated(note = "Use `Channel::split`, which also returns the sending half")]
+    pub fn take_receiver(&mut self) -> Option<Receiver<Message>> {
+        self.receiver.inbound_rx.take()
+    }
+
+    /// Send a status message.
+    pub async fn status(&mut self, msg: Status) -> Result<()> {
+        self.sender.status(msg).await
+    }
+
+    /// Send a options message.
+    pub async fn options(&mut self, msg: Options) -> Result<()> {
+        self.sender.options(msg).await
+    }
+
+    /// Send a have message.
+    pub async fn have(&mut self, msg: Have) -> Result<()> {
+        self.sender.have(msg).await
+    }
+
+    /// Send a have message with a run-length encoded bitfield. Bit `i` of the
+    /// bitfield marks the block at `start + i`.
+    pub async fn have_bitfield(&mut self, start: u64, bitfield: &Bitfield) -> Result<()> {
+        self.sender.have_bitfield(start, bitfield).await
+    }
+
+    /// Send a unhave message.
+    pub async fn unhave(&mut self, msg: Unhave) -> Result<()> {
+        self.sender.unhave(msg).await
+    }
+
+    /// Send a want message.
+    pub async fn want(&mut self, msg: Want) -> Result<()> {
+        self.sender.want(msg).await
+    }
+
+    /// Send a unwant message.
+    pub async fn unwant(&mut self, msg: Unwant) -> Result<()> {
+        self.sender.unwant(msg).await
+    }
+
+    /// Send a request message.
+    pub async fn request(&mut self, msg: Request) -> Result<()> {
+        self.sender.request(msg).await
+    }
+
+    /// Send a cancel message.
+    pub async fn cancel(&mut self, msg: Cancel) -> Result<()> {
+        self.sender.cancel(msg).await
+    }
+
+    /// Send a data message.
+    pub async fn data(&mut self, msg: Data) -> Result<()> {
+        self.sender.data(msg).await
+    }
+
+    /// Send a close message and close this channel.
+    pub async fn close(&mut self) -> Result<()> {
+        self.sender.close().await
+    }
+}
+
+impl AsRef<ChannelSender> for Channel {
+    fn as_ref(&self) -> &ChannelSender {
+        &self.sender
+    }
+}
+
+impl Stream for Channel {
+    type Item = Message;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().receiver).poll_next(cx)
+    }
+}
+
+impl Sink<Message> for Channel {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<()> {
+        Pin::new(&mut self.get_mut().sender).start_send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender).poll_close(cx)
+    }
+}
+
+type SendFuture = Pin<Box<dyn Future<Output = Result<()>> + Send + Sync>>;
+
+/// The sending half of a [`Channel`], created with [`Channel::split`].
+///
+/// Clones send on the same channel.
+///
+/// Like the channel, the sender is a [`Sink`] of messages. A message is
+/// accepted once the previous message was taken by the protocol, so the
+/// sink waits while the protocol's outbound queue is full. Closing the sink
+/// only flushes it, use [`ChannelSender::close`] to close the channel.
+pub struct ChannelSender {
     outbound_tx: Sender<ChannelMessage>,
     key: Key,
     discovery_key: DiscoveryKey,
     local_id: usize,
-    extensions: Extensions,
+    version: Version,
+    extensions: Arc<Mutex<Extensions>>,
     closed: Arc<AtomicBool>,
+    stats: Arc<Mutex<ChannelStats>>,
+    requests: Requests,
+    /// The message that is being sent as a [`Sink`].
+    sending: Option<SendFuture>,
 }
 
-impl PartialEq for Channel {
+impl Clone for ChannelSender {
+    fn clone(&self) -> Self {
+        Self {
+            outbound_tx: self.outbound_tx.clone(),
+            key: self.key,
+            discovery_key: self.discovery_key,
+            local_id: self.local_id,
+            version: self.version,
+            extensions: self.extensions.clone(),
+            closed: self.closed.clone(),
+            stats: self.stats.clone(),
+            requests: self.requests.clone(),
+            sending: None,
+        }
+    }
+}
+
+impl PartialEq for ChannelSender {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
             && self.discovery_key == other.discovery_key
@@ -36,15 +256,15 @@ impl PartialEq for Channel {
     }
 }
 
-impl fmt::Debug for Channel {
+impl fmt::Debug for ChannelSender {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Channel")
+        f.debug_struct("ChannelSender")
             .field("discovery_key", &pretty_hash(&self.discovery_key))
             .finish()
     }
 }
 
-impl Channel {
+impl ChannelSender {
     /// Get the discovery key of this channel.
     pub fn discovery_key(&self) -> &[u8; 32] {
         &self.discovery_key
@@ -65,14 +285,22 @@ impl Channel {
         self.closed.load(Ordering::SeqCst)
     }
 
+    /// Get the protocol version of the connection.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Get a snapshot of the counters of this channel.
+    pub fn stats(&self) -> ChannelStats {
+        self.stats.lock().unwrap().clone()
+    }
+
     /// Send a message over the channel.
-    pub async fn send(&mut self, message: Message) -> Result<()> {
-        if self.closed() {
-            return Err(Error::new(
-                ErrorKind::ConnectionAborted,
-                "Channel is closed",
-            ));
-        }
+    ///
+    /// Fails with [`Error::UnsupportedMessage`] if the protocol version of
+    /// the connection does not have the message.
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.check_send(&message)?;
         let message = ChannelMessage::new(self.local_id as u64, message);
         self.outbound_tx
             .send(message)
@@ -80,66 +308,104 @@ impl Channel {
             .map_err(map_channel_err)
     }
 
-    /// Register a protocol extension.
-    pub async fn register_extension(&mut self, name: impl ToString) -> Extension {
-        self.extensions.register(name.to_string()).await
+    /// Request the block at `index` and wait for its `Data` message.
+    ///
+    /// See [`Channel::get`].
+    pub fn get(&self, index: u64) -> Get {
+        Get::new(self.requester(), index)
     }
 
-    /// Take the receiving part out of the channel.
-    ///
-    /// After taking the receiver, this Channel will not emit messages when
-    /// polled as a stream. The returned receiver will.
-    pub fn take_receiver(&mut self) -> Option<Receiver<Message>> {
-        self.inbound_rx.take()
+    pub(crate) fn requester(&self) -> Requester {
+        Requester {
+            requests: self.requests.clone(),
+            outbound_tx: self.outbound_tx.clone(),
+            local_id: self.local_id as u64,
+            closed: self.closed.clone(),
+            version: self.version,
+        }
+    }
+
+    fn check_send(&self, message: &Message) -> Result<()> {
+        if self.closed() {
+            return Err(Error::ChannelClosed);
+        }
+        if !self.version.supports(message) {
+            return Err(Error::UnsupportedMessage(self.version));
+        }
+        Ok(())
+    }
+
+    /// Register a protocol extension.
+    pub async fn register_extension(&self, name: impl ToString) -> Extension {
+        let (extension, message) = self
+            .extensions
+            .lock()
+            .unwrap()
+            .register_local(name.to_string());
+        // If the protocol is gone, the extension never receives messages.
+        let _ = self.outbound_tx.send(message).await;
+        extension
     }
 
     /// Send a status message.
-    pub async fn status(&mut self, msg: Status) -> Result<()> {
+    pub async fn status(&self, msg: Status) -> Result<()> {
         self.send(Message::Status(msg)).await
     }
 
     /// Send a options message.
-    pub async fn options(&mut self, msg: Options) -> Result<()> {
+    pub async fn options(&self, msg: Options) -> Result<()> {
         self.send(Message::Options(msg)).await
     }
 
     /// Send a have message.
-    pub async fn have(&mut self, msg: Have) -> Result<()> {
+    pub async fn have(&self, msg: Have) -> Result<()> {
         self.send(Message::Have(msg)).await
     }
 
+    /// Send a have message with a run-length encoded bitfield. Bit `i` of the
+    /// bitfield marks the block at `start + i`.
+    pub async fn have_bitfield(&self, start: u64, bitfield: &Bitfield) -> Result<()> {
+        self.have(Have {
+            start,
+            length: Some(bitfield.len()),
+            bitfield: Some(bitfield.encode()),
+            ack: None,
+        })
+        .await
+    }
+
     /// Send a unhave message.
-    pub async fn unhave(&mut self, msg: Unhave) -> Result<()> {
+    pub async fn unhave(&self, msg: Unhave) -> Result<()> {
         self.send(Message::Unhave(msg)).await
     }
 
     /// Send a want message.
-    pub async fn want(&mut self, msg: Want) -> Result<()> {
+    pub async fn want(&self, msg: Want) -> Result<()> {
         self.send(Message::Want(msg)).await
     }
 
     /// Send a unwant message.
-    pub async fn unwant(&mut self, msg: Unwant) -> Result<()> {
+    pub async fn unwant(&self, msg: Unwant) -> Result<()> {
         self.send(Message::Unwant(msg)).await
     }
 
     /// Send a request message.
-    pub async fn request(&mut self, msg: Request) -> Result<()> {
+    pub async fn request(&self, msg: Request) -> Result<()> {
         self.send(Message::Request(msg)).await
     }
 
     /// Send a cancel message.
-    pub async fn cancel(&mut self, msg: Cancel) -> Result<()> {
+    pub async fn cancel(&self, msg: Cancel) -> Result<()> {
         self.send(Message::Cancel(msg)).await
     }
 
     /// Send a data message.
-    pub async fn data(&mut self, msg: Data) -> Result<()> {
+    pub async fn data(&self, msg: Data) -> Result<()> {
         self.send(Message::Data(msg)).await
     }
 
     /// Send a close message and close this channel.
-    pub async fn close(&mut self) -> Result<()> {
+    pub async fn close(&self) -> Result<()> {
         if self.closed() {
             return Ok(());
         }
@@ -152,36 +418,95 @@ impl Channel {
     }
 }
 
-impl Stream for Channel {
-    type Item = Message;
-    fn poll_next(
-        self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
+impl Sink<Message> for ChannelSender {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<()> {
         let this = self.get_mut();
-        loop {
-            match this.inbound_rx.as_mut() {
-                None => {
-                    return Poll::Ready(None);
-                }
-                Some(ref mut inbound_rx) => {
-                    let message = ready!(Pin::new(inbound_rx).poll_next(cx));
-                    match message {
-                        Some(Message::Extension(msg)) => {
-                            this.extensions.on_message(msg);
-                        }
-                        Some(Message::Options(ref msg)) => {
-                            this.extensions.on_remote_update(msg.extensions.clone());
-                            return Poll::Ready(message);
-                        }
-                        _ => return Poll::Ready(message),
-                    }
-                }
-            }
+        this.check_send(&message)?;
+        let message = ChannelMessage::new(this.local_id as u64, message);
+        let outbound_tx = this.outbound_tx.clone();
+        this.sending = Some(Box::pin(async move {
+            outbound_tx.send(message).await.map_err(map_channel_err)
+        }));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let Some(sending) = this.sending.as_mut() {
+            let result = ready!(sending.as_mut().poll(cx));
+            this.sending = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsRef<ChannelSender> for ChannelSender {
+    fn as_ref(&self) -> &ChannelSender {
+        self
+    }
+}
+
+/// The receiving half of a [`Channel`], created with [`Channel::split`].
+///
+/// A stream of the messages the remote sent on the channel. Extension
+/// messages are not part of the stream, they are handed to their
+/// [`Extension`]s by the protocol.
+pub struct ChannelReceiver {
+    inbound_rx: Option<Receiver<Message>>,
+    discovery_key: DiscoveryKey,
+}
+
+impl fmt::Debug for ChannelReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelReceiver")
+            .field("discovery_key", &pretty_hash(&self.discovery_key))
+            .finish()
+    }
+}
+
+impl ChannelReceiver {
+    /// Get the discovery key of this channel.
+    pub fn discovery_key(&self) -> &[u8; 32] {
+        &self.discovery_key
+    }
+}
+
+impl Stream for ChannelReceiver {
+    type Item = Message;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut().inbound_rx.as_mut() {
+            Some(inbound_rx) => Pin::new(inbound_rx).poll_next(cx),
+            None => Poll::Ready(None),
         }
     }
 }
 
+/// An inbound message that waits for space in a channel's inbound queue.
+pub(crate) struct PendingInbound(Pin<Box<dyn Future<Output = ()> + Send>>);
+
+impl PendingInbound {
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for PendingInbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PendingInbound")
+    }
+}
+
 /// The handle for a channel that lives with the main Protocol.
 #[derive(Clone, Debug)]
 pub(crate) struct ChannelHandle {
@@ -189,7 +514,17 @@ pub(crate) struct ChannelHandle {
     local_state: Option<LocalState>,
     remote_state: Option<RemoteState>,
     inbound_tx: Option<Sender<Message>>,
+    /// Messages that the remote sent before the channel was opened locally.
+    early_messages: Vec<Message>,
+    /// The encoded size of the early messages.
+    early_bytes: usize,
     closed: Arc<AtomicBool>,
+    stats: Arc<Mutex<ChannelStats>>,
+    requests: Requests,
+    /// The channel's extensions, once it is open.
+    extensions: Option<Arc<Mutex<Extensions>>>,
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    span: Span,
 }
 
 #[derive(Clone, Debug)]
@@ -205,17 +540,28 @@ struct RemoteState {
 }
 
 impl ChannelHandle {
-    fn new(discovery_key: DiscoveryKey) -> Self {
+    fn new(discovery_key: DiscoveryKey, parent_span: &Span) -> Self {
         Self {
+            span: channel_span(parent_span, &discovery_key),
             discovery_key,
             local_state: None,
             remote_state: None,
             inbound_tx: None,
+            early_messages: vec![],
+            early_bytes: 0,
             closed: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(Mutex::new(ChannelStats::default())),
+            requests: Requests::default(),
+            extensions: None,
         }
     }
-    fn new_local(local_id: usize, discovery_key: DiscoveryKey, key: Key) -> Self {
-        let mut this = Self::new(discovery_key);
+    fn new_local(
+        local_id: usize,
+        discovery_key: DiscoveryKey,
+        key: Key,
+        parent_span: &Span,
+    ) -> Self {
+        let mut this = Self::new(discovery_key, parent_span);
         this.attach_local(local_id, key);
         this
     }
@@ -224,8 +570,9 @@ impl ChannelHandle {
         remote_id: usize,
         discovery_key: DiscoveryKey,
         remote_capability: Option<Vec<u8>>,
+        parent_span: &Span,
     ) -> Self {
-        let mut this = Self::new(discovery_key);
+        let mut this = Self::new(discovery_key, parent_span);
         this.attach_remote(remote_id, remote_capability);
         this
     }
@@ -243,11 +590,13 @@ impl ChannelHandle {
     }
 
     pub fn attach_local(&mut self, local_id: usize, key: Key) {
+        trace_event!(debug, parent: &self.span, local_id, "local opened channel");
         let local_state = LocalState { local_id, key };
         self.local_state = Some(local_state);
     }
 
     pub fn attach_remote(&mut self, remote_id: usize, remote_capability: Option<Vec<u8>>) {
+        trace_event!(debug, parent: &self.span, remote_id, "remote opened channel");
         let remote_state = RemoteState {
             remote_id,
             remote_capability,
@@ -261,7 +610,7 @@ impl ChannelHandle {
 
     pub fn prepare_to_verify(&self) -> Result<(&Key, Option<&Vec<u8>>)> {
         if !self.is_connected() {
-            return Err(error("Channel is not opened from both local and remote"));
+            return Err(Error::ChannelNotOpen);
         }
         // Safe because of the is_connected() check above.
         let local_state = self.local_state.as_ref().unwrap();
@@ -269,33 +618,117 @@ impl ChannelHandle {
         Ok((&local_state.key, remote_state.remote_capability.as_ref()))
     }
 
-    pub fn open(&mut self, outbound_tx: Sender<ChannelMessage>) -> Channel {
+    pub fn open(
+        &mut self,
+        outbound_tx: Sender<ChannelMessage>,
+        capacity: usize,
+        version: Version,
+    ) -> Channel {
         let local_state = self
             .local_state
             .as_ref()
             .expect("May not open channel that is not locally attached");
 
-        let (inbound_tx, inbound_rx) = async_channel::unbounded();
+        trace_event!(debug, parent: &self.span, "channel open");
+        let (inbound_tx, inbound_rx) = async_channel::bounded(capacity);
+        let mut extensions = Extensions::new(outbound_tx.clone(), local_state.local_id as u64);
+        // At most `capacity` messages are buffered, so they all fit.
+        self.early_bytes = 0;
+        for message in self.early_messages.drain(..) {
+            if let Message::Options(msg) = &message {
+                // No extension is registered yet, so there is nothing to send.
+                extensions.on_remote_update(msg.extensions.clone());
+            }
+            let _ = inbound_tx.try_send(message);
+        }
+        let extensions = Arc::new(Mutex::new(extensions));
         let channel = Channel {
-            inbound_rx: Some(inbound_rx),
-            outbound_tx: outbound_tx.clone(),
-            discovery_key: self.discovery_key,
-            key: local_state.key,
-            local_id: local_state.local_id,
-            extensions: Extensions::new(outbound_tx, local_state.local_id as u64),
-            closed: self.closed.clone(),
+            sender: ChannelSender {
+                outbound_tx,
+                discovery_key: self.discovery_key,
+                key: local_state.key,
+                local_id: local_state.local_id,
+                version,
+                extensions: extensions.clone(),
+                closed: self.closed.clone(),
+                stats: self.stats.clone(),
+                requests: self.requests.clone(),
+                sending: None,
+            },
+            receiver: ChannelReceiver {
+                inbound_rx: Some(inbound_rx),
+                discovery_key: self.discovery_key,
+            },
         };
         self.inbound_tx = Some(inbound_tx);
+        self.extensions = Some(extensions);
         channel
     }
 
-    pub fn try_send_inbound(&mut self, message: Message) -> std::io::Result<()> {
-        if let Some(inbound_tx) = self.inbound_tx.as_mut() {
-            inbound_tx
-                .try_send(message)
-                .map_err(|_e| error("Channel is full"))
-        } else {
-            Err(error("Channel is not open"))
+    pub fn is_open(&self) -> bool {
+        self.inbound_tx.is_some()
+    }
+
+    pub fn stats(&self) -> &Mutex<ChannelStats> {
+        &self.stats
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn extensions(&self) -> Option<&Mutex<Extensions>> {
+        self.extensions.as_deref()
+    }
+
+    /// Hand an extension message to its extension. Messages that arrive
+    /// before the channel is open are discarded.
+    pub fn on_extension_message(&self, message: ExtensionMessage) {
+        if let Some(extensions) = &self.extensions {
+            extensions.lock().unwrap().on_message(message);
+        }
+    }
+
+    /// Hand a message to the extension `name`. Messages that arrive before
+    /// the channel is open are discarded.
+    #[cfg(feature = "v10")]
+    pub fn on_named_extension_message(&self, name: &str, message: Bytes) {
+        if let Some(extensions) = &self.extensions {
+            extensions.lock().unwrap().on_named_message(name, message);
+        }
+    }
+
+    /// Apply the extension names that the remote announced.
+    pub fn on_remote_extensions(&self, names: Vec<String>) -> RemoteUpdate {
+        match &self.extensions {
+            Some(extensions) => extensions.lock().unwrap().on_remote_update(names),
+            None => RemoteUpdate::default(),
+        }
+    }
+
+    /// Send a message to the channel.
+    ///
+    /// If the channel's inbound queue is full, the returned [`PendingInbound`]
+    /// completes once the message was delivered.
+    pub fn try_send_inbound(&mut self, message: Message) -> Result<Option<PendingInbound>> {
+        if let Message::Data(data) = &message {
+            if self.requests.on_data(data) {
+                return Ok(None);
+            }
+        }
+        let inbound_tx = self.inbound_tx.as_ref().ok_or(Error::ChannelNotOpen)?;
+        match inbound_tx.try_send(message) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(message)) => {
+                let inbound_tx = inbound_tx.clone();
+                Ok(Some(PendingInbound(Box::pin(async move {
+                    // If the channel is dropped in the meantime, the message is discarded.
+                    let _ = inbound_tx.send(message).await;
+                }))))
+            }
+            // The receiver was dropped, e.g. to only send on a split channel.
+            Err(TrySendError::Closed(_)) => Ok(None),
         }
     }
 }
@@ -303,6 +736,7 @@ impl ChannelHandle {
 impl Drop for ChannelHandle {
     fn drop(&mut self) {
         self.closed.store(true, Ordering::SeqCst);
+        self.requests.close();
     }
 }
 
@@ -312,16 +746,26 @@ pub(crate) struct ChannelMap {
     channels: HashMap<String, ChannelHandle>,
     local_id: Vec<Option<String>>,
     remote_id: Vec<Option<String>>,
+    /// The encoded size of the early messages of all channels.
+    early_bytes: usize,
+    /// The local ID that close messages for channels that were only opened
+    /// by the remote are sent on.
+    close_id: Option<usize>,
+    /// The span of the protocol, the parent of the channel spans.
+    span: Span,
 }
 
 impl ChannelMap {
-    pub fn new() -> Self {
+    pub fn new(span: Span) -> Self {
         Self {
             channels: HashMap::new(),
+            span,
             // Add a first None value to local_id to start ids at 1.
             // This makes sure that 0 may be used for stream-level extensions.
             local_id: vec![None],
             remote_id: vec![],
+            early_bytes: 0,
+            close_id: None,
         }
     }
 
@@ -329,11 +773,12 @@ impl ChannelMap {
         let discovery_key = discovery_key(&key);
         let hdkey = hex::encode(&discovery_key);
         let local_id = self.alloc_local();
+        let span = &self.span;
 
         self.channels
             .entry(hdkey.clone())
             .and_modify(|channel| channel.attach_local(local_id, key))
-            .or_insert_with(|| ChannelHandle::new_local(local_id, discovery_key, key));
+            .or_insert_with(|| ChannelHandle::new_local(local_id, discovery_key, key, span));
 
         self.local_id[local_id] = Some(hdkey.clone());
         self.channels.get(&hdkey).unwrap()
@@ -347,16 +792,25 @@ impl ChannelMap {
     ) -> &ChannelHandle {
         let hdkey = hex::encode(&discovery_key);
         self.alloc_remote(remote_id);
+        let span = &self.span;
         self.channels
             .entry(hdkey.clone())
             .and_modify(|channel| channel.attach_remote(remote_id, remote_capability.clone()))
             .or_insert_with(|| {
-                ChannelHandle::new_remote(remote_id, discovery_key, remote_capability)
+                ChannelHandle::new_remote(remote_id, discovery_key, remote_capability, span)
             });
         self.remote_id[remote_id] = Some(hdkey.clone());
         self.channels.get(&hdkey).unwrap()
     }
 
+    pub fn get(&self, discovery_key: &[u8]) -> Option<&ChannelHandle> {
+        self.channels.get(&hex::encode(discovery_key))
+    }
+
+    pub fn get_mut(&mut self, discovery_key: &[u8]) -> Option<&mut ChannelHandle> {
+        self.channels.get_mut(&hex::encode(discovery_key))
+    }
+
     pub fn get_remote_mut(&mut self, remote_id: usize) -> Option<&mut ChannelHandle> {
         if let Some(Some(hdkey)) = self.remote_id.get(remote_id).as_ref() {
             self.channels.get_mut(hdkey)
@@ -399,14 +853,14 @@ impl ChannelMap {
             if let Some(remote_id) = channel.remote_id() {
                 self.remote_id[remote_id] = None;
             }
+            self.early_bytes -= channel.early_bytes;
+            trace_event!(debug, parent: channel.span(), "channel closed");
         }
         self.channels.remove(&hdkey);
     }
 
     pub fn prepare_to_verify(&self, local_id: usize) -> Result<(&Key, Option<&Vec<u8>>)> {
-        let channel_handle = self
-            .get_local(local_id)
-            .ok_or_else(|| error("Channel not found"))?;
+        let channel_handle = self.get_local(local_id).ok_or(Error::ChannelNotOpen)?;
         channel_handle.prepare_to_verify()
     }
 
@@ -414,28 +868,62 @@ impl ChannelMap {
         &mut self,
         local_id: usize,
         outbound_tx: Sender<ChannelMessage>,
+        capacity: usize,
+        version: Version,
     ) -> Result<Channel> {
-        let channel_handle = self
-            .get_local_mut(local_id)
-            .ok_or_else(|| error("Channel not found"))?;
+        let channel_handle = self.get_local_mut(local_id).ok_or(Error::ChannelNotOpen)?;
         if !channel_handle.is_connected() {
-            return Err(error("Channel is not opened from remote"));
+            return Err(Error::ChannelNotOpen);
         }
-        let channel = channel_handle.open(outbound_tx);
+        let early_bytes = channel_handle.early_bytes;
+        let channel = channel_handle.open(outbound_tx, capacity, version);
+        self.early_bytes -= early_bytes;
         Ok(channel)
     }
 
-    pub fn forward_inbound_message(&mut self, remote_id: usize, message: Message) -> Result<()> {
-        if let Some(channel_handle) = self.get_remote_mut(remote_id) {
-            channel_handle.try_send_inbound(message)?;
+    /// Send a message to the channel with the remote ID `remote_id`.
+    ///
+    /// If the channel was not yet opened locally, the message is kept until
+    /// it is opened. See [`ChannelMap::early_messages_full`] for the limits.
+    pub fn forward_inbound_message(
+        &mut self,
+        remote_id: usize,
+        message: Message,
+    ) -> Result<Option<PendingInbound>> {
+        match self.get_remote_mut(remote_id) {
+            Some(channel_handle) if channel_handle.is_open() => {
+                channel_handle.try_send_inbound(message)
+            }
+            Some(channel_handle) => {
+                let len = message.encoded_len();
+                channel_handle.early_messages.push(message);
+                channel_handle.early_bytes += len;
+                self.early_bytes += len;
+                Ok(None)
+            }
+            None => Ok(None),
         }
-        Ok(())
     }
 
-    fn alloc_local(&mut self) -> usize {
+    /// Check if the early messages of channels that were not yet opened
+    /// locally take `max_bytes` or more, or if a channel has `capacity`
+    /// early messages. No more messages may be read then.
+    pub fn early_messages_full(&self, capacity: usize, max_bytes: usize) -> bool {
+        self.early_bytes >= max_bytes
+            || self
+                .channels
+                .values()
+                .any(|channel| channel.early_messages.len() >= capacity)
+    }
+
+    /// Find a free local channel ID.
+    ///
+    /// The ID is only reserved once it is assigned in `self.local_id`.
+    pub fn alloc_local(&mut self) -> usize {
         let empty_id = self.local_id.iter().skip(1).position(|x| x.is_none());
         match empty_id {
-            Some(empty_id) => empty_id,
+            // Add one because the first (stream-level) ID was skipped.
+            Some(empty_id) => empty_id + 1,
             None => {
                 self.local_id.push(None);
                 self.local_id.len() - 1
@@ -443,6 +931,23 @@ impl ChannelMap {
         }
     }
 
+    /// Get the local ID for close messages of channels that were only opened
+    /// by the remote.
+    ///
+    /// The remote knows these channels by their discovery key, which the
+    /// close message includes. The ID stays reserved, so that the remote never
+    /// confuses it with a channel that is opened locally later.
+    pub fn close_id(&mut self) -> usize {
+        if let Some(close_id) = self.close_id {
+            return close_id;
+        }
+        let close_id = self.alloc_local();
+        // No channel has an empty key, so the ID is taken without a channel.
+        self.local_id[close_id] = Some(String::new());
+        self.close_id = Some(close_id);
+        close_id
+    }
+
     fn alloc_remote(&mut self, id: usize) {
         if self.remote_id.len() > id {
             self.remote_id[id] = None;
@@ -451,11 +956,20 @@ impl ChannelMap {
         }
     }
 
+    /// Number of channels, including channels that are only opened on one side.
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Number of channels that were only opened by the remote.
+    pub fn pending_opens(&self) -> usize {
+        self.channels
+            .values()
+            .filter(|channel| channel.local_id().is_none())
+            .count()
+    }
+
     pub(crate) fn iter(&self) -> impl Iterator<Item = &ChannelHandle> {
         self.channels.values()
     }
 }
-
-fn error(message: &str) -> Error {
-    Error::new(ErrorKind::Other, message)
-}
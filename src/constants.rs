@@ -10,5 +10,28 @@ pub const DEFAULT_TIMEOUT: u32 = 20;
 /// Default keepalive interval (in seconds)
 pub const DEFAULT_KEEPALIVE: u32 = 10;
 
+/// Default handshake timeout (in seconds)
+pub const DEFAULT_HANDSHAKE_TIMEOUT: u32 = 20;
+
+/// Default number of inbound messages that are buffered for each channel
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1000;
+
+/// Default maximum number of channels per connection
+pub const DEFAULT_MAX_CHANNELS: usize = 1024;
+
+/// Default maximum channel ID that the remote may use
+pub const DEFAULT_MAX_REMOTE_CHANNEL_ID: u64 = 4096;
+
+/// Default maximum number of extensions that the remote may announce at once
+pub const DEFAULT_MAX_EXTENSIONS: usize = 64;
+
+/// Default maximum number of channels that the remote opened and that were
+/// not yet opened or rejected locally
+pub const DEFAULT_MAX_PENDING_OPENS: usize = 256;
+
+/// Default maximum number of bytes of messages that are buffered for channels
+/// that the remote opened and that were not yet opened locally
+pub const DEFAULT_MAX_PENDING_BYTES: usize = 16 * 1024 * 1024;
+
 // 4MB is the max wire message size (will be much smaller usually).
 pub const MAX_MESSAGE_SIZE: u64 = 1024 * 1024 * 4;
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::io;
+
+use crate::version::Version;
+
+/// A protocol error.
+///
+/// Errors can be converted into [`std::io::Error`], and [`Error::kind`] returns
+/// the [`io::ErrorKind`] that is used for the conversion.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    /// The Noise handshake failed.
+    Handshake(String),
+    /// The remote did not prove that it knows the key of a channel.
+    CapabilityMismatch,
+    /// The remote peer was rejected by the [`Authorizer`](crate::Authorizer).
+    Unauthorized,
+    /// The peers do not support a common [`Version`](crate::Version).
+    VersionMismatch,
+    /// The peers use different [`Encryption`](crate::Encryption) modes.
+    EncryptionMismatch,
+    /// The remote did not send any data within the timeout.
+    Timeout,
+    /// A message exceeds the maximum allowed size.
+    OversizeFrame(usize),
+    /// A message could not be decoded. The source is the
+    /// [`prost::DecodeError`] for protobuf messages.
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+    /// A frame failed authentication (see [`Encryption::Authenticated`](crate::Encryption::Authenticated)).
+    Decrypt,
+    /// The channel is closed.
+    ChannelClosed,
+    /// The channel is not opened from both the local and the remote side.
+    ChannelNotOpen,
+    /// A message was sent on a channel whose protocol [`Version`] does not
+    /// have this message.
+    UnsupportedMessage(Version),
+    /// A message was sent on an [`Extension`](crate::Extension) that the
+    /// remote has not enabled, see [`InactivePolicy`](crate::InactivePolicy).
+    ExtensionInactive(String),
+    /// A key or keypair is invalid.
+    InvalidKey(String),
+    /// The [`Options`](crate::Options) of the protocol are invalid.
+    InvalidOptions(String),
+    /// The remote exceeded one of the limits in [`Options`](crate::Options).
+    LimitExceeded(String),
+    /// The storage of a replicated [`Feed`](crate::replication::Feed) failed,
+    /// or a block could not be verified.
+    Feed(Box<dyn std::error::Error + Send + Sync>),
+    /// An IO error.
+    Io(io::Error),
+}
+
+/// A [`Result`](std::result::Result) with the protocol [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The [`io::ErrorKind`] of this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Handshake(_) => io::ErrorKind::PermissionDenied,
+            Error::CapabilityMismatch => io::ErrorKind::PermissionDenied,
+            Error::Unauthorized => io::ErrorKind::ConnectionRefused,
+            Error::VersionMismatch => io::ErrorKind::ConnectionRefused,
+            Error::EncryptionMismatch => io::ErrorKind::ConnectionRefused,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::OversizeFrame(_) => io::ErrorKind::InvalidData,
+            Error::Decode(_) => io::ErrorKind::InvalidData,
+            Error::Decrypt => io::ErrorKind::InvalidData,
+            Error::ChannelClosed => io::ErrorKind::ConnectionAborted,
+            Error::ChannelNotOpen => io::ErrorKind::NotConnected,
+            Error::UnsupportedMessage(_) => io::ErrorKind::InvalidInput,
+            Error::ExtensionInactive(_) => io::ErrorKind::NotConnected,
+            Error::InvalidKey(_) => io::ErrorKind::InvalidInput,
+            Error::InvalidOptions(_) => io::ErrorKind::InvalidInput,
+            Error::LimitExceeded(_) => io::ErrorKind::InvalidData,
+            Error::Feed(_) => io::ErrorKind::Other,
+            Error::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Handshake(reason) => write!(f, "Handshake failed: {}", reason),
+            Error::CapabilityMismatch => write!(f, "Invalid remote channel capability"),
+            Error::Unauthorized => write!(f, "Remote peer was rejected by the authorizer"),
+            Error::VersionMismatch => write!(f, "No common protocol version with remote peer"),
+            Error::EncryptionMismatch => {
+                write!(f, "Remote peer uses a different transport encryption")
+            }
+            Error::Timeout => write!(f, "Remote timed out"),
+            Error::OversizeFrame(len) => {
+                write!(f, "Message length above max allowed size (length {})", len)
+            }
+            Error::Decode(reason) => write!(f, "Cannot decode message: {}", reason),
+            Error::Decrypt => write!(f, "Frame failed authentication"),
+            Error::ChannelClosed => write!(f, "Channel is closed"),
+            Error::ChannelNotOpen => {
+                write!(f, "Channel is not opened from both local and remote")
+            }
+            Error::UnsupportedMessage(version) => {
+                write!(
+                    f,
+                    "Message is not supported by protocol version {:?}",
+                    version
+                )
+            }
+            Error::ExtensionInactive(name) => {
+                write!(f, "Extension {} is not enabled by the remote", name)
+            }
+            Error::InvalidKey(reason) => write!(f, "Invalid key: {}", reason),
+            Error::InvalidOptions(reason) => write!(f, "Invalid options: {}", reason),
+            Error::LimitExceeded(reason) => write!(f, "Remote exceeded a limit: {}", reason),
+            Error::Feed(e) => write!(f, "Feed error: {}", e),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Decode(e) => Some(e.as_ref()),
+            Error::Feed(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(e: prost::DecodeError) -> Self {
+        Error::Decode(Box::new(e))
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_kind(error: Error) -> io::ErrorKind {
+        io::Error::from(error).kind()
+    }
+
+    #[test]
+    fn io_error_kinds() {
+        use io::ErrorKind::*;
+        assert_eq!(io_kind(Error::Handshake("".into())), PermissionDenied);
+        assert_eq!(io_kind(Error::CapabilityMismatch), PermissionDenied);
+        assert_eq!(io_kind(Error::Unauthorized), ConnectionRefused);
+        assert_eq!(io_kind(Error::VersionMismatch), ConnectionRefused);
+        assert_eq!(io_kind(Error::EncryptionMismatch), ConnectionRefused);
+        assert_eq!(io_kind(Error::Timeout), TimedOut);
+        assert_eq!(io_kind(Error::OversizeFrame(0)), InvalidData);
+        assert_eq!(io_kind(Error::Decode("".into())), InvalidData);
+        assert_eq!(io_kind(Error::Decrypt), InvalidData);
+        assert_eq!(io_kind(Error::ChannelClosed), ConnectionAborted);
+        assert_eq!(io_kind(Error::ChannelNotOpen), NotConnected);
+        assert_eq!(
+            io_kind(Error::UnsupportedMessage(Version::V1)),
+            InvalidInput
+        );
+        assert_eq!(io_kind(Error::ExtensionInactive("".into())), NotConnected);
+        assert_eq!(io_kind(Error::InvalidKey("".into())), InvalidInput);
+        assert_eq!(io_kind(Error::InvalidOptions("".into())), InvalidInput);
+        assert_eq!(io_kind(Error::LimitExceeded("".into())), InvalidData);
+        assert_eq!(io_kind(Error::Feed("".into())), Other);
+        assert_eq!(io_kind(Error::Io(io::Error::from(BrokenPipe))), BrokenPipe);
+    }
+
+    #[test]
+    fn decode_error_keeps_source() {
+        use prost::Message;
+        use std::error::Error as _;
+        let error = Error::from(crate::schema::Open::decode(&[0xff][..]).unwrap_err());
+        assert!(error.source().unwrap().is::<prost::DecodeError>());
+    }
+
+    #[test]
+    fn io_error_keeps_source() {
+        let error = io::Error::from(Error::Timeout);
+        let inner = error.into_inner().unwrap();
+        assert!(matches!(
+            inner.downcast_ref::<Error>(),
+            Some(Error::Timeout)
+        ));
+    }
+}
@@ -1,12 +1,18 @@
 use crate::constants::MAX_MESSAGE_SIZE;
+use crate::error::{Error, Result};
 use crate::message::{ChannelMessage, ExtensionMessage, Message};
 use crate::schema::*;
+use crate::util::map_channel_err;
 use async_channel::{Receiver, Sender};
-use futures_lite::{ready, AsyncRead, AsyncWrite, FutureExt, Stream};
+use bytes::Bytes;
+use futures_lite::{future, ready, AsyncRead, AsyncWrite, FutureExt, Stream};
+use futures_sink::Sink;
 use std::collections::HashMap;
 use std::future::Future;
 use std::io;
+use std::mem;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 
 const MAX_BODY_SIZE: usize = MAX_MESSAGE_SIZE as usize - 16;
@@ -15,7 +21,11 @@ const MAX_BODY_SIZE: usize = MAX_MESSAGE_SIZE as usize - 16;
 pub struct Extensions {
     extensions: HashMap<String, ExtensionHandle>,
     channel: u64,
+    /// The names of the local extensions, sorted.
     local_ids: Vec<String>,
+    /// The names that were last sent to the remote. The position of a name
+    /// is the ID of its messages.
+    announced_ids: Vec<String>,
     remote_ids: Vec<String>,
     outbound_tx: Sender<ChannelMessage>,
 }
@@ -26,33 +36,58 @@ impl Extensions {
             channel,
             extensions: HashMap::new(),
             local_ids: vec![],
+            announced_ids: vec![],
             remote_ids: vec![],
             outbound_tx,
         }
     }
 
-    pub fn add_local_name(&mut self, name: String) -> u64 {
-        self.local_ids.push(name.clone());
-        self.local_ids.sort();
-        let local_id = self.local_ids.iter().position(|x| x == &name).unwrap();
-        local_id as u64
+    pub fn add_local_name(&mut self, name: String) {
+        if let Err(position) = self.local_ids.binary_search(&name) {
+            self.local_ids.insert(position, name);
+        }
     }
 
     pub async fn register(&mut self, name: String) -> Extension {
-        let local_id = self.add_local_name(name.clone());
+        let (extension, message) = self.register_local(name);
+        // If the protocol is gone, the extension never receives messages.
+        let _ = self.outbound_tx.send(message).await;
+        extension
+    }
+
+    /// Register an extension and return the options message that announces
+    /// it to the remote.
+    ///
+    /// A new name may change the IDs of the other extensions. Their messages
+    /// keep the IDs of the options that were sent before them, because IDs
+    /// are only set when the protocol writes a message, see [`Extensions::wire_id`].
+    pub fn register_local(&mut self, name: String) -> (Extension, ChannelMessage) {
+        self.add_local_name(name.clone());
         let (inbound_tx, inbound_rx) = async_channel::unbounded();
+        let (events_tx, events_rx) = async_channel::unbounded();
+        let active = self.remote_ids.contains(&name);
+        if active {
+            let _ = events_tx.try_send(ExtensionEvent::RemoteEnabled);
+        }
+        let state = Arc::new(Mutex::new(ExtensionState {
+            active,
+            policy: InactivePolicy::Send,
+            buffer: vec![],
+        }));
         let handle = ExtensionHandle {
             name: name.clone(),
             channel: self.channel,
-            local_id,
             inbound_tx,
+            events_tx,
+            state: state.clone(),
         };
         let extension = Extension {
             name: name.clone(),
             channel: self.channel,
-            local_id,
             outbound_tx: self.outbound_tx.clone(),
             inbound_rx,
+            events_rx,
+            state,
             write_state: WriteState::Idle,
             read_state: None,
         };
@@ -63,13 +98,51 @@ impl Extensions {
             ack: None,
         };
         let message = ChannelMessage::new(self.channel, Message::Options(message));
-        self.outbound_tx.send(message).await.unwrap();
+        (extension, message)
+    }
 
-        extension
+    /// Replace the extension names of the remote, and activate or deactivate
+    /// the local extensions accordingly.
+    pub fn on_remote_update(&mut self, names: Vec<String>) -> RemoteUpdate {
+        let previous = mem::replace(&mut self.remote_ids, names);
+        let mut update = RemoteUpdate::default();
+        for name in self.remote_ids.iter() {
+            if previous.contains(name) || update.events.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            update
+                .events
+                .push((name.clone(), ExtensionEvent::RemoteEnabled));
+            if let Some(handle) = self.extensions.get(name) {
+                update.messages.extend(handle.set_active(true));
+            }
+        }
+        for name in previous.iter() {
+            if self.remote_ids.contains(name) || update.events.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            update
+                .events
+                .push((name.clone(), ExtensionEvent::RemoteDisabled));
+            if let Some(handle) = self.extensions.get(name) {
+                handle.set_active(false);
+            }
+        }
+        update
+    }
+
+    /// Remember the extension names that are written to the remote.
+    pub fn on_local_update(&mut self, names: &[String]) {
+        self.announced_ids = names.to_vec();
     }
 
-    pub fn on_remote_update(&mut self, names: Vec<String>) {
-        self.remote_ids = names;
+    /// The ID of the extension `name` in the names that were last written
+    /// to the remote.
+    pub fn wire_id(&self, name: &str) -> Option<u64> {
+        self.announced_ids
+            .iter()
+            .position(|x| x == name)
+            .map(|id| id as u64)
     }
 
     pub fn on_message(&mut self, message: ExtensionMessage) {
@@ -80,22 +153,102 @@ impl Extensions {
             }
         }
     }
+
+    /// Hand a message to the local extension `name`. v10 names the
+    /// extension in each message instead of announcing ids.
+    #[cfg(feature = "v10")]
+    pub fn on_named_message(&mut self, name: &str, message: Bytes) {
+        if let Some(handle) = self.extensions.get_mut(name) {
+            handle.inbound_send(message);
+        }
+    }
+}
+
+/// The changes after the remote announced its extensions.
+#[derive(Debug, Default)]
+pub struct RemoteUpdate {
+    /// The names that the remote enabled or disabled.
+    pub events: Vec<(String, ExtensionEvent)>,
+    /// The messages that were buffered on extensions that are now active.
+    pub messages: Vec<ChannelMessage>,
 }
 
 #[derive(Debug)]
 pub struct ExtensionHandle {
     name: String,
     channel: u64,
-    local_id: u64,
-    inbound_tx: Sender<Vec<u8>>,
+    inbound_tx: Sender<Bytes>,
+    events_tx: Sender<ExtensionEvent>,
+    state: Arc<Mutex<ExtensionState>>,
 }
 
 impl ExtensionHandle {
-    fn inbound_send(&mut self, message: Vec<u8>) {
+    fn inbound_send(&mut self, message: Bytes) {
         // This should be safe because inbound_tx is an unbounded channel,
         // and is only dropped when the whole channel is dropped.
         let _ = self.inbound_tx.try_send(message);
     }
+
+    /// Activate or deactivate the extension. Returns the buffered messages
+    /// once it is activated.
+    fn set_active(&self, active: bool) -> Vec<ChannelMessage> {
+        let mut state = self.state.lock().unwrap();
+        if state.active == active {
+            return vec![];
+        }
+        state.active = active;
+        let event = if active {
+            ExtensionEvent::RemoteEnabled
+        } else {
+            ExtensionEvent::RemoteDisabled
+        };
+        let _ = self.events_tx.try_send(event);
+        if !active {
+            return vec![];
+        }
+        log::debug!(
+            "extension {} enabled by remote, sending {} buffered messages",
+            self.name,
+            state.buffer.len()
+        );
+        state
+            .buffer
+            .drain(..)
+            .map(|message| ChannelMessage::extension(self.channel, &self.name, message))
+            .collect()
+    }
+}
+
+/// A change of an extension on the remote side.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionEvent {
+    /// The remote registered an extension with the same name. Messages sent
+    /// from now on reach the remote's extension.
+    RemoteEnabled,
+    /// The remote no longer lists the extension.
+    RemoteDisabled,
+}
+
+/// What happens to messages that are sent on an extension while the remote
+/// has not enabled it.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InactivePolicy {
+    /// Send the messages anyway, the remote discards them. This is the default.
+    Send,
+    /// Fail with [`Error::ExtensionInactive`].
+    Fail,
+    /// Keep up to this many messages and send them once the remote enables
+    /// the extension. Messages beyond that fail with [`Error::ExtensionInactive`].
+    Buffer(usize),
+}
+
+#[derive(Debug)]
+struct ExtensionState {
+    active: bool,
+    policy: InactivePolicy,
+    buffer: Vec<Bytes>,
 }
 
 /// A protocol extension.
@@ -104,26 +257,36 @@ impl ExtensionHandle {
 /// any [`Channel`]. An extension is identified by a string. When both peers
 /// open an extension with the same name, the extensions are connected. Then, they function as a
 /// binary duplex stream. The stream is fully encrypted, but there's no authentication
-/// performed on individual messages.
+/// performed on individual messages unless [`Encryption::Authenticated`] is used.
 ///
 /// The Extension struct implements both [`AsyncRead`] and [`AsyncWrite`]
 /// and is also a [`Stream`]. You should use the extension either as a stream or as
 /// an async reader; if being used as both, the messages would appear in either poll randomly.
+/// Likewise, messages can be sent either through [`AsyncWrite`] or as a [`Sink`] of
+/// messages, which keeps message boundaries.
+///
+/// The extension is active while the remote has an extension with the same
+/// name, see [`Extension::is_active`] and [`Extension::next_event`]. What
+/// happens to messages that are sent while it is inactive is set with
+/// [`Extension::set_inactive_policy`].
 ///
 /// [`Channel`]: crate::Channel
+/// [`Encryption::Authenticated`]: crate::Encryption::Authenticated
 /// [`Stream`]: futures_lite::Stream
 /// [`AsyncRead`]: futures_lite::AsyncRead
 /// [`AsyncWrite`]: futures_lite::AsyncWrite
+/// [`Sink`]: futures_sink::Sink
 /// [`Protocol` stream]: crate::Protocol
 #[derive(Debug)]
 pub struct Extension {
     name: String,
     channel: u64,
-    local_id: u64,
     outbound_tx: Sender<ChannelMessage>,
-    inbound_rx: Receiver<Vec<u8>>,
+    inbound_rx: Receiver<Bytes>,
+    events_rx: Receiver<ExtensionEvent>,
+    state: Arc<Mutex<ExtensionState>>,
     write_state: WriteState,
-    read_state: Option<Vec<u8>>,
+    read_state: Option<Bytes>,
 }
 
 impl std::clone::Clone for Extension {
@@ -131,16 +294,17 @@ impl std::clone::Clone for Extension {
         Self {
             name: self.name.clone(),
             channel: self.channel,
-            local_id: self.local_id,
             outbound_tx: self.outbound_tx.clone(),
             inbound_rx: self.inbound_rx.clone(),
+            events_rx: self.events_rx.clone(),
+            state: self.state.clone(),
             write_state: WriteState::Idle,
             read_state: None,
         }
     }
 }
 
-type SendFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send + Sync + 'static>>;
+type SendFuture = Pin<Box<dyn Future<Output = Result<()>> + Send + Sync + 'static>>;
 
 enum WriteState {
     Sending(SendFuture, usize),
@@ -159,34 +323,81 @@ impl std::fmt::Debug for WriteState {
 }
 
 impl Extension {
-    /// Send a message
-    pub async fn send(&self, message: Vec<u8>) {
-        let message = ExtensionMessage::new(self.local_id, message);
-        let message = ChannelMessage::new(self.channel, Message::Extension(message));
-        self.outbound_tx.send(message).await.unwrap()
+    /// Send a message.
+    ///
+    /// Fails if the extension is inactive and its [`InactivePolicy`] does
+    /// not allow to send or buffer the message.
+    pub async fn send(&self, message: impl Into<Bytes>) -> Result<()> {
+        match self.prepare(message.into())? {
+            Some(message) => self
+                .outbound_tx
+                .send(message)
+                .await
+                .map_err(map_channel_err),
+            None => Ok(()),
+        }
+    }
+
+    /// Check if the remote has enabled this extension.
+    pub fn is_active(&self) -> bool {
+        self.state.lock().unwrap().active
+    }
+
+    /// Set what happens to messages that are sent while the extension is
+    /// inactive. Applies to all clones of this extension.
+    pub fn set_inactive_policy(&self, policy: InactivePolicy) {
+        self.state.lock().unwrap().policy = policy;
     }
 
-    fn send_pinned(&self, message: Vec<u8>) -> SendFuture {
-        let message = ExtensionMessage::new(self.local_id, message);
-        let message = ChannelMessage::new(self.channel, Message::Extension(message));
-        // TODO: It would be nice to do this without cloning, but I didn't find a way so far.
-        let fut = send_message(self.outbound_tx.clone(), message);
-        Box::pin(fut)
+    /// Wait until the remote enables or disables this extension.
+    ///
+    /// If the remote had already enabled the extension when it was
+    /// registered, [`ExtensionEvent::RemoteEnabled`] is the first event.
+    /// Returns `None` once the channel or protocol is gone. Clones share the
+    /// events, each event is returned to only one of them.
+    pub async fn next_event(&mut self) -> Option<ExtensionEvent> {
+        self.events_rx.recv().await.ok()
+    }
+
+    /// Turn a message into the message that is sent now, or buffer it.
+    fn prepare(&self, message: Bytes) -> Result<Option<ChannelMessage>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.active {
+            match state.policy {
+                InactivePolicy::Send => {}
+                InactivePolicy::Fail => return Err(Error::ExtensionInactive(self.name.clone())),
+                InactivePolicy::Buffer(capacity) => {
+                    if state.buffer.len() >= capacity {
+                        return Err(Error::ExtensionInactive(self.name.clone()));
+                    }
+                    state.buffer.push(message);
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(ChannelMessage::extension(
+            self.channel,
+            &self.name,
+            message,
+        )))
+    }
+
+    fn send_pinned(&self, message: Bytes) -> SendFuture {
+        match self.prepare(message) {
+            // TODO: It would be nice to do this without cloning, but I didn't find a way so far.
+            Ok(Some(message)) => Box::pin(send_message(self.outbound_tx.clone(), message)),
+            Ok(None) => Box::pin(future::ready(Ok(()))),
+            Err(e) => Box::pin(future::ready(Err(e))),
+        }
     }
 }
 
-pub async fn send_message(
-    sender: Sender<ChannelMessage>,
-    message: ChannelMessage,
-) -> io::Result<()> {
-    sender
-        .send(message)
-        .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Interrupted, format!("Channel error: {}", e)))
+pub async fn send_message(sender: Sender<ChannelMessage>, message: ChannelMessage) -> Result<()> {
+    sender.send(message).await.map_err(map_channel_err)
 }
 
 impl Stream for Extension {
-    type Item = Vec<u8>;
+    type Item = Bytes;
     fn poll_next(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -211,7 +422,7 @@ impl AsyncRead for Extension {
         let len = message.len().min(buf.len());
         buf[..len].copy_from_slice(&message[..len]);
         if message.len() > len {
-            this.read_state = Some(message[len..].to_vec());
+            this.read_state = Some(message.slice(len..));
         } else {
             this.read_state = None
         }
@@ -230,12 +441,12 @@ impl AsyncWrite for Extension {
             match this.write_state {
                 WriteState::Idle => {
                     let len = buf.len().min(MAX_BODY_SIZE);
-                    let fut = this.send_pinned(buf.to_vec());
+                    let fut = this.send_pinned(Bytes::copy_from_slice(&buf[..len]));
                     this.write_state = WriteState::Sending(fut, len);
                 }
                 WriteState::Sending(ref mut fut, len) => {
                     let res = ready!(fut.poll(cx));
-                    let res = res.map(|_| len);
+                    let res = res.map(|_| len).map_err(io::Error::from);
                     this.write_state = WriteState::Idle;
                     return Poll::Ready(res);
                 }
@@ -251,3 +462,35 @@ impl AsyncWrite for Extension {
         Poll::Ready(Ok(()))
     }
 }
+
+impl Sink<Vec<u8>> for Extension {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Sink::<Vec<u8>>::poll_flush(self, cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Vec<u8>) -> Result<()> {
+        if message.len() > MAX_BODY_SIZE {
+            return Err(Error::OversizeFrame(message.len()));
+        }
+        let this = self.get_mut();
+        let len = message.len();
+        this.write_state = WriteState::Sending(this.send_pinned(message.into()), len);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if let WriteState::Sending(ref mut fut, _) = this.write_state {
+            let res = ready!(fut.poll(cx));
+            this.write_state = WriteState::Idle;
+            res?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Sink::<Vec<u8>>::poll_flush(self, cx)
+    }
+}
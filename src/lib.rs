@@ -14,7 +14,8 @@
 //! individual channel and on the main protocol stream. Extensions are registered with a string
 //! name and are only established if both peers register an extension with the same name. Each
 //! extension then can be used as a duplex stream. Note that individual messages on an extension
-//! stream are enrypted but not authenticated.
+//! stream are enrypted but not authenticated, unless [`Encryption::Authenticated`] is enabled
+//! with [`ProtocolBuilder::set_encryption`] on both peers.
 //!
 //! [AsyncRead]: futures_lite::AsyncRead
 //! [AsyncWrite]: futures_lite::AsyncWrite
@@ -59,7 +60,7 @@
 //!         eprintln!("{} received event {:?}", name, event);
 //!         match event {
 //!             // The handshake event is emitted after the protocol is fully established.
-//!             Event::Handshake(_remote_key) => {
+//!             Event::Handshake(_remote_key, _version) => {
 //!                 protocol.open(key.clone()).await;
 //!             },
 //!             // A Channel event is emitted for each established channel.
@@ -85,16 +86,29 @@
 // #![warn(missing_docs, missing_doc_code_examples, unreachable_pub)]
 #![warn(missing_docs, missing_doc_code_examples)]
 
+mod authorizer;
+pub mod bitfield;
 mod builder;
+pub mod capture;
 mod channels;
 mod constants;
 mod duplex;
+mod error;
 mod extension;
 mod message;
 mod noise;
 mod protocol;
 mod reader;
+pub mod replication;
+mod request;
+mod resolver;
+mod stats;
+mod throttle;
+mod trace;
 mod util;
+#[cfg(feature = "v10")]
+pub mod v10;
+mod version;
 mod writer;
 
 /// The wire messages used by the protocol.
@@ -104,10 +118,18 @@ pub mod schema {
     pub use crate::message::ExtensionMessage;
 }
 
+pub use authorizer::Authorizer;
 pub use builder::{Builder as ProtocolBuilder, Options};
-pub use channels::Channel;
+pub use channels::{Channel, ChannelReceiver, ChannelSender};
 pub use duplex::Duplex;
-pub use extension::Extension;
+pub use error::{Error, Result};
+pub use extension::{Extension, ExtensionEvent, InactivePolicy};
 pub use message::Message;
-pub use protocol::{DiscoveryKey, Event, Key, Protocol};
+pub use noise::{Encryption, Keypair};
+pub use protocol::{CloseReason, DiscoveryKey, Event, Key, Protocol};
+pub use request::Get;
+pub use resolver::Resolver;
+pub use stats::{ChannelStats, MessageCounter, MessageStats, ProtocolStats};
+pub use throttle::RateLimiter;
 pub use util::discovery_key;
+pub use version::Version;
@@ -1,10 +1,14 @@
 use crate::schema::*;
+use bytes::Bytes;
 use pretty_hash::fmt as pretty_fmt;
 use prost::Message as _;
 use std::fmt;
 use std::io;
 
 use crate::constants::MAX_MESSAGE_SIZE;
+use crate::error::Error;
+#[cfg(feature = "v10")]
+use crate::v10;
 
 /// Error if the buffer has insufficient size to encode a message.
 #[derive(Debug)]
@@ -19,7 +23,7 @@ impl fmt::Display for EncodeError {
 }
 
 impl EncodeError {
-    fn new(required: usize) -> Self {
+    pub(crate) fn new(required: usize) -> Self {
         Self { required }
     }
 }
@@ -36,6 +40,16 @@ impl From<EncodeError> for io::Error {
     }
 }
 
+impl From<EncodeError> for Error {
+    fn from(e: EncodeError) -> Self {
+        if e.required > MAX_MESSAGE_SIZE as usize {
+            Error::OversizeFrame(e.required)
+        } else {
+            Error::Io(e.into())
+        }
+    }
+}
+
 /// Encode data into a buffer.
 ///
 /// This trait is implemented on data frames and their components
@@ -72,13 +86,42 @@ pub enum FrameType {
     Message,
 }
 
+/// How frames are delimited and messages are encoded on the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// Varint length prefixes and protobuf messages ([`Version::V1`](crate::Version::V1)).
+    Varint,
+    /// 24 bit length prefixes and v10 messages ([`Version::V10`](crate::Version::V10)).
+    #[cfg(feature = "v10")]
+    Uint24,
+    /// Not known yet, the first bytes of the remote decide between
+    /// [`Framing::Varint`] and [`Framing::Uint24`].
+    #[cfg(feature = "v10")]
+    Detect,
+}
+
+/// The header of a [`Frame::Ping`]: type 11 on channel 0, which is not a
+/// message type of [`Version::V1`](crate::Version::V1).
+const PING_HEADER: u64 = 11;
+/// The header of a [`Frame::Pong`]: type 12 on channel 0.
+const PONG_HEADER: u64 = 12;
+
 /// A frame of data, either a buffer or a message.
 #[derive(Clone, PartialEq)]
 pub enum Frame {
     /// A raw binary buffer. Used in the handshaking phase.
-    Raw(Vec<u8>),
+    Raw(Bytes),
     /// A message. Used for everything after the handshake.
     Message(ChannelMessage),
+    /// A keepalive with a timestamp, sent instead of an empty keepalive to
+    /// peers that acknowledge keepalives.
+    Ping(u64),
+    /// The acknowledgement of a [`Frame::Ping`] with the timestamp of the ping.
+    Pong(u64),
+    /// A v10 message. Used for everything after the handshake of
+    /// [`Version::V10`](crate::Version::V10).
+    #[cfg(feature = "v10")]
+    V10(v10::ChannelMessage),
 }
 
 impl fmt::Debug for Frame {
@@ -86,6 +129,10 @@ impl fmt::Debug for Frame {
         match self {
             Frame::Raw(buf) => write!(f, "Frame(Raw <{}>)", buf.len()),
             Frame::Message(message) => write!(f, "Frame({:?})", message),
+            Frame::Ping(timestamp) => write!(f, "Frame(Ping {})", timestamp),
+            Frame::Pong(timestamp) => write!(f, "Frame(Pong {})", timestamp),
+            #[cfg(feature = "v10")]
+            Frame::V10(message) => write!(f, "Frame({:?})", message),
         }
     }
 }
@@ -98,25 +145,94 @@ impl From<ChannelMessage> for Frame {
 
 impl From<Vec<u8>> for Frame {
     fn from(m: Vec<u8>) -> Self {
+        Self::Raw(m.into())
+    }
+}
+
+impl From<Bytes> for Frame {
+    fn from(m: Bytes) -> Self {
         Self::Raw(m)
     }
 }
 
 impl Frame {
     /// Decode a frame from a buffer.
-    pub fn decode(buf: &[u8], frame_type: &FrameType) -> Result<Self, io::Error> {
+    ///
+    /// Message bodies are slices of `buf` and are not copied.
+    pub fn decode(buf: Bytes, frame_type: &FrameType) -> Result<Self, Error> {
         match frame_type {
-            FrameType::Raw => Ok(Frame::Raw(buf.to_vec())),
-            FrameType::Message => Ok(Frame::Message(ChannelMessage::decode(buf)?)),
+            FrameType::Raw => Ok(Frame::Raw(buf)),
+            FrameType::Message => {
+                let mut header = 0u64;
+                let header_len = varinteger::decode(&buf, &mut header);
+                match header {
+                    PING_HEADER => Ok(Frame::Ping(decode_timestamp(&buf[header_len..])?)),
+                    PONG_HEADER => Ok(Frame::Pong(decode_timestamp(&buf[header_len..])?)),
+                    _ => Ok(Frame::Message(ChannelMessage::decode(buf)?)),
+                }
+            }
+        }
+    }
+
+    /// The header and timestamp of a ping or pong.
+    fn keepalive_parts(&self) -> Option<(u64, u64)> {
+        match self {
+            Self::Ping(timestamp) => Some((PING_HEADER, *timestamp)),
+            Self::Pong(timestamp) => Some((PONG_HEADER, *timestamp)),
+            _ => None,
         }
     }
 
+    /// Encode the body of a ping or pong into `buf`.
+    fn encode_keepalive(&self, buf: &mut [u8]) -> usize {
+        let (header, timestamp) = self.keepalive_parts().unwrap();
+        let header_len = varinteger::encode(header, buf);
+        header_len + varinteger::encode(timestamp, &mut buf[header_len..])
+    }
+
     fn body_len(&self) -> usize {
         match self {
-            Self::Raw(message) => message.as_slice().encoded_len(),
+            Self::Raw(message) => message.len(),
             Self::Message(message) => message.encoded_len(),
+            Self::Ping(_) | Self::Pong(_) => {
+                let (header, timestamp) = self.keepalive_parts().unwrap();
+                varinteger::length(header) + varinteger::length(timestamp)
+            }
+            #[cfg(feature = "v10")]
+            Self::V10(message) => message.encode().len(),
         }
     }
+
+    /// Encode the body of the frame, without the length prefix.
+    #[cfg(feature = "v10")]
+    pub(crate) fn encode_body(&self) -> Result<Vec<u8>, EncodeError> {
+        match self {
+            Self::Raw(message) => Ok(message.to_vec()),
+            Self::Message(message) => {
+                let mut buf = vec![0u8; message.encoded_len()];
+                message.encode(&mut buf)?;
+                Ok(buf)
+            }
+            Self::Ping(_) | Self::Pong(_) => {
+                let mut buf = vec![0u8; self.body_len()];
+                self.encode_keepalive(&mut buf);
+                Ok(buf)
+            }
+            Self::V10(message) => Ok(message.encode()),
+        }
+    }
+}
+
+/// Decode the timestamp of a ping or pong.
+fn decode_timestamp(buf: &[u8]) -> Result<u64, Error> {
+    match buf.last() {
+        Some(byte) if byte & 0x80 == 0 && buf.len() < 10 => {
+            let mut timestamp = 0u64;
+            varinteger::decode(buf, &mut timestamp);
+            Ok(timestamp)
+        }
+        _ => Err(Error::Decode("Invalid keepalive timestamp".into())),
+    }
 }
 
 impl Encoder for Frame {
@@ -134,8 +250,11 @@ impl Encoder for Frame {
         let header_len = len - body_len;
         varinteger::encode(body_len as u64, &mut buf[..header_len]);
         match self {
-            Self::Raw(ref message) => message.as_slice().encode(&mut buf[header_len..]),
+            Self::Raw(ref message) => (&message[..]).encode(&mut buf[header_len..]),
             Self::Message(ref message) => message.encode(&mut buf[header_len..]),
+            Self::Ping(_) | Self::Pong(_) => Ok(self.encode_keepalive(&mut buf[header_len..])),
+            #[cfg(feature = "v10")]
+            Self::V10(ref message) => (&message.encode()[..]).encode(&mut buf[header_len..]),
         }?;
         Ok(len)
     }
@@ -157,11 +276,14 @@ pub enum Message {
     Data(Data),
     Close(Close),
     Extension(ExtensionMessage),
+    /// A message of [`Version::V10`](crate::Version::V10).
+    #[cfg(feature = "v10")]
+    V10(v10::Message),
 }
 
 impl Message {
     /// Decode a message from a buffer.
-    pub fn decode(buf: &[u8], typ: u64) -> io::Result<Self> {
+    pub fn decode(buf: Bytes, typ: u64) -> Result<Self, Error> {
         match typ {
             0 => Ok(Self::Open(Open::decode(buf)?)),
             1 => Ok(Self::Options(Options::decode(buf)?)),
@@ -175,9 +297,8 @@ impl Message {
             9 => Ok(Self::Data(Data::decode(buf)?)),
             10 => Ok(Self::Close(Close::decode(buf)?)),
             15 => Ok(Self::Extension(ExtensionMessage::decode(buf)?)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid message type",
+            _ => Err(Error::Decode(
+                format!("Invalid message type {}", typ).into(),
             )),
         }
     }
@@ -197,6 +318,8 @@ impl Message {
             Self::Data(_) => 9,
             Self::Close(_) => 10,
             Self::Extension(_) => 15,
+            #[cfg(feature = "v10")]
+            Self::V10(message) => message.typ(),
         }
     }
 }
@@ -216,6 +339,8 @@ impl Encoder for Message {
             Self::Data(ref message) => message.encoded_len(),
             Self::Close(ref message) => message.encoded_len(),
             Self::Extension(ref message) => message.encoded_len(),
+            #[cfg(feature = "v10")]
+            Self::V10(ref message) => message.body().len(),
         }
     }
 
@@ -233,6 +358,8 @@ impl Encoder for Message {
             Self::Data(ref message) => encode_prost_message(message, buf),
             Self::Close(ref message) => encode_prost_message(message, buf),
             Self::Extension(ref message) => message.encode(buf),
+            #[cfg(feature = "v10")]
+            Self::V10(ref message) => (&message.body()[..]).encode(buf),
         }
     }
 }
@@ -273,6 +400,9 @@ impl fmt::Display for Message {
 pub struct ChannelMessage {
     pub channel: u64,
     pub message: Message,
+    /// The name of the extension that sent this message. The protocol sets
+    /// the extension ID when the message is written.
+    pub(crate) extension: Option<String>,
 }
 
 impl fmt::Debug for ChannelMessage {
@@ -284,7 +414,21 @@ impl fmt::Debug for ChannelMessage {
 impl ChannelMessage {
     /// Create a new message.
     pub fn new(channel: u64, message: Message) -> Self {
-        Self { channel, message }
+        Self {
+            channel,
+            message,
+            extension: None,
+        }
+    }
+
+    /// Create a message of the extension `name`.
+    pub(crate) fn extension(channel: u64, name: &str, message: Bytes) -> Self {
+        let message = Message::Extension(ExtensionMessage::new(0, message));
+        Self {
+            channel,
+            message,
+            extension: Some(name.to_string()),
+        }
     }
 
     /// Consume self and return (channel, Message).
@@ -296,23 +440,17 @@ impl ChannelMessage {
     ///
     /// Note: `buf` has to have a valid length, and the length
     /// prefix has to be removed already.
-    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+    pub fn decode(buf: Bytes) -> Result<Self, Error> {
         if buf.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "received empty message",
-            ));
+            return Err(Error::Decode("Received empty message".into()));
         }
         let mut header = 0u64;
         let headerlen = varinteger::decode(&buf, &mut header);
-        // let body = buf.split_off(headerlen);
         let channel = header >> 4;
         let typ = header & 0b1111;
-        let message = Message::decode(&buf[headerlen..], typ)?;
-
-        let channel_message = Self { channel, message };
+        let message = Message::decode(buf.slice(headerlen..), typ)?;
 
-        Ok(channel_message)
+        Ok(Self::new(channel, message))
     }
 
     fn header(&self) -> u64 {
@@ -348,28 +486,28 @@ pub struct ExtensionMessage {
     /// ID of this extension
     pub id: u64,
     /// Message content
-    pub message: Vec<u8>,
+    pub message: Bytes,
 }
 
 impl ExtensionMessage {
     /// Create a new extension message.
-    pub fn new(id: u64, message: Vec<u8>) -> Self {
-        Self { id, message }
+    pub fn new(id: u64, message: impl Into<Bytes>) -> Self {
+        Self {
+            id,
+            message: message.into(),
+        }
     }
 
     /// Decode an extension message from a buffer.
-    fn decode(buf: &[u8]) -> io::Result<Self> {
+    fn decode(buf: Bytes) -> Result<Self, Error> {
         if buf.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Extension message may not be empty",
-            ));
+            return Err(Error::Decode("Extension message may not be empty".into()));
         }
         let mut id: u64 = 0;
         let id_len = varinteger::decode(&buf, &mut id);
         Ok(Self {
             id,
-            message: buf[id_len..].to_vec(),
+            message: buf.slice(id_len..),
         })
     }
 }
@@ -403,7 +541,7 @@ mod tests {
                 let channel_message = ChannelMessage::new(channel, $msg);
                 let mut buf = vec![0u8; channel_message.encoded_len()];
                 let n = channel_message.encode(&mut buf[..]).expect("Failed to encode message");
-                let decoded = ChannelMessage::decode(&buf[..n]).expect("Failed to decode message").into_split();
+                let decoded = ChannelMessage::decode(Bytes::from(buf).slice(..n)).expect("Failed to decode message").into_split();
                 assert_eq!(channel, decoded.0);
                 assert_eq!($msg, decoded.1);
             )*
@@ -461,4 +599,30 @@ mod tests {
             })
         };
     }
+
+    #[test]
+    fn decode_without_copy() {
+        let value = Bytes::from(vec![7u8; 100]);
+        let message = ChannelMessage::new(
+            1,
+            Message::Data(Data {
+                index: 1,
+                value: Some(value.clone()),
+                nodes: vec![],
+                signature: None,
+            }),
+        );
+        let mut buf = vec![0u8; message.encoded_len()];
+        message.encode(&mut buf[..]).unwrap();
+        let buf = Bytes::from(buf);
+        let range = buf.as_ptr_range();
+        let decoded = ChannelMessage::decode(buf.clone()).unwrap();
+        let decoded_value = match decoded.message {
+            Message::Data(Data { value: Some(v), .. }) => v,
+            _ => panic!("Expected data message"),
+        };
+        assert_eq!(decoded_value, value);
+        // The value points into the decoded buffer.
+        assert!(range.contains(&decoded_value.as_ptr()));
+    }
 }
@@ -1,12 +1,61 @@
+use crate::error::{Error, Result};
 use crate::noise::HandshakeResult;
 use salsa20::stream_cipher::{NewStreamCipher, SyncStreamCipher};
 use salsa20::XSalsa20;
-use std::io::{Error, ErrorKind, Result};
+use snow::params::CipherChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
 
 // TODO: Don't define here but use the values from the XSalsa20 impl.
 const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 24;
 
+/// Length of the authentication tag that is appended to each frame.
+pub const TAG_LEN: usize = 16;
+
+/// The transport encryption that is used after the handshake.
+///
+/// Peers advertise their mode in the handshake payload, and the handshake
+/// fails with [`Error::EncryptionMismatch`] if the modes differ. Peers that do
+/// not advertise a mode use [`Encryption::Stream`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// The whole byte stream is encrypted with XSalsa20. Frames are not
+    /// authenticated. This is compatible with other hypercore-protocol
+    /// implementations.
+    Stream,
+    /// Each frame is encrypted and authenticated with ChaCha20-Poly1305, keyed
+    /// with the Noise transport keys. A frame that fails authentication aborts
+    /// the connection. Both peers have to use this mode.
+    Authenticated,
+}
+
+// Deriving with `#[default]` on the variant needs Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for Encryption {
+    fn default() -> Self {
+        Self::Stream
+    }
+}
+
+impl Encryption {
+    pub(crate) fn as_u64(&self) -> u64 {
+        match self {
+            Self::Stream => 0,
+            Self::Authenticated => 1,
+        }
+    }
+
+    /// The mode advertised in a handshake payload. Unknown modes are `None`.
+    pub(crate) fn from_payload(encryption: Option<u64>) -> Option<Self> {
+        match encryption.unwrap_or(0) {
+            0 => Some(Self::Stream),
+            1 => Some(Self::Authenticated),
+            _ => None,
+        }
+    }
+}
+
 pub struct Cipher(XSalsa20);
 
 impl std::fmt::Debug for Cipher {
@@ -21,12 +70,7 @@ impl Cipher {
             &handshake.split_rx[..KEY_SIZE],
             &handshake.remote_nonce[..NONCE_SIZE],
         )
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::PermissionDenied,
-                format!("Cannot initialize cipher: {}", e),
-            )
-        })?;
+        .map_err(|e| Error::Handshake(format!("Cannot initialize cipher: {}", e)))?;
         Ok(Self(cipher))
     }
 
@@ -35,12 +79,7 @@ impl Cipher {
             &handshake.split_tx[..KEY_SIZE],
             &handshake.local_nonce[..NONCE_SIZE],
         )
-        .map_err(|e| {
-            Error::new(
-                ErrorKind::PermissionDenied,
-                format!("Cannot initialize cipher: {}", e),
-            )
-        })?;
+        .map_err(|e| Error::Handshake(format!("Cannot initialize cipher: {}", e)))?;
         Ok(Self(cipher))
     }
 
@@ -48,3 +87,56 @@ impl Cipher {
         self.0.apply_keystream(buffer);
     }
 }
+
+/// Encrypts and authenticates single frames with ChaCha20-Poly1305.
+///
+/// Like a Noise transport `CipherState`, the nonce is a counter that is
+/// incremented for each frame.
+pub struct FrameCipher {
+    cipher: Box<dyn snow::types::Cipher>,
+    nonce: u64,
+}
+
+impl std::fmt::Debug for FrameCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FrameCipher(ChaChaPoly, nonce {})", self.nonce)
+    }
+}
+
+impl FrameCipher {
+    pub fn from_handshake_rx(handshake: &HandshakeResult) -> Self {
+        Self::new(&handshake.split_rx)
+    }
+
+    pub fn from_handshake_tx(handshake: &HandshakeResult) -> Self {
+        Self::new(&handshake.split_tx)
+    }
+
+    fn new(key: &[u8]) -> Self {
+        let mut cipher = DefaultResolver
+            .resolve_cipher(&CipherChoice::ChaChaPoly)
+            .expect("ChaChaPoly is supported by the default resolver");
+        cipher.set(key);
+        Self { cipher, nonce: 0 }
+    }
+
+    /// Encrypt `plaintext` into `out`, which has to be `TAG_LEN` bytes longer.
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> usize {
+        let len = self.cipher.encrypt(self.nonce, &[], plaintext, out);
+        self.nonce += 1;
+        len
+    }
+
+    /// Decrypt and authenticate a frame.
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(Error::Decrypt);
+        }
+        let mut plaintext = vec![0u8; ciphertext.len() - TAG_LEN];
+        self.cipher
+            .decrypt(self.nonce, &[], ciphertext, &mut plaintext)
+            .map_err(|_| Error::Decrypt)?;
+        self.nonce += 1;
+        Ok(plaintext)
+    }
+}
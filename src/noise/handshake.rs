@@ -2,12 +2,15 @@
 use blake2_rfc::blake2b::Blake2b;
 use prost::Message;
 use rand::Rng;
-pub use snow::Keypair;
 use snow::{Builder, Error as SnowError, HandshakeState};
-use std::io::{Error, ErrorKind, Result};
 
+use super::{Encryption, Keypair};
 use crate::constants::CAP_NS_BUF;
+use crate::error::{Error, Result};
 use crate::schema::NoisePayload;
+#[cfg(feature = "v10")]
+use crate::v10;
+use crate::version::Version;
 
 const CIPHERKEYLEN: usize = 32;
 const HANDSHAKE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2b";
@@ -18,8 +21,13 @@ pub struct HandshakeResult {
     pub local_pubkey: Vec<u8>,
     pub local_seckey: Vec<u8>,
     pub remote_pubkey: Vec<u8>,
+    pub remote_payload: Vec<u8>,
     pub local_nonce: Vec<u8>,
     pub remote_nonce: Vec<u8>,
+    pub remote_versions: Vec<u64>,
+    pub remote_encryption: Option<u64>,
+    pub remote_pings: bool,
+    pub handshake_hash: Vec<u8>,
     pub split_tx: [u8; CIPHERKEYLEN],
     pub split_rx: [u8; CIPHERKEYLEN],
 }
@@ -47,36 +55,30 @@ impl HandshakeResult {
         let expected_capability = self.remote_capability(key);
         match (capability, expected_capability) {
             (Some(c1), Some(c2)) if c1 == c2 => Ok(()),
-            (None, None) => Err(Error::new(
-                ErrorKind::PermissionDenied,
-                "Missing capabilities for verification",
-            )),
-            _ => Err(Error::new(
-                ErrorKind::PermissionDenied,
-                "Invalid remote channel capability",
-            )),
+            _ => Err(Error::CapabilityMismatch),
         }
     }
 }
 
 pub fn build_handshake_state(
     is_initiator: bool,
-) -> std::result::Result<(HandshakeState, Keypair), SnowError> {
+    key_pair: &Keypair,
+) -> std::result::Result<HandshakeState, SnowError> {
     let builder: Builder<'_> = Builder::new(HANDSHAKE_PATTERN.parse()?);
-    let key_pair = builder.generate_keypair().unwrap();
-    let builder = builder.local_private_key(&key_pair.private);
+    let builder = builder.local_private_key(key_pair.secret_key());
     // log::trace!("hs local pubkey: {:x?}", &key_pair.public);
     let handshake_state = if is_initiator {
         builder.build_initiator()?
     } else {
         builder.build_responder()?
     };
-    Ok((handshake_state, key_pair))
+    Ok(handshake_state)
 }
 
 pub struct Handshake {
     result: HandshakeResult,
     state: HandshakeState,
+    version: Version,
     payload: Vec<u8>,
     tx_buf: Vec<u8>,
     rx_buf: Vec<u8>,
@@ -85,22 +87,48 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn new(is_initiator: bool) -> Result<Self> {
-        let (state, local_keypair) = build_handshake_state(is_initiator).map_err(map_err)?;
-
+    /// Create the handshake of `version`. The handshake of [`Version::V1`]
+    /// advertises `versions`, the transport `encryption` and that it answers
+    /// timestamped keepalives in its payload.
+    pub fn new(
+        is_initiator: bool,
+        local_keypair: Option<&Keypair>,
+        version: Version,
+        versions: &[Version],
+        encryption: Option<Encryption>,
+    ) -> Result<Self> {
+        let local_keypair = match local_keypair {
+            Some(keypair) => keypair.clone(),
+            None => Keypair::generate(),
+        };
         let local_nonce = generate_nonce();
-        let payload = encode_nonce(local_nonce.clone());
+        let (state, local_pubkey, payload) = match version {
+            Version::V1 => (
+                build_handshake_state(is_initiator, &local_keypair).map_err(map_err)?,
+                local_keypair.public_key().to_vec(),
+                encode_payload(local_nonce.clone(), versions, encryption),
+            ),
+            // The secret key of the keypair is the seed of an Ed25519 keypair.
+            #[cfg(feature = "v10")]
+            Version::V10 => (
+                v10::handshake::build_handshake_state(is_initiator, local_keypair.secret_key())
+                    .map_err(map_err)?,
+                v10::handshake::public_key(local_keypair.secret_key()),
+                vec![],
+            ),
+        };
 
         let result = HandshakeResult {
             is_initiator,
-            local_pubkey: local_keypair.public,
-            local_seckey: local_keypair.private,
+            local_pubkey,
+            local_seckey: local_keypair.secret_key().to_vec(),
             // local_keypair,
             local_nonce,
             ..Default::default()
         };
         Ok(Self {
             state,
+            version,
             result,
             payload,
             tx_buf: vec![0u8; 512],
@@ -127,6 +155,10 @@ impl Handshake {
         self.result.is_initiator
     }
 
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
     fn recv(&mut self, msg: &[u8]) -> Result<usize> {
         self.state
             .read_message(&msg, &mut self.rx_buf)
@@ -141,7 +173,7 @@ impl Handshake {
     pub fn read(&mut self, msg: &[u8]) -> Result<Option<&'_ [u8]>> {
         // eprintln!("hs read len {}", msg.len());
         if self.complete() {
-            return Err(Error::new(ErrorKind::Other, "Handshake read after finish"));
+            return Err(Error::Handshake("Handshake read after finish".into()));
         }
 
         // eprintln!(
@@ -174,7 +206,15 @@ impl Handshake {
             self.result.split_tx = split.1;
             self.result.split_rx = split.0;
         }
-        self.result.remote_nonce = decode_nonce(&self.rx_buf[..rx_len])?;
+        if self.version == Version::V1 {
+            let remote_payload = NoisePayload::decode(&self.rx_buf[..rx_len])?;
+            self.result.remote_nonce = remote_payload.nonce;
+            self.result.remote_versions = remote_payload.versions;
+            self.result.remote_encryption = remote_payload.encryption;
+            self.result.remote_pings = remote_payload.pings.unwrap_or(false);
+        }
+        self.result.handshake_hash = self.state.get_handshake_hash().to_vec();
+        self.result.remote_payload = self.rx_buf[..rx_len].to_vec();
         self.result.remote_pubkey = self.state.get_remote_static().unwrap().to_vec();
         self.complete = true;
 
@@ -183,7 +223,7 @@ impl Handshake {
 
     pub fn into_result(self) -> Result<HandshakeResult> {
         if !self.complete() {
-            Err(Error::new(ErrorKind::Other, "Handshake is not complete"))
+            Err(Error::Handshake("Handshake is not complete".into()))
         } else {
             Ok(self.result)
         }
@@ -191,10 +231,7 @@ impl Handshake {
 }
 
 fn map_err(e: SnowError) -> Error {
-    Error::new(
-        ErrorKind::PermissionDenied,
-        format!("handshake error: {}", e),
-    )
+    Error::Handshake(e.to_string())
 }
 
 #[inline]
@@ -204,15 +241,52 @@ fn generate_nonce() -> Vec<u8> {
 }
 
 #[inline]
-fn encode_nonce(nonce: Vec<u8>) -> Vec<u8> {
-    let nonce_msg = NoisePayload { nonce };
+fn encode_payload(nonce: Vec<u8>, versions: &[Version], encryption: Option<Encryption>) -> Vec<u8> {
+    let versions = versions.iter().map(Version::as_u64).collect();
+    let encryption = encryption.map(|encryption| encryption.as_u64());
+    let payload = NoisePayload {
+        nonce,
+        versions,
+        encryption,
+        pings: Some(true),
+    };
     let mut buf = vec![0u8; 0];
-    nonce_msg.encode(&mut buf).unwrap();
+    payload.encode(&mut buf).unwrap();
     buf
 }
 
-#[inline]
-fn decode_nonce(msg: &[u8]) -> Result<Vec<u8>> {
-    let decoded = NoisePayload::decode(msg)?;
-    Ok(decoded.nonce)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake_pair() -> (HandshakeResult, HandshakeResult) {
+        let new = |is_initiator| Handshake::new(is_initiator, None, Version::V1, &[], None);
+        let mut initiator = new(true).unwrap();
+        let mut responder = new(false).unwrap();
+        let msg = initiator.start().unwrap().unwrap().to_vec();
+        let msg = responder.read(&msg).unwrap().unwrap().to_vec();
+        let msg = initiator.read(&msg).unwrap().unwrap().to_vec();
+        assert!(responder.read(&msg).unwrap().is_none());
+        (
+            initiator.into_result().unwrap(),
+            responder.into_result().unwrap(),
+        )
+    }
+
+    #[test]
+    fn verify_capability() {
+        let key = [1u8; 32];
+        let (a, b) = handshake_pair();
+        assert!(b.verify_remote_capability(a.capability(&key), &key).is_ok());
+        assert!(a.verify_remote_capability(b.capability(&key), &key).is_ok());
+
+        // A capability proves the key only on the connection it was made for.
+        let (c, _) = handshake_pair();
+        for capability in vec![None, a.capability(&[2u8; 32]), c.capability(&key)] {
+            assert!(matches!(
+                b.verify_remote_capability(capability, &key),
+                Err(Error::CapabilityMismatch)
+            ));
+        }
+    }
 }
@@ -0,0 +1,101 @@
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::error::{Error, Result};
+use crate::util::pretty_hash;
+
+/// Length of a public or secret key (in bytes).
+pub const KEY_LENGTH: usize = 32;
+
+/// A static Noise keypair (Curve25519).
+///
+/// By default, a new keypair is generated for each [`Protocol`] instance. To keep
+/// a stable identity across connections, generate a keypair once, store it with
+/// [`Keypair::to_bytes`] and pass it to [`ProtocolBuilder::set_keypair`] after
+/// loading it with [`Keypair::from_bytes`].
+///
+/// [`Protocol`]: crate::Protocol
+/// [`ProtocolBuilder::set_keypair`]: crate::ProtocolBuilder::set_keypair
+#[derive(Clone)]
+pub struct Keypair {
+    public: [u8; KEY_LENGTH],
+    secret: [u8; KEY_LENGTH],
+}
+
+// The public key is derived from the secret key, so comparing the public keys
+// is enough and does not leak the secret key through the timing.
+impl PartialEq for Keypair {
+    fn eq(&self, other: &Self) -> bool {
+        self.public == other.public
+    }
+}
+
+impl Eq for Keypair {}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keypair")
+            .field("public", &pretty_hash(&self.public))
+            .finish()
+    }
+}
+
+impl Keypair {
+    /// Generate a new random keypair.
+    pub fn generate() -> Self {
+        let secret = rand::random::<[u8; KEY_LENGTH]>();
+        Self::from_secret_key(&secret).expect("Generated secret key has a valid length")
+    }
+
+    /// Create a keypair from a secret key. The public key is derived from it.
+    pub fn from_secret_key(secret: &[u8]) -> Result<Self> {
+        let secret: [u8; KEY_LENGTH] = secret
+            .try_into()
+            .map_err(|_| Error::InvalidKey("Secret key must be 32 bytes long".into()))?;
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("Curve25519 is supported by the default resolver");
+        dh.set(&secret);
+        let public = dh.pubkey().try_into().unwrap();
+        Ok(Self { public, secret })
+    }
+
+    /// Load a keypair from its serialized form (see [`Keypair::to_bytes`]).
+    ///
+    /// Returns an error if the buffer has the wrong length or if the public key
+    /// does not match the secret key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 2 * KEY_LENGTH {
+            return Err(Error::InvalidKey(
+                "Serialized keypair must be 64 bytes long".into(),
+            ));
+        }
+        let keypair = Self::from_secret_key(&bytes[..KEY_LENGTH])?;
+        if keypair.public[..] != bytes[KEY_LENGTH..] {
+            return Err(Error::InvalidKey(
+                "Public key does not match secret key".into(),
+            ));
+        }
+        Ok(keypair)
+    }
+
+    /// Serialize the keypair as 64 bytes (secret key followed by public key).
+    pub fn to_bytes(&self) -> [u8; 2 * KEY_LENGTH] {
+        let mut bytes = [0u8; 2 * KEY_LENGTH];
+        bytes[..KEY_LENGTH].copy_from_slice(&self.secret);
+        bytes[KEY_LENGTH..].copy_from_slice(&self.public);
+        bytes
+    }
+
+    /// Get the public key.
+    pub fn public_key(&self) -> &[u8; KEY_LENGTH] {
+        &self.public
+    }
+
+    /// Get the secret key.
+    pub fn secret_key(&self) -> &[u8; KEY_LENGTH] {
+        &self.secret
+    }
+}
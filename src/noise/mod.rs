@@ -1,4 +1,6 @@
 mod cipher;
 mod handshake;
-pub use cipher::Cipher;
+mod keypair;
+pub use cipher::{Cipher, Encryption, FrameCipher, TAG_LEN};
 pub use handshake::{Handshake, HandshakeResult};
+pub use keypair::Keypair;
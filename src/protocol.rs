@@ -1,39 +1,55 @@
 use async_channel::{Receiver, Sender};
+use bytes::Bytes;
 use futures_lite::io::{AsyncRead, AsyncWrite};
+use futures_lite::ready;
 use futures_lite::stream::Stream;
 use futures_timer::Delay;
+use instant::Instant;
 use log::*;
 use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::fmt;
 use std::future::Future;
-use std::io::{self, Error, ErrorKind, Result};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use crate::builder::{Builder, Options};
-use crate::channels::{Channel, ChannelMap};
-use crate::constants::DEFAULT_KEEPALIVE;
-use crate::extension::{Extension, Extensions};
-use crate::message::{ChannelMessage, EncodeError, Frame, FrameType, Message};
-use crate::noise::{Handshake, HandshakeResult};
+use crate::channels::{Channel, ChannelMap, PendingInbound};
+use crate::error::{Error, Result};
+use crate::extension::{Extension, ExtensionEvent, Extensions};
+use crate::message::{ChannelMessage, EncodeError, Frame, FrameType, Framing, Message};
+use crate::noise::{Encryption, Handshake, HandshakeResult};
 use crate::reader::ReadState;
+use crate::resolver::Resolutions;
 use crate::schema::*;
+use crate::stats::ProtocolStats;
+use crate::throttle::Throttle;
+use crate::trace::{protocol_span, record_remote_key, trace_event, Span};
 use crate::util::map_channel_err;
-use crate::util::pretty_hash;
+use crate::util::{self, pretty_hash};
+#[cfg(feature = "v10")]
+use crate::v10;
+use crate::version::{negotiate, Version};
 use crate::writer::WriteState;
 
 macro_rules! return_error {
     ($msg:expr) => {
         if let Err(e) = $msg {
-            return Poll::Ready(Err(e));
+            trace_event!(warn, error = %e, "protocol error");
+            return Poll::Ready(Some(Err(e)));
         }
     };
 }
 
 const CHANNEL_CAP: usize = 1000;
-const KEEPALIVE_DURATION: Duration = Duration::from_secs(DEFAULT_KEEPALIVE as u64);
+const OUTBOUND_CAP: usize = 64;
+
+/// The framing of the responder until it recognized the initiator's version.
+#[cfg(feature = "v10")]
+const RESPONDER_FRAMING: Framing = Framing::Detect;
+#[cfg(not(feature = "v10"))]
+const RESPONDER_FRAMING: Framing = Framing::Varint;
 
 /// Remote public key (32 bytes).
 pub type RemotePublicKey = [u8; 32];
@@ -46,15 +62,36 @@ pub type Key = [u8; 32];
 #[non_exhaustive]
 #[derive(PartialEq)]
 pub enum Event {
-    /// Emitted after the handshake with the remove peer is complete.
+    /// Emitted after the handshake with the remove peer is complete, with
+    /// the negotiated protocol version.
     /// This is the first event (if the handshake is not disabled).
-    Handshake(RemotePublicKey),
+    Handshake(RemotePublicKey, Version),
     /// Emitted when the remote peer opens a channel that we did not yet open.
+    /// Open the channel with [`Protocol::open`] or reject it with
+    /// [`Protocol::reject`]. Not emitted if the [`Resolver`](crate::Resolver)
+    /// returned the channel's key.
     DiscoveryKey(DiscoveryKey),
     /// Emitted when a channel is established.
     Channel(Channel),
     /// Emitted when a channel is closed.
     Close(DiscoveryKey),
+    /// Emitted when the remote enables or disables a stream-level extension,
+    /// whether or not it is registered locally. Changes of a registered
+    /// extension are also emitted on the [`Extension`] itself.
+    Extension(String, ExtensionEvent),
+    /// Emitted when the protocol stream is closed. This is the last event,
+    /// afterwards the stream ends.
+    Closed(CloseReason),
+}
+
+/// The reason why a protocol stream was closed.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CloseReason {
+    /// The protocol was closed locally with [`Protocol::close`].
+    Local,
+    /// The remote peer closed the connection.
+    RemoteClosed,
 }
 
 /// A protocol command.
@@ -62,14 +99,18 @@ pub enum Event {
 pub enum Command {
     Open(Key),
     Close(DiscoveryKey),
+    Reject(DiscoveryKey),
 }
 
 impl fmt::Debug for Event {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Event::Handshake(remote_key) => {
-                write!(f, "Handshake(remote_key={})", &pretty_hash(remote_key))
-            }
+            Event::Handshake(remote_key, version) => write!(
+                f,
+                "Handshake(remote_key={}, version={:?})",
+                &pretty_hash(remote_key),
+                version
+            ),
             Event::DiscoveryKey(discovery_key) => {
                 write!(f, "DiscoveryKey({})", &pretty_hash(discovery_key))
             }
@@ -77,18 +118,31 @@ impl fmt::Debug for Event {
                 write!(f, "Channel({})", &pretty_hash(channel.discovery_key()))
             }
             Event::Close(discovery_key) => write!(f, "Close({})", &pretty_hash(discovery_key)),
+            Event::Extension(name, event) => write!(f, "Extension({}, {:?})", name, event),
+            Event::Closed(reason) => write!(f, "Closed({:?})", reason),
         }
     }
 }
 
+type AuthorizeFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
 /// Protocol state
-#[allow(clippy::large_enum_variant)]
 pub enum State {
     NotInitialized,
-    // The Handshake struct sits behind an option only so that we can .take()
-    // it out, it's never actually empty when in State::Handshake.
-    Handshake(Option<Handshake>),
+    // The Handshake struct sits behind an option so that we can .take() it
+    // out. It is only empty while the responder waits for the first message,
+    // which decides the version of the handshake. It is boxed to keep the
+    // protocol small.
+    Handshake(Option<Box<Handshake>>),
+    // The handshake is complete and the remote peer is being authorized.
+    Authorizing(AuthorizeFuture),
     Established,
+    // The handshake failed. Queued handshake messages are being flushed so
+    // that the remote can detect the failure too, then the error is returned.
+    Failing(Option<Error>),
+    // Queued messages are being flushed and the IO is being closed.
+    Closing(CloseReason),
+    Closed,
 }
 
 impl fmt::Debug for State {
@@ -96,7 +150,11 @@ impl fmt::Debug for State {
         match self {
             State::NotInitialized => write!(f, "NotInitialized"),
             State::Handshake(_) => write!(f, "Handshaking"),
+            State::Authorizing(_) => write!(f, "Authorizing"),
             State::Established => write!(f, "Established"),
+            State::Failing(error) => write!(f, "Failing({:?})", error),
+            State::Closing(reason) => write!(f, "Closing({:?})", reason),
+            State::Closed => write!(f, "Closed"),
         }
     }
 }
@@ -111,14 +169,25 @@ pub struct Protocol<IO> {
     state: State,
     options: Options,
     handshake: Option<HandshakeResult>,
+    version: Option<Version>,
     channels: ChannelMap,
     command_rx: Receiver<Command>,
     command_tx: CommandTx,
     outbound_rx: Receiver<ChannelMessage>,
     outbound_tx: Sender<ChannelMessage>,
-    keepalive: Delay,
+    keepalive: Option<Delay>,
+    /// The time that the timestamps of keepalive pings are relative to.
+    started: Instant,
+    handshake_timeout: Option<Delay>,
     queued_events: VecDeque<Event>,
+    pending_inbound: Option<PendingInbound>,
     extensions: Extensions,
+    /// Lookups of the keys of channels that the remote opened.
+    resolutions: Resolutions,
+    /// Message counters and round trip time. The other counters are
+    /// collected when taking a snapshot.
+    stats: ProtocolStats,
+    span: Span,
 }
 
 impl<IO> Protocol<IO>
@@ -128,22 +197,37 @@ where
     /// Create a new protocol instance.
     pub fn new(io: IO, options: Options) -> Self {
         let (command_tx, command_rx) = async_channel::bounded(CHANNEL_CAP);
-        let (outbound_tx, outbound_rx) = async_channel::bounded(1);
+        let (outbound_tx, outbound_rx) = async_channel::bounded(OUTBOUND_CAP);
+        let span = protocol_span(options.is_initiator);
+        let mut read_state = ReadState::new(
+            options.timeout,
+            Throttle::new(options.download_limits.clone()),
+        );
+        read_state.set_capture(options.capture.clone());
+        let mut write_state = WriteState::new(Throttle::new(options.upload_limits.clone()));
+        write_state.set_capture(options.capture.clone());
         Protocol {
             io,
-            read_state: ReadState::new(),
-            write_state: WriteState::new(),
+            read_state,
+            keepalive: options.keepalive.map(Delay::new),
+            started: Instant::now(),
+            handshake_timeout: None,
+            write_state,
             options,
             state: State::NotInitialized,
-            channels: ChannelMap::new(),
+            channels: ChannelMap::new(span.clone()),
             handshake: None,
+            version: None,
             extensions: Extensions::new(outbound_tx.clone(), 0),
             command_rx,
             command_tx: CommandTx(command_tx),
             outbound_tx,
             outbound_rx,
-            keepalive: Delay::new(Duration::from_secs(DEFAULT_KEEPALIVE as u64)),
             queued_events: VecDeque::new(),
+            pending_inbound: None,
+            resolutions: Resolutions::default(),
+            stats: ProtocolStats::default(),
+            span,
         }
     }
 
@@ -183,6 +267,28 @@ where
         }
     }
 
+    /// Get the negotiated protocol version.
+    ///
+    /// Empty before the handshake completed. If the handshake is disabled,
+    /// this is the highest version of [`Options::versions`].
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+
+    /// Get a snapshot of the counters of this protocol.
+    pub fn stats(&self) -> ProtocolStats {
+        let mut stats = self.stats.clone();
+        stats.bytes_read = self.read_state.bytes_read();
+        stats.bytes_written = self.write_state.bytes_written();
+        stats.frames_read = self.read_state.frames_read();
+        stats.frames_written = self.write_state.frames_written();
+        stats.queued_messages = self.outbound_rx.len();
+        stats.queued_frames = self.write_state.queued_frames();
+        stats.queued_bytes = self.write_state.queued_bytes();
+        stats.open_channels = self.channels.iter().filter(|c| c.is_open()).count();
+        stats
+    }
+
     /// Get a sender to send commands.
     pub fn commands(&self) -> CommandTx {
         self.command_tx.clone()
@@ -206,18 +312,47 @@ where
         self.command_tx.open(key).await
     }
 
+    /// Reject a channel that the remote opened, usually after an
+    /// [`Event::DiscoveryKey`].
+    ///
+    /// The remote is sent a close message with the discovery key. Channels
+    /// that were opened locally are not affected, close them with
+    /// [`Channel::close`](crate::Channel::close) instead.
+    pub async fn reject(&mut self, discovery_key: DiscoveryKey) -> Result<()> {
+        self.command_tx.reject(discovery_key).await
+    }
+
     /// Iterator of all currently opened channels.
     pub fn channels(&self) -> impl Iterator<Item = &DiscoveryKey> {
         self.channels.iter().map(|c| c.discovery_key())
     }
 
+    /// Close the protocol.
+    ///
+    /// All channels are closed, and after all queued messages have been written
+    /// the IO is closed. Keep polling the protocol stream to drive the shutdown:
+    /// It emits [`Event::Close`] for each channel, then [`Event::Closed`], and then ends.
+    pub fn close(&mut self) {
+        match self.state {
+            State::Failing(_) | State::Closing(_) | State::Closed => return,
+            State::Established => {
+                self.drain_outbound();
+                self.close_all_channels(true);
+            }
+            _ => {}
+        }
+        self.state = State::Closing(CloseReason::Local);
+    }
+
     /// Stop the protocol and return the inner reader and writer.
     pub fn release(self) -> IO {
         self.io
     }
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Event>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Event>>> {
         let this = self.get_mut();
+        let span = this.span.clone();
+        let _entered = span.enter();
 
         if let State::NotInitialized = this.state {
             return_error!(this.init());
@@ -225,26 +360,53 @@ where
 
         // Drain queued events first.
         if let Some(event) = this.queued_events.pop_front() {
-            return Poll::Ready(Ok(event));
+            return Poll::Ready(Some(Ok(event)));
+        }
+
+        match this.state {
+            State::Failing(_) => return this.poll_fail(cx),
+            State::Closing(_) => return this.poll_close(cx),
+            State::Closed => return Poll::Ready(None),
+            _ => {}
         }
 
         // Read and process incoming messages.
         return_error!(this.poll_inbound_read(cx));
 
+        if let State::Failing(_) = this.state {
+            return this.poll_fail(cx);
+        }
+
+        // If the remote closed the connection, emit the channel close events and shut down.
+        if let State::Closing(_) = this.state {
+            return match this.queued_events.pop_front() {
+                Some(event) => Poll::Ready(Some(Ok(event))),
+                None => this.poll_close(cx),
+            };
+        }
+
         if let State::Established = this.state {
             // Check for commands, but only once the connection is established.
+            let early_messages_full = this.early_messages_full();
             return_error!(this.poll_commands(cx));
+            return_error!(this.poll_resolutions(cx));
+            // Opening or rejecting channels frees their early messages, so
+            // reading can continue.
+            if early_messages_full && !this.early_messages_full() {
+                cx.waker().wake_by_ref();
+            }
         }
 
-        // Poll the keepalive timer.
+        // Poll the keepalive and handshake timers.
         this.poll_keepalive(cx);
+        return_error!(this.poll_handshake_timeout(cx));
 
         // Write everything we can write.
         return_error!(this.poll_outbound_write(cx));
 
         // Check if any events are enqueued.
         if let Some(event) = this.queued_events.pop_front() {
-            Poll::Ready(Ok(event))
+            Poll::Ready(Some(Ok(event)))
         } else {
             Poll::Pending
         }
@@ -259,23 +421,159 @@ where
             State::NotInitialized => {}
             _ => return Ok(()),
         };
+        if self.options.versions.is_empty() {
+            return Err(Error::InvalidOptions(
+                "No protocol version is enabled".into(),
+            ));
+        }
+        #[cfg(all(feature = "v10", not(feature = "v10-unstable")))]
+        if self.options.versions.contains(&Version::V10) {
+            return Err(Error::InvalidOptions(
+                "Version::V10 requires the v10-unstable feature".into(),
+            ));
+        }
 
+        // The initiator speaks the highest version.
+        let version = *self.options.versions.iter().max().unwrap();
         self.state = if self.options.noise {
-            let mut handshake = Handshake::new(self.options.is_initiator)?;
-            // If the handshake start returns a buffer, send it now.
-            if let Some(buf) = handshake.start()? {
-                self.queue_frame_direct(buf.to_vec()).unwrap();
-            }
+            self.handshake_timeout = self.options.handshake_timeout.map(Delay::new);
             self.read_state.set_frame_type(FrameType::Raw);
-            State::Handshake(Some(handshake))
+            if self.options.is_initiator {
+                State::Handshake(Some(self.start_handshake(version)?))
+            } else {
+                self.read_state.set_framing(RESPONDER_FRAMING);
+                State::Handshake(None)
+            }
         } else {
+            // Without a handshake, both peers must enable the same versions.
+            self.version = Some(version);
+            self.set_framing(version.framing());
             self.read_state.set_frame_type(FrameType::Message);
+            self.write_state.set_cork(self.options.cork);
             State::Established
         };
 
         Ok(())
     }
 
+    /// Create the handshake of `version` and send its first message if this
+    /// is the initiator.
+    fn start_handshake(&mut self, version: Version) -> Result<Box<Handshake>> {
+        let mut handshake = Handshake::new(
+            self.options.is_initiator,
+            self.options.keypair.as_ref(),
+            version,
+            &self.legacy_versions(),
+            self.encryption(),
+        )?;
+        self.set_framing(version.framing());
+        // If the handshake start returns a buffer, send it now.
+        if let Some(buf) = handshake.start()? {
+            self.queue_frame_direct(buf.to_vec()).unwrap();
+        }
+        Ok(Box::new(handshake))
+    }
+
+    /// The transport encryption of [`Version::V1`], if enabled.
+    fn encryption(&self) -> Option<Encryption> {
+        if self.options.encrypted {
+            Some(self.options.encryption)
+        } else {
+            None
+        }
+    }
+
+    /// Negotiate the version of a [`Version::V1`] handshake, and check that
+    /// the remote uses the same transport encryption.
+    fn negotiate_v1(&self, result: &HandshakeResult) -> Result<Version> {
+        let version = negotiate(&self.legacy_versions(), &result.remote_versions)?;
+        if let Some(encryption) = self.encryption() {
+            if Encryption::from_payload(result.remote_encryption) != Some(encryption) {
+                return Err(Error::EncryptionMismatch);
+            }
+        }
+        Ok(version)
+    }
+
+    /// The enabled versions that share the framing of [`Version::V1`]. They
+    /// are advertised in the handshake payload of [`Version::V1`].
+    fn legacy_versions(&self) -> Vec<Version> {
+        self.options
+            .versions
+            .iter()
+            .copied()
+            .filter(|version| version.framing() == Framing::Varint)
+            .collect()
+    }
+
+    /// The highest enabled version with the framing that the responder
+    /// recognized from the first message.
+    fn responder_version(&self) -> Option<Version> {
+        let framing = self.read_state.framing();
+        self.options
+            .versions
+            .iter()
+            .copied()
+            .filter(|version| version.framing() == framing)
+            .max()
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.read_state.set_framing(framing);
+        self.write_state.set_framing(framing);
+    }
+
+    /// Poll the authorizer and establish the connection if the remote peer is accepted.
+    fn poll_authorize(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        let authorized = match &mut self.state {
+            State::Authorizing(authorize) => match authorize.as_mut().poll(cx) {
+                Poll::Ready(authorized) => authorized,
+                Poll::Pending => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        if !authorized {
+            return Err(Error::Unauthorized);
+        }
+        self.on_authorized()
+    }
+
+    /// Flush all queued frames, then return the error of a failed handshake.
+    fn poll_fail(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Event>>> {
+        // A write error is less useful than the handshake error, ignore it.
+        let _ = ready!(self.write_state.poll_send(cx, &mut self.io));
+        let error = match &mut self.state {
+            State::Failing(error) => error.take().unwrap(),
+            _ => unreachable!("May not call poll_fail when not in Failing state"),
+        };
+        self.state = State::Closed;
+        trace_event!(warn, error = %error, "handshake failed");
+        Poll::Ready(Some(Err(error)))
+    }
+
+    /// Flush all queued frames and close the IO.
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Event>>> {
+        let reason = match &self.state {
+            State::Closing(reason) => reason.clone(),
+            _ => unreachable!("May not call poll_close when not in Closing state"),
+        };
+        let result = ready!(self.poll_close_io(cx));
+        self.state = State::Closed;
+        trace_event!(debug, ?reason, "protocol closed");
+        match (result, reason) {
+            (Err(e), CloseReason::Local) => Poll::Ready(Some(Err(e))),
+            // Errors are expected if the remote closed the connection, ignore them.
+            (_, reason) => Poll::Ready(Some(Ok(Event::Closed(reason)))),
+        }
+    }
+
+    fn poll_close_io(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Don't hold back the last frames.
+        self.write_state.set_cork(None);
+        ready!(self.write_state.poll_send(cx, &mut self.io))?;
+        Pin::new(&mut self.io).poll_close(cx).map_err(Error::from)
+    }
+
     /// Poll commands.
     fn poll_commands(&mut self, cx: &mut Context<'_>) -> Result<()> {
         while let Poll::Ready(Some(command)) = Pin::new(&mut self.command_rx).poll_next(cx) {
@@ -284,57 +582,251 @@ where
         Ok(())
     }
 
+    /// Poll the resolver for the keys of channels that the remote opened.
+    fn poll_resolutions(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        while let Poll::Ready((discovery_key, key)) = self.resolutions.poll_next(cx) {
+            self.on_resolved(discovery_key, key)?;
+        }
+        Ok(())
+    }
+
     /// Poll the keepalive timer and queue a ping message if needed.
     fn poll_keepalive(&mut self, cx: &mut Context<'_>) {
-        if Pin::new(&mut self.keepalive).poll(cx).is_ready() {
-            self.write_state.queue_frame(Frame::Raw(vec![0u8; 0]));
-            self.keepalive.reset(KEEPALIVE_DURATION);
+        let answers_pings = self.remote_answers_pings();
+        if let (Some(keepalive), Some(duration)) = (self.keepalive.as_mut(), self.options.keepalive)
+        {
+            if Pin::new(&mut *keepalive).poll(cx).is_ready() {
+                // Remotes that answer pings get a timestamp to measure the
+                // round trip time, the others an empty message.
+                let frame = if answers_pings {
+                    Frame::Ping(self.started.elapsed().as_micros() as u64)
+                } else {
+                    Frame::Raw(Bytes::new())
+                };
+                self.write_state.queue_frame(frame);
+                keepalive.reset(duration);
+            }
+        }
+    }
+
+    /// Whether the remote advertised in the handshake that it answers
+    /// timestamped keepalive pings.
+    fn remote_answers_pings(&self) -> bool {
+        matches!(self.state, State::Established)
+            && matches!(
+                self.handshake,
+                Some(HandshakeResult {
+                    remote_pings: true,
+                    ..
+                })
+            )
+    }
+
+    /// Measure the round trip time from the answer to a keepalive ping.
+    fn on_pong(&mut self, timestamp: u64) {
+        let sent = Duration::from_micros(timestamp);
+        if let Some(rtt) = self.started.elapsed().checked_sub(sent) {
+            self.stats.rtt = Some(rtt);
         }
     }
 
-    fn on_outbound_message(&mut self, message: &ChannelMessage) {
+    /// Poll the handshake timer and return an error if it expired.
+    fn poll_handshake_timeout(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        match self.state {
+            State::Handshake(_) | State::Authorizing(_) => {}
+            _ => return Ok(()),
+        }
+        if let Some(timeout) = self.handshake_timeout.as_mut() {
+            if Pin::new(timeout).poll(cx).is_ready() {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepare a message that was sent on a channel or extension to be
+    /// written. Returns false if the message is dropped.
+    fn on_outbound_message(&mut self, message: &mut ChannelMessage) -> bool {
+        let channel = message.channel;
+        let announces_extensions = self.announces_extensions();
+        match &mut message.message {
+            // The IDs of extension messages follow the last options that were
+            // written, so that the remote maps them to the right extension.
+            Message::Options(msg) => {
+                self.with_extensions(channel, |extensions| {
+                    extensions.on_local_update(&msg.extensions)
+                });
+            }
+            Message::Extension(msg) if announces_extensions => {
+                if let Some(name) = &message.extension {
+                    match self
+                        .with_extensions(channel, |extensions| extensions.wire_id(name))
+                        .flatten()
+                    {
+                        Some(id) => msg.id = id,
+                        None => {
+                            log::debug!(
+                                "dropped message of extension {} that is not announced",
+                                name
+                            );
+                            return false;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        if !self.is_writable(message) {
+            log::debug!(
+                "dropped message {} that version {:?} does not support",
+                message.message,
+                self.version
+            );
+            return false;
+        }
+        self.record_outbound(message);
         // If message is close, close the local channel.
         if let ChannelMessage {
             channel,
             message: Message::Close(_),
+            ..
         } = message
         {
             self.close_local(*channel);
         }
+        true
+    }
+
+    /// Queue a message that was sent on a channel or extension.
+    fn queue_outbound(&mut self, mut message: ChannelMessage) -> bool {
+        if !self.on_outbound_message(&mut message) {
+            return false;
+        }
+        self.queue_frame(message);
+        true
+    }
+
+    /// Check if `message` can be written with the version of the connection.
+    fn is_writable(&self, message: &ChannelMessage) -> bool {
+        match self.version {
+            #[cfg(feature = "v10")]
+            Some(Version::V10) => is_v10_writable(message),
+            _ => Version::V1.supports(&message.message),
+        }
+    }
+
+    /// Whether extensions are announced with options messages, so that
+    /// their messages carry the announced id. v10 names the extension in
+    /// each message instead.
+    fn announces_extensions(&self) -> bool {
+        #[cfg(feature = "v10")]
+        if self.version == Some(Version::V10) {
+            return false;
+        }
+        true
+    }
+
+    /// Queue the frame of a message that [`Self::is_writable`].
+    fn queue_frame(&mut self, message: ChannelMessage) {
+        #[cfg(feature = "v10")]
+        if self.version == Some(Version::V10) {
+            if let Some(message) = to_v10(message) {
+                self.write_state.queue_frame(Frame::V10(message));
+            }
+            return;
+        }
+        self.write_state.queue_frame(Frame::Message(message));
+    }
+
+    /// Queue the messages that were already sent on channels or extensions.
+    fn drain_outbound(&mut self) {
+        while let Ok(message) = self.outbound_rx.try_recv() {
+            self.queue_outbound(message);
+        }
+    }
+
+    /// Run `f` on the extensions of the stream (channel 0) or of a local channel.
+    fn with_extensions<T>(
+        &mut self,
+        channel: u64,
+        f: impl FnOnce(&mut Extensions) -> T,
+    ) -> Option<T> {
+        if channel == 0 {
+            return Some(f(&mut self.extensions));
+        }
+        let channel_handle = self.channels.get_local(channel as usize)?;
+        let mut extensions = channel_handle.extensions()?.lock().unwrap();
+        Some(f(&mut extensions))
     }
 
     /// Poll for inbound messages and processs them.
     fn poll_inbound_read(&mut self, cx: &mut Context<'_>) -> Result<()> {
         loop {
+            // Do not read any messages before the remote peer is authorized.
+            if let State::Authorizing(_) = self.state {
+                self.poll_authorize(cx)?;
+                if let State::Authorizing(_) = self.state {
+                    return Ok(());
+                }
+            }
+            // Do not read any messages while channels that were not opened
+            // locally buffer too many messages.
+            if self.early_messages_full() {
+                return Ok(());
+            }
+            // Do not read any messages while a channel's inbound queue is full.
+            if let Some(pending_inbound) = self.pending_inbound.as_mut() {
+                match pending_inbound.poll(cx) {
+                    Poll::Ready(()) => {
+                        self.pending_inbound = None;
+                        self.read_state.reset_timeout();
+                    }
+                    Poll::Pending => return Ok(()),
+                }
+            }
             let msg = self.read_state.poll_reader(cx, &mut self.io);
             match msg {
-                Poll::Ready(Ok(message)) => {
+                Poll::Ready(Some(Ok(message))) => {
                     self.on_inbound_frame(message)?;
+                    if let State::Failing(_) = self.state {
+                        return Ok(());
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Err(e),
+                Poll::Ready(None) => {
+                    self.on_remote_closed();
+                    return Ok(());
                 }
-                Poll::Ready(Err(e)) => return Err(e),
                 Poll::Pending => return Ok(()),
             }
         }
     }
 
     /// Poll for outbound messages and write them.
+    ///
+    /// All messages that are ready are queued at once, so that the writer can
+    /// coalesce them into few writes.
     fn poll_outbound_write(&mut self, cx: &mut Context<'_>) -> Result<()> {
         loop {
             if let Poll::Ready(Err(e)) = self.write_state.poll_send(cx, &mut self.io) {
                 return Err(e);
             }
-            if !self.write_state.can_park_frame() || !matches!(self.state, State::Established) {
+            if !matches!(self.state, State::Established) {
                 return Ok(());
             }
 
-            match Pin::new(&mut self.outbound_rx).poll_next(cx) {
-                Poll::Ready(Some(message)) => {
-                    self.on_outbound_message(&message);
-                    let frame = Frame::Message(message);
-                    self.write_state.park_frame(frame);
+            let mut queued = false;
+            while self.write_state.can_queue_frame() {
+                match Pin::new(&mut self.outbound_rx).poll_next(cx) {
+                    Poll::Ready(Some(message)) => {
+                        queued |= self.queue_outbound(message);
+                    }
+                    Poll::Ready(None) => unreachable!("Channel closed before end"),
+                    Poll::Pending => break,
                 }
-                Poll::Ready(None) => unreachable!("Channel closed before end"),
-                Poll::Pending => return Ok(()),
+            }
+            if !queued {
+                return Ok(());
             }
         }
     }
@@ -349,14 +841,82 @@ where
                 State::Established => self.on_inbound_message(channel_message),
                 _ => unreachable!("May not receive message frames when not established"),
             },
+            Frame::Ping(timestamp) => {
+                // Skip the answer rather than grow the queue if the remote
+                // does not read.
+                if self.write_state.can_queue_frame() {
+                    self.write_state.queue_frame(Frame::Pong(timestamp));
+                }
+                Ok(())
+            }
+            Frame::Pong(timestamp) => {
+                self.on_pong(timestamp);
+                Ok(())
+            }
+            #[cfg(feature = "v10")]
+            Frame::V10(channel_message) => match self.state {
+                State::Established => self.on_inbound_v10_message(channel_message),
+                _ => unreachable!("May not receive message frames when not established"),
+            },
         }
     }
 
-    fn on_handshake_message(&mut self, buf: Vec<u8>) -> Result<()> {
-        let mut handshake = match &mut self.state {
-            State::Handshake(handshake) => handshake.take().unwrap(),
+    /// Handle the control messages of v10 like their [`Version::V1`]
+    /// counterparts, and all other messages as [`Message::V10`].
+    #[cfg(feature = "v10")]
+    fn on_inbound_v10_message(&mut self, channel_message: v10::ChannelMessage) -> Result<()> {
+        let (channel, message) = channel_message.into_split();
+        let message = match message {
+            // Channels of other protocols on the same connection are not supported.
+            v10::Message::Open(msg) if msg.protocol != v10::message::PROTOCOL_NAME => {
+                self.queue_reject(channel);
+                return Ok(());
+            }
+            v10::Message::Open(msg) => Message::Open(Open {
+                discovery_key: msg.discovery_key,
+                capability: msg.handshake.map(|handshake| handshake.capability.to_vec()),
+            }),
+            v10::Message::Close(_) => Message::Close(Close {
+                discovery_key: None,
+            }),
+            // A reject refers to the local id of a channel that the remote did not open.
+            v10::Message::Reject(msg) => {
+                self.stats
+                    .received
+                    .record(&Message::V10(v10::Message::Reject(msg)));
+                self.close_local(channel);
+                return Ok(());
+            }
+            message => Message::V10(message),
+        };
+        self.on_inbound_message(ChannelMessage::new(channel, message))
+    }
+
+    /// Reject a channel that the remote opened with the id `remote_id`.
+    #[cfg(feature = "v10")]
+    fn queue_reject(&mut self, remote_id: u64) {
+        let reject = v10::Message::Reject(v10::message::Reject);
+        self.stats.sent.record(&Message::V10(reject.clone()));
+        let message = v10::ChannelMessage::new(remote_id, reject);
+        self.write_state.queue_frame(Frame::V10(message));
+    }
+
+    fn on_handshake_message(&mut self, buf: Bytes) -> Result<()> {
+        let handshake = match &mut self.state {
+            State::Handshake(handshake) => handshake.take(),
             _ => unreachable!("May not call on_handshake_message when not in Handshake state"),
         };
+        // The responder starts its handshake with the version of the first message.
+        let mut handshake = match handshake {
+            Some(handshake) => handshake,
+            None => match self.responder_version() {
+                Some(version) => self.start_handshake(version)?,
+                None => {
+                    self.state = State::Failing(Some(Error::VersionMismatch));
+                    return Ok(());
+                }
+            },
+        };
 
         if let Some(response_buf) = handshake.read(&buf)? {
             self.queue_frame_direct(response_buf.to_vec()).unwrap();
@@ -365,32 +925,109 @@ where
         if !handshake.complete() {
             self.state = State::Handshake(Some(handshake));
         } else {
+            let handshake_version = handshake.version();
             let result = handshake.into_result()?;
-            if self.options.encrypted {
-                self.read_state.upgrade_with_handshake(&result)?;
-                self.write_state.upgrade_with_handshake(&result)?;
-            }
+            let version = match handshake_version {
+                Version::V1 => match self.negotiate_v1(&result) {
+                    Ok(version) => version,
+                    Err(error) => {
+                        // The initiator still sends its last handshake message, so
+                        // that the remote can negotiate and fail as well.
+                        self.state = State::Failing(Some(error));
+                        return Ok(());
+                    }
+                },
+                #[cfg(feature = "v10")]
+                Version::V10 => Version::V10,
+            };
+            self.version = Some(version);
+            self.upgrade_with_handshake(version, &result)?;
             self.read_state.set_frame_type(FrameType::Message);
             let remote_public_key = parse_key(&result.remote_pubkey)?;
             log::debug!(
                 "handshake complete, remote_key {}",
                 pretty_hash(&remote_public_key)
             );
-            self.handshake = Some(result);
-            self.state = State::Established;
-            self.queue_event(Event::Handshake(remote_public_key));
+            match self.options.authorizer.clone() {
+                Some(authorizer) => {
+                    let payload = result.remote_payload.clone();
+                    let authorize =
+                        async move { authorizer.authorize(&remote_public_key, &payload).await };
+                    self.state = State::Authorizing(Box::pin(authorize));
+                    self.handshake = Some(result);
+                }
+                None => {
+                    self.handshake = Some(result);
+                    self.on_authorized()?;
+                }
+            }
         }
         Ok(())
     }
 
+    /// Set up the transport encryption of `version`.
+    fn upgrade_with_handshake(&mut self, version: Version, result: &HandshakeResult) -> Result<()> {
+        match version {
+            Version::V1 if self.options.encrypted => {
+                let encryption = self.options.encryption;
+                self.read_state.upgrade_with_handshake(result, encryption)?;
+                self.write_state
+                    .upgrade_with_handshake(result, encryption)?;
+            }
+            Version::V1 => {}
+            // v10 is always encrypted.
+            #[cfg(feature = "v10")]
+            Version::V10 => {
+                self.read_state.upgrade_with_secret_stream(result);
+                self.write_state.upgrade_with_secret_stream(result)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_authorized(&mut self) -> Result<()> {
+        // Safe because on_authorized is only called after the handshake completed.
+        let remote_public_key = parse_key(self.remote_public_key().unwrap())?;
+        let version = self.version.unwrap();
+        record_remote_key(&self.span, &remote_public_key);
+        trace_event!(debug, ?version, "handshake complete");
+        self.state = State::Established;
+        self.write_state.set_cork(self.options.cork);
+        self.queue_event(Event::Handshake(remote_public_key, version));
+        Ok(())
+    }
+
     fn on_inbound_message(&mut self, channel_message: ChannelMessage) -> Result<()> {
         // let channel_message = ChannelMessage::decode(buf)?;
         log::debug!("[{}] recv {:?}", self.is_initiator(), channel_message);
         let (remote_id, message) = channel_message.into_split();
+        self.stats.received.record(&message);
+        if let Message::Options(msg) = &message {
+            if msg.extensions.len() > self.options.max_extensions {
+                return Err(Error::LimitExceeded(format!(
+                    "More than {} extensions",
+                    self.options.max_extensions
+                )));
+            }
+        }
+        // Open messages are counted on the channel once it exists.
+        let open = match message {
+            Message::Open(_) => Some(message.clone()),
+            _ => {
+                self.record_channel_inbound(remote_id, &message);
+                None
+            }
+        };
         match remote_id {
             // Id 0 means stream-level, where only extension and options messages are supported.
             0 => match message {
-                Message::Options(msg) => self.extensions.on_remote_update(msg.extensions),
+                Message::Options(msg) => {
+                    let update = self.extensions.on_remote_update(msg.extensions);
+                    for (name, event) in update.events {
+                        self.queue_event(Event::Extension(name, event));
+                    }
+                    self.queue_buffered(update.messages);
+                }
                 Message::Extension(msg) => self.extensions.on_message(msg),
                 _ => {}
             },
@@ -398,18 +1035,93 @@ where
             _ => match message {
                 Message::Open(msg) => self.on_open(remote_id, msg)?,
                 Message::Close(msg) => self.on_close(remote_id, msg)?,
-                _ => self
-                    .channels
-                    .forward_inbound_message(remote_id as usize, message)?,
+                // Extensions are handled right away, so that their messages
+                // are not held up by the channel's inbound queue.
+                Message::Extension(msg) => {
+                    if let Some(channel_handle) = self.channels.get_remote(remote_id as usize) {
+                        channel_handle.on_extension_message(msg);
+                    }
+                }
+                #[cfg(feature = "v10")]
+                Message::V10(v10::Message::Extension(msg)) => {
+                    if let Some(channel_handle) = self.channels.get_remote(remote_id as usize) {
+                        channel_handle.on_named_extension_message(&msg.name, msg.message.into());
+                    }
+                }
+                _ => {
+                    if let Message::Options(msg) = &message {
+                        self.on_channel_options(remote_id, msg);
+                    }
+                    self.pending_inbound = self
+                        .channels
+                        .forward_inbound_message(remote_id as usize, message)?
+                }
             },
         }
+        if let Some(open) = open {
+            self.record_channel_inbound(remote_id, &open);
+        }
         Ok(())
     }
 
+    fn on_channel_options(&mut self, remote_id: u64, msg: &crate::schema::Options) {
+        let update = match self.channels.get_remote(remote_id as usize) {
+            Some(channel_handle) => channel_handle.on_remote_extensions(msg.extensions.clone()),
+            None => return,
+        };
+        self.queue_buffered(update.messages);
+    }
+
+    fn early_messages_full(&self) -> bool {
+        let capacity = self.options.channel_capacity.max(1);
+        self.channels
+            .early_messages_full(capacity, self.options.max_pending_bytes)
+    }
+
+    /// Queue the messages of extensions that were enabled by the remote,
+    /// after the messages that were sent before, which include the options
+    /// that announced the extensions.
+    fn queue_buffered(&mut self, messages: Vec<ChannelMessage>) {
+        if messages.is_empty() {
+            return;
+        }
+        self.drain_outbound();
+        for message in messages {
+            self.queue_outbound(message);
+        }
+    }
+
+    fn record_outbound(&mut self, message: &ChannelMessage) {
+        self.stats.sent.record(&message.message);
+        if let Some(channel_handle) = self.channels.get_local(message.channel as usize) {
+            trace_event!(trace, parent: channel_handle.span(), message = %message.message, "send");
+            let mut stats = channel_handle.stats().lock().unwrap();
+            stats.record_sent(&message.message);
+        }
+    }
+
+    fn record_channel_inbound(&self, remote_id: u64, message: &Message) {
+        if remote_id == 0 {
+            return;
+        }
+        if let Some(channel_handle) = self.channels.get_remote(remote_id as usize) {
+            trace_event!(trace, parent: channel_handle.span(), message = %message, "recv");
+            let mut stats = channel_handle.stats().lock().unwrap();
+            stats.record_received(message);
+        }
+    }
+
     fn on_command(&mut self, command: Command) -> Result<()> {
         match command {
             Command::Open(key) => self.command_open(key),
-            _ => Ok(()),
+            Command::Close(discovery_key) => {
+                self.close_channel(discovery_key);
+                Ok(())
+            }
+            Command::Reject(discovery_key) => {
+                self.reject_channel(discovery_key);
+                Ok(())
+            }
         }
     }
 
@@ -433,13 +1145,72 @@ where
             capability,
         });
         let channel_message = ChannelMessage::new(local_id as u64, message);
-        self.write_state
-            .queue_frame(Frame::Message(channel_message));
+        self.queue_message(channel_message);
         Ok(())
     }
 
+    fn close_channel(&mut self, discovery_key: DiscoveryKey) {
+        if self.send_close(discovery_key) {
+            self.queue_event(Event::Close(discovery_key));
+        }
+    }
+
+    /// Close a channel that was only opened by the remote. No event is
+    /// emitted, because the channel was never established.
+    fn reject_channel(&mut self, discovery_key: DiscoveryKey) {
+        match self.channels.get(&discovery_key) {
+            Some(channel_handle) if channel_handle.local_id().is_none() => {}
+            _ => return,
+        }
+        trace_event!(debug, discovery_key = %pretty_hash(&discovery_key), "channel rejected");
+        self.send_close(discovery_key);
+    }
+
+    /// Send a close message for a channel and remove it. Returns false if
+    /// there is no such channel.
+    fn send_close(&mut self, discovery_key: DiscoveryKey) -> bool {
+        let (local_id, remote_id) = match self.channels.get(&discovery_key) {
+            Some(channel_handle) => (channel_handle.local_id(), channel_handle.remote_id()),
+            None => return false,
+        };
+
+        // v10 rejects channels that were only opened by the remote.
+        #[cfg(feature = "v10")]
+        if let (Some(Version::V10), None, Some(remote_id)) = (self.version, local_id, remote_id) {
+            self.queue_reject(remote_id as u64);
+            self.channels.remove(&discovery_key);
+            return true;
+        }
+        #[cfg(not(feature = "v10"))]
+        let _ = remote_id;
+
+        // If the channel was opened locally, close it on its own channel ID.
+        // Otherwise, the remote only knows the channel by its discovery key, so
+        // include it in the close message and send it on the reserved close ID.
+        let (local_id, close) = match local_id {
+            Some(local_id) => (
+                local_id,
+                Close {
+                    discovery_key: None,
+                },
+            ),
+            None => (
+                self.channels.close_id(),
+                Close {
+                    discovery_key: Some(discovery_key.to_vec()),
+                },
+            ),
+        };
+
+        let channel_message = ChannelMessage::new(local_id as u64, Message::Close(close));
+        self.queue_message(channel_message);
+        self.channels.remove(&discovery_key);
+        true
+    }
+
     fn on_open(&mut self, ch: u64, msg: Open) -> Result<()> {
         let discovery_key: DiscoveryKey = parse_key(&msg.discovery_key)?;
+        self.check_open_limits(ch, &discovery_key)?;
         let channel_handle =
             self.channels
                 .attach_remote(discovery_key, ch as usize, msg.capability);
@@ -447,6 +1218,8 @@ where
         if channel_handle.is_connected() {
             let local_id = channel_handle.local_id().unwrap();
             self.accept_channel(local_id)?;
+        } else if let Some(resolver) = self.options.resolver.clone() {
+            self.resolutions.push(resolver, discovery_key);
         } else {
             self.queue_event(Event::DiscoveryKey(discovery_key));
         }
@@ -454,23 +1227,107 @@ where
         Ok(())
     }
 
+    /// Check that the remote may open a channel with ID `ch`.
+    fn check_open_limits(&self, ch: u64, discovery_key: &DiscoveryKey) -> Result<()> {
+        let options = &self.options;
+        if ch > options.max_remote_channel_id {
+            return Err(Error::LimitExceeded(format!(
+                "Channel ID {} is above {}",
+                ch, options.max_remote_channel_id
+            )));
+        }
+        // Channels that were opened locally do not count against the limits.
+        if self.channels.get(discovery_key).is_some() {
+            return Ok(());
+        }
+        if self.channels.len() >= options.max_channels {
+            return Err(Error::LimitExceeded(format!(
+                "More than {} channels",
+                options.max_channels
+            )));
+        }
+        if self.channels.pending_opens() >= options.max_pending_opens {
+            return Err(Error::LimitExceeded(format!(
+                "More than {} channels that were not opened locally",
+                options.max_pending_opens
+            )));
+        }
+        Ok(())
+    }
+
+    fn on_resolved(&mut self, discovery_key: DiscoveryKey, key: Option<Key>) -> Result<()> {
+        // The channel may have been closed by the remote or opened locally
+        // in the meantime.
+        match self.channels.get(&discovery_key) {
+            Some(channel_handle) if channel_handle.local_id().is_none() => {}
+            _ => return Ok(()),
+        }
+        match key {
+            Some(key) if util::discovery_key(&key) == discovery_key => self.command_open(key),
+            Some(_) => {
+                log::warn!(
+                    "resolver returned a key that does not match discovery key {}",
+                    pretty_hash(&discovery_key)
+                );
+                self.queue_event(Event::DiscoveryKey(discovery_key));
+                Ok(())
+            }
+            None => {
+                self.queue_event(Event::DiscoveryKey(discovery_key));
+                Ok(())
+            }
+        }
+    }
+
     fn queue_event(&mut self, event: Event) {
         self.queued_events.push_back(event);
     }
 
+    /// Queue a message that is sent by the protocol itself.
+    fn queue_message(&mut self, channel_message: ChannelMessage) {
+        self.record_outbound(&channel_message);
+        self.queue_frame(channel_message);
+    }
+
     fn queue_frame_direct(&mut self, body: Vec<u8>) -> std::result::Result<bool, EncodeError> {
-        let frame = Frame::Raw(body);
+        let frame = Frame::Raw(body.into());
         self.write_state.try_queue_direct(&frame)
     }
 
     fn accept_channel(&mut self, local_id: usize) -> Result<()> {
         let (key, remote_capability) = self.channels.prepare_to_verify(local_id)?;
         self.verify_remote_capability(remote_capability.cloned(), key)?;
-        let channel = self.channels.accept(local_id, self.outbound_tx.clone())?;
+        let capacity = self.options.channel_capacity.max(1);
+        // Safe because channels are only accepted once the connection is established.
+        let version = self.version.unwrap();
+        let channel =
+            self.channels
+                .accept(local_id, self.outbound_tx.clone(), capacity, version)?;
         self.queue_event(Event::Channel(channel));
         Ok(())
     }
 
+    /// Close all channels. If `notify_remote` is true, a close message is
+    /// sent for each channel.
+    fn close_all_channels(&mut self, notify_remote: bool) {
+        let discovery_keys: Vec<DiscoveryKey> =
+            self.channels.iter().map(|c| *c.discovery_key()).collect();
+        for discovery_key in discovery_keys {
+            if notify_remote {
+                self.close_channel(discovery_key);
+            } else {
+                self.channels.remove(&discovery_key);
+                self.queue_event(Event::Close(discovery_key));
+            }
+        }
+    }
+
+    fn on_remote_closed(&mut self) {
+        log::debug!("[{}] remote closed the connection", self.is_initiator());
+        self.close_all_channels(false);
+        self.state = State::Closing(CloseReason::RemoteClosed);
+    }
+
     fn close_local(&mut self, local_id: u64) {
         if let Some(channel) = self.channels.get_local(local_id as usize) {
             let discovery_key = *channel.discovery_key();
@@ -480,10 +1337,19 @@ where
     }
 
     fn on_close(&mut self, remote_id: u64, msg: Close) -> Result<()> {
-        if let Some(channel_handle) = self.channels.get_remote(remote_id as usize) {
-            let discovery_key = *channel_handle.discovery_key();
-            self.channels
-                .forward_inbound_message(remote_id as usize, Message::Close(msg))?;
+        // If the remote did not open the channel, the close message
+        // identifies the channel by its discovery key.
+        let discovery_key = match msg.discovery_key.as_deref() {
+            Some(discovery_key) => parse_key(discovery_key)?,
+            None => match self.channels.get_remote(remote_id as usize) {
+                Some(channel_handle) => *channel_handle.discovery_key(),
+                None => return Ok(()),
+            },
+        };
+        if let Some(channel_handle) = self.channels.get_mut(&discovery_key) {
+            if channel_handle.is_open() {
+                self.pending_inbound = channel_handle.try_send_inbound(Message::Close(msg))?;
+            }
             self.channels.remove(&discovery_key);
             self.queue_event(Event::Close(discovery_key));
         }
@@ -491,19 +1357,35 @@ where
     }
 
     fn capability(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.handshake.as_ref() {
-            Some(handshake) => handshake.capability(key),
-            None => None,
+        match (self.handshake.as_ref(), self.version) {
+            #[cfg(feature = "v10")]
+            (Some(handshake), Some(Version::V10)) => {
+                let hash = &handshake.handshake_hash;
+                Some(v10::handshake::capability(hash, handshake.is_initiator, key).to_vec())
+            }
+            (Some(handshake), _) => handshake.capability(key),
+            (None, _) => None,
         }
     }
 
     fn verify_remote_capability(&self, capability: Option<Vec<u8>>, key: &[u8]) -> Result<()> {
+        // Capabilities are derived from the handshake, so disabling it also
+        // disables their verification, as documented on `Options::noise`.
+        if !self.options.noise {
+            return Ok(());
+        }
         match self.handshake.as_ref() {
+            #[cfg(feature = "v10")]
+            Some(handshake) if self.version == Some(Version::V10) => {
+                let hash = &handshake.handshake_hash;
+                let expected = v10::handshake::capability(hash, !handshake.is_initiator, key);
+                match capability {
+                    Some(capability) if capability == expected => Ok(()),
+                    _ => Err(Error::CapabilityMismatch),
+                }
+            }
             Some(handshake) => handshake.verify_remote_capability(capability, key),
-            None => Err(Error::new(
-                ErrorKind::PermissionDenied,
-                "Missing handshake state for capability verification",
-            )),
+            None => Err(Error::CapabilityMismatch),
         }
     }
 }
@@ -514,7 +1396,7 @@ where
 {
     type Item = Result<Event>;
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Protocol::poll_next(self, cx).map(Some)
+        Protocol::poll_next(self, cx)
     }
 }
 
@@ -537,9 +1419,57 @@ impl CommandTx {
     pub async fn close(&mut self, discovery_key: DiscoveryKey) -> Result<()> {
         self.send(Command::Close(discovery_key)).await
     }
+
+    /// Reject a protocol channel that was only opened by the remote.
+    pub async fn reject(&mut self, discovery_key: DiscoveryKey) -> Result<()> {
+        self.send(Command::Reject(discovery_key)).await
+    }
+}
+
+/// Check if `message` has a v10 counterpart, see [`to_v10`].
+#[cfg(feature = "v10")]
+fn is_v10_writable(message: &ChannelMessage) -> bool {
+    match &message.message {
+        Message::Open(_) | Message::Close(_) => true,
+        // There are no extensions on the main protocol stream.
+        Message::Extension(_) => message.channel != 0 && message.extension.is_some(),
+        Message::V10(msg) => !msg.is_control(),
+        _ => false,
+    }
+}
+
+/// Convert a message to its v10 counterpart.
+#[cfg(feature = "v10")]
+fn to_v10(message: ChannelMessage) -> Option<v10::ChannelMessage> {
+    let ChannelMessage {
+        channel,
+        message,
+        extension,
+    } = message;
+    let message = match message {
+        Message::Open(msg) => v10::Message::Open(v10::message::Open {
+            protocol: v10::message::PROTOCOL_NAME.into(),
+            discovery_key: msg.discovery_key,
+            handshake: msg
+                .capability
+                .and_then(|capability| capability.as_slice().try_into().ok())
+                .map(|capability| v10::message::Handshake {
+                    seeks: false,
+                    capability,
+                }),
+        }),
+        Message::Close(_) => v10::Message::Close(v10::message::Close),
+        Message::Extension(msg) => v10::Message::Extension(v10::message::Extension {
+            name: extension?,
+            message: msg.message.to_vec(),
+        }),
+        Message::V10(msg) => msg,
+        _ => return None,
+    };
+    Some(v10::ChannelMessage::new(channel, message))
 }
 
-fn parse_key(key: &[u8]) -> io::Result<[u8; 32]> {
+fn parse_key(key: &[u8]) -> Result<[u8; 32]> {
     key.try_into()
-        .map_err(|_e| io::Error::new(io::ErrorKind::InvalidInput, "Key must be 32 bytes long"))
+        .map_err(|_e| Error::InvalidKey("Key must be 32 bytes long".into()))
 }
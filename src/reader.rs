@@ -1,46 +1,82 @@
-use crate::noise::{Cipher, HandshakeResult};
+use crate::capture::{Capture, Direction, FrameKind};
+use crate::error::{Error, Result};
+use crate::noise::{Cipher, Encryption, FrameCipher, HandshakeResult, TAG_LEN};
+use crate::throttle::Throttle;
+#[cfg(feature = "v10")]
+use crate::v10::{self, secretstream::StreamRx};
+use bytes::{Buf, Bytes, BytesMut};
 use futures_lite::io::AsyncRead;
 use futures_timer::Delay;
+#[cfg(feature = "v10")]
+use std::collections::VecDeque;
 use std::future::Future;
-use std::io::{Error, ErrorKind, Result};
+use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use crate::constants::{DEFAULT_TIMEOUT, MAX_MESSAGE_SIZE};
-use crate::message::{Frame, FrameType};
+use crate::constants::MAX_MESSAGE_SIZE;
+use crate::message::{Frame, FrameType, Framing};
 use std::time::Duration;
 
-const TIMEOUT: Duration = Duration::from_secs(DEFAULT_TIMEOUT as u64);
 const READ_BUF_INITIAL_SIZE: usize = 1024 * 128;
 
 #[derive(Debug)]
 pub struct ReadState {
-    /// The read buffer.
-    buf: Vec<u8>,
-    /// The start of the not-yet-processed byte range in the read buffer.
-    start: usize,
+    /// The read buffer. Frames are split off the front of the buffer and
+    /// handed out without copying, so the not-yet-processed bytes always
+    /// start at index 0.
+    buf: BytesMut,
     /// The end of the not-yet-processed byte range in the read buffer.
     end: usize,
     /// The logical state of the reading (either header or body).
     step: Step,
     /// The timeout after which the connection is closed.
-    timeout: Delay,
+    timeout: Option<(Delay, Duration)>,
     /// Optional encryption cipher.
     cipher: Option<Cipher>,
+    /// Optional cipher for authenticated frames.
+    frame_cipher: Option<FrameCipher>,
+    /// Optional secret-stream of v10.
+    #[cfg(feature = "v10")]
+    secret_stream: Option<StreamRx>,
+    /// How frames are delimited.
+    framing: Framing,
     /// The frame type to be passed to the decoder.
     frame_type: FrameType,
+    /// Decoded v10 messages that were sent in a batch and not handed out yet.
+    #[cfg(feature = "v10")]
+    pending: VecDeque<Frame>,
+    /// Limits the rate at which bytes are read.
+    throttle: Throttle,
+    /// Number of bytes read from the connection.
+    bytes_read: u64,
+    /// Number of frames read, including keepalives.
+    frames_read: u64,
+    /// Records the frames after they are decrypted.
+    capture: Option<Capture>,
 }
 
 impl ReadState {
-    pub fn new() -> ReadState {
+    pub fn new(timeout: Option<Duration>, throttle: Throttle) -> ReadState {
+        let mut buf = BytesMut::with_capacity(READ_BUF_INITIAL_SIZE);
+        buf.resize(READ_BUF_INITIAL_SIZE, 0u8);
         ReadState {
-            buf: vec![0u8; READ_BUF_INITIAL_SIZE as usize],
-            start: 0,
+            buf,
             end: 0,
             step: Step::Header,
-            timeout: Delay::new(TIMEOUT),
+            timeout: timeout.map(|duration| (Delay::new(duration), duration)),
             cipher: None,
+            frame_cipher: None,
+            #[cfg(feature = "v10")]
+            secret_stream: None,
+            framing: Framing::Varint,
             frame_type: FrameType::Raw,
+            #[cfg(feature = "v10")]
+            pending: VecDeque::new(),
+            throttle,
+            bytes_read: 0,
+            frames_read: 0,
+            capture: None,
         }
     }
 }
@@ -52,40 +88,127 @@ enum Step {
 }
 
 impl ReadState {
-    pub fn upgrade_with_handshake(&mut self, handshake: &HandshakeResult) -> Result<()> {
-        let mut cipher = Cipher::from_handshake_rx(handshake)?;
-        cipher.apply(&mut self.buf[self.start..self.end]);
-        self.cipher = Some(cipher);
+    pub fn upgrade_with_handshake(
+        &mut self,
+        handshake: &HandshakeResult,
+        encryption: Encryption,
+    ) -> Result<()> {
+        match encryption {
+            Encryption::Stream => {
+                let mut cipher = Cipher::from_handshake_rx(handshake)?;
+                cipher.apply(&mut self.buf[..self.end]);
+                self.cipher = Some(cipher);
+            }
+            Encryption::Authenticated => {
+                self.frame_cipher = Some(FrameCipher::from_handshake_rx(handshake));
+            }
+        }
         Ok(())
     }
 
+    /// Decrypt all following frames with the secret-stream of v10.
+    #[cfg(feature = "v10")]
+    pub fn upgrade_with_secret_stream(&mut self, handshake: &HandshakeResult) {
+        self.secret_stream = Some(StreamRx::new(handshake));
+    }
+
+    /// Restart the timeout, e.g. after reading was paused.
+    pub fn reset_timeout(&mut self) {
+        if let Some((timeout, duration)) = self.timeout.as_mut() {
+            timeout.reset(*duration);
+        }
+    }
+
     pub fn set_frame_type(&mut self, frame_type: FrameType) {
         self.frame_type = frame_type;
     }
 
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    fn capture(&self, body: &[u8]) {
+        if let Some(capture) = self.capture.as_ref() {
+            let kind = match self.frame_type {
+                FrameType::Raw => FrameKind::Handshake,
+                FrameType::Message => FrameKind::Message,
+            };
+            capture.record(Direction::Inbound, kind, body);
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn frames_read(&self) -> u64 {
+        self.frames_read
+    }
+
+    /// Poll the reader for the next frame.
+    ///
+    /// Returns `None` if the remote closed the connection.
     pub fn poll_reader<R>(
         &mut self,
         cx: &mut Context<'_>,
         mut reader: &mut R,
-    ) -> Poll<Result<Frame>>
+    ) -> Poll<Option<Result<Frame>>>
     where
         R: AsyncRead + Unpin,
     {
         loop {
             if let Some(result) = self.process() {
-                return Poll::Ready(result);
+                return Poll::Ready(Some(result));
             }
+            self.reserve_read_space();
 
-            let n = match Pin::new(&mut reader).poll_read(cx, &mut self.buf[self.end..]) {
+            let len = match self.throttle.poll_acquire(cx, self.buf.len() - self.end) {
+                Poll::Ready(len) => len,
+                Poll::Pending => {
+                    // Waiting for the rate limit does not count towards the timeout.
+                    self.reset_timeout();
+                    return Poll::Pending;
+                }
+            };
+            let result =
+                Pin::new(&mut reader).poll_read(cx, &mut self.buf[self.end..self.end + len]);
+            let read = match &result {
+                Poll::Ready(Ok(n)) => *n,
+                _ => 0,
+            };
+            self.throttle.refund(len - read);
+            let n = match result {
                 Poll::Ready(Ok(n)) if n > 0 => n,
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                // If the reader returns 0 bytes, the remote closed the connection.
+                Poll::Ready(Ok(_)) => {
+                    if self.end == 0 {
+                        return Poll::Ready(None);
+                    } else {
+                        return Poll::Ready(Some(Err(Error::Io(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Remote closed the connection in the middle of a message",
+                        )))));
+                    }
+                }
                 // If the reader is pending, poll the timeout.
-                Poll::Pending | Poll::Ready(Ok(_)) => {
-                    // Return Pending if the timeout is pending, or an error if the
-                    // timeout expired (i.e. returned Poll::Ready).
-                    return Pin::new(&mut self.timeout)
-                        .poll(cx)
-                        .map(|()| Err(Error::new(ErrorKind::TimedOut, "Remote timed out")));
+                Poll::Pending => {
+                    // Return Pending if the timeout is pending or disabled, or an error
+                    // if the timeout expired (i.e. returned Poll::Ready).
+                    return match self.timeout.as_mut() {
+                        Some((timeout, _)) => Pin::new(timeout)
+                            .poll(cx)
+                            .map(|()| Some(Err(Error::Timeout))),
+                        None => Poll::Pending,
+                    };
                 }
             };
 
@@ -94,43 +217,48 @@ impl ReadState {
                 cipher.apply(&mut self.buf[self.end..end]);
             }
             self.end = end;
-            self.timeout.reset(TIMEOUT);
+            self.bytes_read += n as u64;
+            self.reset_timeout();
         }
     }
 
-    fn cycle_buf_if_needed(&mut self) {
-        // TODO: It would be great if we wouldn't have to allocate here.
+    /// Make sure there is space to read into. This only allocates if frames
+    /// that were handed out still reference the current buffer, otherwise the
+    /// buffer is reused.
+    fn reserve_read_space(&mut self) {
         if self.end == self.buf.len() {
-            let temp = self.buf[self.start..self.end].to_vec();
-            let len = temp.len();
-            self.buf[..len].copy_from_slice(&temp[..]);
-            self.end = len;
-            self.start = 0;
+            self.buf.resize(self.end + READ_BUF_INITIAL_SIZE, 0u8);
         }
     }
 
+    /// Split the first `len` bytes off the buffer.
+    fn split_frame(&mut self, len: usize) -> Bytes {
+        self.end -= len;
+        self.buf.split_to(len).freeze()
+    }
+
     fn process(&mut self) -> Option<Result<Frame>> {
-        if self.start == self.end {
+        #[cfg(feature = "v10")]
+        if let Some(frame) = self.pending.pop_front() {
+            return Some(Ok(frame));
+        }
+        if self.end == 0 {
             return None;
         }
         loop {
             match self.step {
                 Step::Header => {
-                    let varint = varint_decode(&self.buf[self.start..self.end]);
-                    if let Some((header_len, body_len)) = varint {
+                    if let Some((header_len, body_len)) = self.decode_header() {
                         let body_len = body_len as usize;
-                        if body_len > MAX_MESSAGE_SIZE as usize {
-                            return Some(Err(Error::new(
-                                ErrorKind::InvalidData,
-                                "Message length above max allowed size",
-                            )));
+                        let max_len = MAX_MESSAGE_SIZE as usize + self.overhead();
+                        if body_len > max_len {
+                            return Some(Err(Error::OversizeFrame(body_len)));
                         }
                         self.step = Step::Body {
                             header_len,
                             body_len,
                         };
                     } else {
-                        self.cycle_buf_if_needed();
                         return None;
                     }
                 }
@@ -139,23 +267,103 @@ impl ReadState {
                     body_len,
                 } => {
                     let message_len = header_len + body_len;
-                    if message_len > self.buf.len() {
-                        self.buf.resize(message_len, 0u8);
-                    }
-                    if (self.end - self.start) < message_len {
-                        self.cycle_buf_if_needed();
+                    if self.end < message_len {
+                        if message_len > self.buf.len() {
+                            self.buf.resize(message_len, 0u8);
+                        }
                         return None;
+                    }
+                    self.step = Step::Header;
+                    self.frames_read += 1;
+                    if body_len == 0 && self.frame_cipher.is_none() {
+                        // Empty messages are keepalive pings, skip them.
+                        self.buf.advance(message_len);
+                        self.end -= message_len;
                     } else {
-                        let range = self.start + header_len..self.start + message_len;
-                        let frame = Frame::decode(&self.buf[range], &self.frame_type);
-                        self.start += message_len;
-                        self.step = Step::Header;
-                        return Some(frame);
+                        let body = match self.decrypt_frame(header_len, message_len) {
+                            Ok(body) => body,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        // Empty messages are keepalive pings, skip them.
+                        if let Some(body) = body.filter(|body| !body.is_empty()) {
+                            self.capture(&body);
+                            if let Some(result) = self.decode(body) {
+                                return Some(result);
+                            }
+                        }
+                    }
+                    if self.end == 0 {
+                        return None;
                     }
                 }
             }
         }
     }
+
+    /// Decode the length prefix of the next frame.
+    fn decode_header(&mut self) -> Option<(usize, u64)> {
+        let buf = &self.buf[..self.end];
+        match self.framing {
+            Framing::Varint => varint_decode(buf),
+            #[cfg(feature = "v10")]
+            Framing::Uint24 => {
+                let len = v10::frame_len(buf)?;
+                Some((v10::FRAME_HEADER_LEN, (len - v10::FRAME_HEADER_LEN) as u64))
+            }
+            #[cfg(feature = "v10")]
+            Framing::Detect => {
+                self.framing = v10::detect_framing(buf)?;
+                self.decode_header()
+            }
+        }
+    }
+
+    /// The number of bytes that encryption adds to a frame body.
+    fn overhead(&self) -> usize {
+        #[cfg(feature = "v10")]
+        if self.secret_stream.is_some() {
+            return v10::secretstream::ABYTES;
+        }
+        match self.frame_cipher {
+            Some(_) => TAG_LEN,
+            None => 0,
+        }
+    }
+
+    /// Split the frame off the buffer and decrypt its body. Returns `None`
+    /// for the header frame of a secret-stream.
+    fn decrypt_frame(&mut self, header_len: usize, message_len: usize) -> Result<Option<Bytes>> {
+        #[cfg(feature = "v10")]
+        if let Some(secret_stream) = self.secret_stream.as_mut() {
+            let result = secret_stream.decrypt(&self.buf[header_len..message_len]);
+            self.buf.advance(message_len);
+            self.end -= message_len;
+            return result.map(|body| body.map(Bytes::from));
+        }
+        match self.frame_cipher.as_mut() {
+            Some(frame_cipher) => {
+                let result = frame_cipher.decrypt(&self.buf[header_len..message_len]);
+                self.buf.advance(message_len);
+                self.end -= message_len;
+                result.map(|body| Some(body.into()))
+            }
+            None => Ok(Some(self.split_frame(message_len).slice(header_len..))),
+        }
+    }
+
+    /// Decode the body of a frame. Returns `None` for an empty batch of v10
+    /// messages.
+    fn decode(&mut self, body: Bytes) -> Option<Result<Frame>> {
+        #[cfg(feature = "v10")]
+        if self.framing == Framing::Uint24 && self.frame_type == FrameType::Message {
+            match v10::ChannelMessage::decode_frame(&body) {
+                Ok(messages) => self.pending.extend(messages.into_iter().map(Frame::V10)),
+                Err(e) => return Some(Err(e)),
+            }
+            return self.pending.pop_front().map(Ok);
+        }
+        Some(Frame::decode(body, &self.frame_type))
+    }
 }
 
 fn varint_decode(buf: &[u8]) -> Option<(usize, u64)> {
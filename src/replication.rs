@@ -0,0 +1,657 @@
+//! Replicate a feed over a [`Channel`].
+//!
+//! The [`Replicator`] implements the Want/Have/Request/Data exchange on top of
+//! an open channel. Storage and verification are left to the [`Feed`] trait,
+//! so any append-only log (for example a `hypercore::Feed`) can be replicated
+//! by implementing it.
+//!
+//! The replicator speaks the messages of [`Version::V1`](crate::Version::V1),
+//! on channels of other versions [`Replicator::run`] fails with
+//! [`Error::UnsupportedMessage`].
+//!
+//! ```no_run
+//! # use hypercore_protocol::Channel;
+//! use hypercore_protocol::replication::{Feed, Replicator};
+//!
+//! async fn onchannel(channel: Channel, feed: impl Feed + 'static) {
+//!     let replicator = Replicator::new(channel, feed);
+//!     async_std::task::spawn(async move {
+//!         if let Err(e) = replicator.run().await {
+//!             eprintln!("replication failed: {}", e);
+//!         }
+//!     });
+//! }
+//! ```
+
+use async_channel::{Receiver, Sender};
+use async_trait::async_trait;
+use futures_lite::future;
+use futures_lite::stream::StreamExt;
+use futures_timer::Delay;
+use instant::Instant;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::bitfield::{Bitfield, MAX_BITFIELD_SIZE};
+use crate::constants::MAX_MESSAGE_SIZE;
+use crate::error::{Error, Result};
+use crate::schema::*;
+use crate::{Channel, Message};
+
+pub use crate::schema::data::Node;
+
+/// The default number of requests that may wait for a response.
+pub const DEFAULT_MAX_REQUESTS: usize = 16;
+
+/// The default time after which an unanswered request is sent again.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The maximum number of blocks announced by one Have with a bitfield.
+const MAX_HAVE_BITS: u64 = MAX_BITFIELD_SIZE as u64 * 8;
+
+/// The maximum size of the encoded bitfield of a Have, which leaves room for
+/// the other fields within [`MAX_MESSAGE_SIZE`].
+const MAX_HAVE_SIZE: usize = MAX_MESSAGE_SIZE as usize - 64;
+
+/// The proof that is sent along with a block so that the receiver can verify it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Proof {
+    /// The Merkle tree nodes needed to verify the block.
+    pub nodes: Vec<Node>,
+    /// The signature of the tree root.
+    pub signature: Option<Vec<u8>>,
+}
+
+/// A storage-agnostic append-only log that can be replicated.
+///
+/// Implementations are responsible for verifying blocks in [`Feed::put`]. Errors
+/// should be returned as [`Error::Feed`].
+#[async_trait]
+pub trait Feed: Send {
+    /// The number of blocks in the feed.
+    async fn len(&mut self) -> u64;
+
+    /// Check if the feed has no blocks.
+    async fn is_empty(&mut self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Check if the block at `index` is available locally.
+    async fn has(&mut self, index: u64) -> bool;
+
+    /// Get the runs of blocks in `range` that are available locally, as
+    /// sorted, non-overlapping ranges.
+    ///
+    /// Wants from the remote are answered with this query, so its cost should
+    /// not grow with the length of `range`. The default implementation calls
+    /// [`Feed::has`] for each index, override it if the storage keeps a
+    /// bitfield of its blocks.
+    async fn has_ranges(&mut self, range: Range<u64>) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for index in range {
+            if !self.has(index).await {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == index => last.end = index + 1,
+                _ => ranges.push(index..index + 1),
+            }
+        }
+        ranges
+    }
+
+    /// Get the block at `index`.
+    async fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>>;
+
+    /// Get the proof for the block at `index`.
+    async fn proof(&mut self, index: u64) -> Result<Proof>;
+
+    /// Verify a block received from the remote and store it.
+    async fn put(&mut self, index: u64, data: Option<&[u8]>, proof: Proof) -> Result<()>;
+}
+
+/// Options for a [`Replicator`].
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    /// Keep the channel open and replicate new blocks as they are announced.
+    ///
+    /// If false, replication ends once both peers have downloaded everything.
+    pub live: bool,
+    /// Only download the ranges passed to [`ReplicatorHandle::download`].
+    pub sparse: bool,
+    /// Serve blocks to the remote.
+    pub upload: bool,
+    /// Download blocks from the remote.
+    pub download: bool,
+    /// Maximum number of requests that may wait for a response.
+    pub max_requests: usize,
+    /// Time after which a request that was not answered is sent again.
+    ///
+    /// `None` waits forever.
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> Self {
+        Self {
+            live: false,
+            sparse: false,
+            upload: true,
+            download: true,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Announce(Range<u64>),
+    Download(Range<u64>),
+    Close,
+}
+
+/// A handle to control a running [`Replicator`].
+#[derive(Debug, Clone)]
+pub struct ReplicatorHandle {
+    command_tx: Sender<Command>,
+}
+
+impl ReplicatorHandle {
+    /// Announce blocks that were added to the local feed, e.g. after an append
+    /// or after they were downloaded from another peer.
+    pub async fn announce(&self, range: Range<u64>) -> Result<()> {
+        self.send(Command::Announce(range)).await
+    }
+
+    /// Download a range of blocks. This is only needed in sparse mode.
+    pub async fn download(&self, range: Range<u64>) -> Result<()> {
+        self.send(Command::Download(range)).await
+    }
+
+    /// Stop replicating and close the channel.
+    pub async fn close(&self) -> Result<()> {
+        self.send(Command::Close).await
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.command_tx
+            .send(command)
+            .await
+            .map_err(|_| Error::ChannelClosed)
+    }
+}
+
+/// Replicates a [`Feed`] with the remote end of a [`Channel`].
+///
+/// The replicator sends a Want for the whole feed (or for the ranges passed to
+/// [`ReplicatorHandle::download`] in sparse mode), answers Wants with Haves for
+/// the blocks it has, and requests and verifies the blocks announced by the
+/// remote. Requests for blocks the remote does not have are answered with an
+/// Unhave, and requests that are not answered within
+/// [`ReplicationOptions::request_timeout`] are sent again.
+///
+/// The Haves that answer a Want are followed by a Status with the `uploading`
+/// flag of the replicator, which tells the remote that the answer is
+/// complete. If [`ReplicationOptions::live`] is false, each peer sends a
+/// Status with only `downloading: false` once all its Wants are answered and
+/// it has all blocks it wants, and the channel is closed after both peers are
+/// done. Peers that do not answer Wants with a Status never finish a
+/// non-live replication.
+#[derive(Debug)]
+pub struct Replicator<F> {
+    channel: Channel,
+    feed: F,
+    options: ReplicationOptions,
+    command_tx: Sender<Command>,
+    command_rx: Receiver<Command>,
+    wanted: RangeSet,
+    remote_has: RangeSet,
+    remote_wants: RangeSet,
+    local_has: RangeSet,
+    /// The time at which each pending request was sent.
+    inflight: HashMap<u64, Instant>,
+    pending_wants: usize,
+    local_done: bool,
+    remote_done: bool,
+}
+
+enum Input {
+    Message(Option<Message>),
+    Command(Option<Command>),
+    Timeout,
+}
+
+impl<F> Replicator<F>
+where
+    F: Feed,
+{
+    /// Create a replicator with the default options.
+    pub fn new(channel: Channel, feed: F) -> Self {
+        Self::with_options(channel, feed, ReplicationOptions::default())
+    }
+
+    /// Create a replicator.
+    pub fn with_options(channel: Channel, feed: F, options: ReplicationOptions) -> Self {
+        let (command_tx, command_rx) = async_channel::unbounded();
+        let mut wanted = RangeSet::default();
+        if !options.sparse {
+            wanted.insert(0..u64::MAX);
+        }
+        Self {
+            channel,
+            feed,
+            options,
+            command_tx,
+            command_rx,
+            wanted,
+            remote_has: RangeSet::default(),
+            remote_wants: RangeSet::default(),
+            local_has: RangeSet::default(),
+            inflight: HashMap::new(),
+            pending_wants: 0,
+            local_done: false,
+            remote_done: false,
+        }
+    }
+
+    /// Get a handle to announce new blocks or request ranges while replicating.
+    pub fn handle(&self) -> ReplicatorHandle {
+        ReplicatorHandle {
+            command_tx: self.command_tx.clone(),
+        }
+    }
+
+    /// Run the replication until the channel is closed, and return the feed.
+    pub async fn run(mut self) -> Result<F> {
+        if self.options.download && !self.options.sparse {
+            self.send_want(0, None).await?;
+        }
+        while let Ok(command) = self.command_rx.try_recv() {
+            if !self.on_command(command).await? {
+                return Ok(self.feed);
+            }
+        }
+        self.update_status().await?;
+
+        loop {
+            if self.local_done && self.remote_done {
+                self.channel.close().await?;
+                return Ok(self.feed);
+            }
+            let timer = self.request_timer();
+            let channel = &mut self.channel;
+            let command_rx = &self.command_rx;
+            let message = async { Input::Message(channel.next().await) };
+            let command = async { Input::Command(command_rx.recv().await.ok()) };
+            let timeout = async {
+                match timer {
+                    Some(timer) => timer.await,
+                    None => future::pending().await,
+                }
+                Input::Timeout
+            };
+            let input = future::or(future::or(message, command), timeout).await;
+            match input {
+                Input::Message(Some(message)) => self.on_message(message).await?,
+                Input::Timeout => self.on_timeout().await?,
+                Input::Command(Some(command)) => {
+                    if !self.on_command(command).await? {
+                        return Ok(self.feed);
+                    }
+                }
+                Input::Message(None) | Input::Command(None) => return Ok(self.feed),
+            }
+        }
+    }
+
+    async fn on_command(&mut self, command: Command) -> Result<bool> {
+        match command {
+            Command::Announce(range) => {
+                let ranges: Vec<_> = self.remote_wants.intersect(&range).collect();
+                for range in ranges {
+                    self.send_haves(range).await?;
+                }
+            }
+            Command::Download(range) => {
+                if range.start < range.end {
+                    self.wanted.insert(range.clone());
+                    self.local_done = false;
+                    let length = range.end - range.start;
+                    self.send_want(range.start, Some(length)).await?;
+                }
+            }
+            Command::Close => {
+                self.channel.close().await?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn on_message(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Want(msg) => self.on_want(msg).await?,
+            Message::Unwant(msg) => {
+                self.remote_wants.remove(&range(msg.start, msg.length));
+            }
+            Message::Have(msg) => self.on_have(msg).await?,
+            Message::Unhave(msg) => {
+                let range = range(msg.start, msg.length);
+                self.remote_has.remove(&range);
+                // The remote does not answer requests for these blocks.
+                self.inflight.retain(|index, _| !range.contains(index));
+                self.update_status().await?;
+            }
+            Message::Request(msg) => self.on_request(msg).await?,
+            Message::Data(msg) => self.on_data(msg).await?,
+            Message::Status(msg) => {
+                if msg.uploading.is_some() {
+                    self.pending_wants = self.pending_wants.saturating_sub(1);
+                }
+                if msg.downloading == Some(false) {
+                    self.remote_done = true;
+                }
+                self.update_status().await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_want(&mut self, msg: Want) -> Result<()> {
+        let wanted = match msg.length {
+            Some(length) => msg.start..msg.start.saturating_add(length),
+            None => msg.start..u64::MAX,
+        };
+        self.remote_wants.insert(wanted.clone());
+        if self.options.upload {
+            let len = self.feed.len().await;
+            self.send_haves(wanted.start..wanted.end.min(len)).await?;
+        }
+        self.channel
+            .status(Status {
+                uploading: Some(self.options.upload),
+                downloading: None,
+            })
+            .await
+    }
+
+    async fn on_have(&mut self, msg: Have) -> Result<()> {
+        if let Some(bitfield) = &msg.bitfield {
+            for range in Bitfield::decode_ranges(bitfield)? {
+                let start = msg.start.checked_add(range.start);
+                let end = msg.start.checked_add(range.end);
+                match (start, end) {
+                    (Some(start), Some(end)) => self.remote_has.insert(start..end),
+                    _ => return Err(Error::Decode("Have bitfield is out of range".into())),
+                }
+            }
+            return self.update_status().await;
+        }
+        self.remote_has.insert(range(msg.start, msg.length));
+        self.update_status().await
+    }
+
+    async fn on_request(&mut self, msg: Request) -> Result<()> {
+        if !self.options.upload || !self.feed.has(msg.index).await {
+            return self
+                .channel
+                .unhave(Unhave {
+                    start: msg.index,
+                    length: None,
+                })
+                .await;
+        }
+        let value = self.feed.get(msg.index).await?;
+        let proof = self.feed.proof(msg.index).await?;
+        self.channel
+            .data(Data {
+                index: msg.index,
+                value: value.map(Into::into),
+                nodes: proof.nodes,
+                signature: proof.signature,
+            })
+            .await
+    }
+
+    async fn on_data(&mut self, msg: Data) -> Result<()> {
+        let end = msg
+            .index
+            .checked_add(1)
+            .ok_or_else(|| Error::Decode("Data index is out of range".into()))?;
+        if self.inflight.remove(&msg.index).is_none() {
+            return Ok(());
+        }
+        let proof = Proof {
+            nodes: msg.nodes,
+            signature: msg.signature,
+        };
+        self.feed
+            .put(msg.index, msg.value.as_deref(), proof)
+            .await?;
+        self.local_has.insert(msg.index..end);
+        self.update_status().await
+    }
+
+    /// Request missing blocks and tell the remote when we are done downloading.
+    async fn update_status(&mut self) -> Result<()> {
+        if !self.options.download {
+            return self.set_local_done().await;
+        }
+        let complete = self.request_more().await?;
+        if complete && self.pending_wants == 0 && !self.options.live {
+            self.set_local_done().await?;
+        }
+        Ok(())
+    }
+
+    async fn set_local_done(&mut self) -> Result<()> {
+        if self.local_done || self.options.live {
+            return Ok(());
+        }
+        self.local_done = true;
+        self.channel
+            .status(Status {
+                uploading: None,
+                downloading: Some(false),
+            })
+            .await
+    }
+
+    /// Request blocks the remote has and we want. Returns true if no block
+    /// is missing.
+    async fn request_more(&mut self) -> Result<bool> {
+        let mut candidates = RangeSet::default();
+        for range in self.wanted.ranges() {
+            for range in self.remote_has.intersect(range) {
+                candidates.insert(range);
+            }
+        }
+        for range in self.local_has.ranges() {
+            candidates.remove(range);
+        }
+        // Blocks beyond the local length are missing, so only the blocks
+        // below it are looked up. Found blocks are remembered in `local_has`,
+        // so each block is looked up at most once.
+        let len = self.feed.len().await;
+        let lookups: Vec<_> = candidates.intersect(&(0..len)).collect();
+        for range in lookups {
+            for local in self.feed.has_ranges(range).await {
+                candidates.remove(&local);
+                self.local_has.insert(local);
+            }
+        }
+
+        let mut complete = true;
+        for range in candidates.ranges() {
+            for index in range.clone() {
+                complete = false;
+                if self.inflight.contains_key(&index) {
+                    continue;
+                }
+                if self.inflight.len() >= self.options.max_requests {
+                    return Ok(false);
+                }
+                self.send_request(index).await?;
+            }
+        }
+        Ok(complete)
+    }
+
+    async fn send_request(&mut self, index: u64) -> Result<()> {
+        self.inflight.insert(index, Instant::now());
+        self.channel
+            .request(Request {
+                index,
+                bytes: None,
+                hash: None,
+                nodes: None,
+            })
+            .await
+    }
+
+    /// A timer that fires when the oldest pending request expires.
+    fn request_timer(&self) -> Option<Delay> {
+        let timeout = self.options.request_timeout?;
+        let sent = self.inflight.values().min()?;
+        Some(Delay::new(
+            (*sent + timeout).saturating_duration_since(Instant::now()),
+        ))
+    }
+
+    /// Send the expired requests again.
+    async fn on_timeout(&mut self) -> Result<()> {
+        let timeout = match self.options.request_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        let mut expired: Vec<u64> = self
+            .inflight
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() >= timeout)
+            .map(|(index, _)| *index)
+            .collect();
+        expired.sort_unstable();
+        for index in expired {
+            self.send_request(index).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_want(&mut self, start: u64, length: Option<u64>) -> Result<()> {
+        self.pending_wants += 1;
+        self.channel.want(Want { start, length }).await
+    }
+
+    /// Announce the local blocks in `range`. Runs that are close to each
+    /// other are sent as a bitfield, which covers at most [`MAX_HAVE_BITS`]
+    /// blocks and is encoded in at most [`MAX_HAVE_SIZE`] bytes.
+    async fn send_haves(&mut self, range: Range<u64>) -> Result<()> {
+        let runs = self.feed.has_ranges(range).await;
+        let mut runs = &runs[..];
+        while let Some(first) = runs.first() {
+            let len = 1 + runs[1..]
+                .iter()
+                .take_while(|run| run.end - first.start <= MAX_HAVE_BITS)
+                .count();
+            // Split the chunk until the bitfield of each part fits into a message.
+            let mut chunks = vec![&runs[..len]];
+            while let Some(chunk) = chunks.pop() {
+                let have = match chunk {
+                    [run] => Have {
+                        start: run.start,
+                        length: Some(run.end - run.start),
+                        bitfield: None,
+                        ack: None,
+                    },
+                    _ => {
+                        let start = chunk[0].start;
+                        let mut bitfield = Bitfield::new();
+                        for run in chunk {
+                            bitfield.set_range(run.start - start..run.end - start, true);
+                        }
+                        let encoded = bitfield.encode();
+                        if encoded.len() > MAX_HAVE_SIZE {
+                            let (head, tail) = chunk.split_at(chunk.len() / 2);
+                            chunks.push(tail);
+                            chunks.push(head);
+                            continue;
+                        }
+                        Have {
+                            start,
+                            length: Some(bitfield.len()),
+                            bitfield: Some(encoded),
+                            ack: None,
+                        }
+                    }
+                };
+                self.channel.have(have).await?;
+            }
+            runs = &runs[len..];
+        }
+        Ok(())
+    }
+}
+
+fn range(start: u64, length: Option<u64>) -> Range<u64> {
+    start..start.saturating_add(length.unwrap_or(1))
+}
+
+/// A set of indexes, stored as sorted, non-overlapping ranges.
+#[derive(Debug, Default)]
+struct RangeSet(Vec<Range<u64>>);
+
+impl RangeSet {
+    fn ranges(&self) -> &[Range<u64>] {
+        &self.0
+    }
+
+    fn insert(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+        let mut merged = range;
+        let mut ranges = Vec::with_capacity(self.0.len() + 1);
+        for r in self.0.drain(..) {
+            if r.end < merged.start || r.start > merged.end {
+                ranges.push(r);
+            } else {
+                merged = merged.start.min(r.start)..merged.end.max(r.end);
+            }
+        }
+        ranges.push(merged);
+        ranges.sort_by_key(|r| r.start);
+        self.0 = ranges;
+    }
+
+    fn remove(&mut self, range: &Range<u64>) {
+        let mut ranges = Vec::with_capacity(self.0.len() + 1);
+        for r in self.0.drain(..) {
+            if r.end <= range.start || r.start >= range.end {
+                ranges.push(r);
+                continue;
+            }
+            if r.start < range.start {
+                ranges.push(r.start..range.start);
+            }
+            if r.end > range.end {
+                ranges.push(range.end..r.end);
+            }
+        }
+        self.0 = ranges;
+    }
+
+    fn intersect<'a>(&'a self, range: &'a Range<u64>) -> impl Iterator<Item = Range<u64>> + 'a {
+        self.0.iter().filter_map(move |r| {
+            let start = r.start.max(range.start);
+            let end = r.end.min(range.end);
+            if start < end {
+                Some(start..end)
+            } else {
+                None
+            }
+        })
+    }
+}
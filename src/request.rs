@@ -0,0 +1,308 @@
+use crate::channels::ChannelSender;
+use crate::error::{Error, Result};
+use crate::message::ChannelMessage;
+use crate::schema::{Cancel, Data, Request};
+use crate::util::map_channel_err;
+use crate::version::Version;
+use crate::Message;
+use async_channel::{Receiver, Sender};
+use futures_lite::stream::Stream;
+use futures_timer::Delay;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The requests of a channel that wait for their `Data` message.
+///
+/// Shared between a [`Channel`](crate::Channel) and the protocol, which
+/// hands matching `Data` messages to the requests.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Requests(Arc<Mutex<RequestMap>>);
+
+#[derive(Debug, Default)]
+struct RequestMap {
+    waiters: HashMap<u64, Vec<(u64, Sender<Data>)>>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl Requests {
+    /// Wait for the block `index`. Returns the ID of the waiter, whether it
+    /// is the first waiter for this block, and the receiver for the data.
+    fn register(&self, index: u64) -> Result<(u64, bool, Receiver<Data>)> {
+        let mut map = self.0.lock().unwrap();
+        if map.closed {
+            return Err(Error::ChannelClosed);
+        }
+        let id = map.next_id;
+        map.next_id += 1;
+        let (tx, rx) = async_channel::bounded(1);
+        let waiters = map.waiters.entry(index).or_default();
+        waiters.push((id, tx));
+        Ok((id, waiters.len() == 1, rx))
+    }
+
+    /// Stop waiting. Returns true if no one else waits for the block.
+    fn unregister(&self, index: u64, id: u64) -> bool {
+        let mut map = self.0.lock().unwrap();
+        match map.waiters.get_mut(&index) {
+            Some(waiters) => {
+                waiters.retain(|(waiter_id, _)| *waiter_id != id);
+                if waiters.is_empty() {
+                    map.waiters.remove(&index);
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Hand a `Data` message to the requests that wait for it. Returns false
+    /// if no one waits for it.
+    pub(crate) fn on_data(&self, data: &Data) -> bool {
+        let waiters = self.0.lock().unwrap().waiters.remove(&data.index);
+        match waiters {
+            Some(waiters) => {
+                for (_, tx) in waiters {
+                    let _ = tx.try_send(data.clone());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fail all waiting requests with [`Error::ChannelClosed`].
+    pub(crate) fn close(&self) {
+        let mut map = self.0.lock().unwrap();
+        map.closed = true;
+        map.waiters.clear();
+    }
+}
+
+/// Everything that is needed to send requests on a channel.
+#[derive(Clone, Debug)]
+pub(crate) struct Requester {
+    pub(crate) requests: Requests,
+    pub(crate) outbound_tx: Sender<ChannelMessage>,
+    pub(crate) local_id: u64,
+    pub(crate) closed: Arc<AtomicBool>,
+    pub(crate) version: Version,
+}
+
+type SendFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A request for a block on one channel.
+struct Attempt {
+    requester: Requester,
+    index: u64,
+    id: u64,
+    receiver: Receiver<Data>,
+    send: Option<SendFuture>,
+    timeout: Option<Delay>,
+    done: bool,
+}
+
+impl Attempt {
+    fn start(requester: Requester, index: u64, timeout: Option<Duration>) -> Result<Self> {
+        if requester.closed.load(Ordering::SeqCst) {
+            return Err(Error::ChannelClosed);
+        }
+        let request = Message::Request(Request {
+            index,
+            bytes: None,
+            hash: None,
+            nodes: None,
+        });
+        if !requester.version.supports(&request) {
+            return Err(Error::UnsupportedMessage(requester.version));
+        }
+        let (id, first, receiver) = requester.requests.register(index)?;
+        // Blocks that were already requested are not requested again.
+        let send: Option<SendFuture> = if first {
+            let outbound_tx = requester.outbound_tx.clone();
+            let message = ChannelMessage::new(requester.local_id, request);
+            Some(Box::pin(async move {
+                outbound_tx.send(message).await.map_err(map_channel_err)
+            }))
+        } else {
+            None
+        };
+        Ok(Self {
+            requester,
+            index,
+            id,
+            receiver,
+            send,
+            timeout: timeout.map(Delay::new),
+            done: false,
+        })
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<Data>> {
+        if let Some(send) = self.send.as_mut() {
+            match send.as_mut().poll(cx) {
+                Poll::Ready(Ok(())) => self.send = None,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+        }
+        match Pin::new(&mut self.receiver).poll_next(cx) {
+            Poll::Ready(Some(data)) => {
+                self.done = true;
+                return Poll::Ready(Ok(data));
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                return Poll::Ready(Err(Error::ChannelClosed));
+            }
+            Poll::Pending => {}
+        }
+        if let Some(timeout) = self.timeout.as_mut() {
+            if Pin::new(timeout).poll(cx).is_ready() {
+                return Poll::Ready(Err(Error::Timeout));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let last = self.requester.requests.unregister(self.index, self.id);
+        // A request that was not sent yet does not need to be cancelled.
+        let sent = self.send.is_none();
+        if sent && last && !self.requester.closed.load(Ordering::SeqCst) {
+            let message = ChannelMessage::new(
+                self.requester.local_id,
+                Message::Cancel(Cancel {
+                    index: self.index,
+                    bytes: None,
+                    hash: None,
+                }),
+            );
+            // Drop cannot wait for space in the outbound queue. If it is full
+            // or closed, the remote may still send the block, which then
+            // arrives on the channel like any other `Data` message.
+            if self.requester.outbound_tx.try_send(message).is_err() {
+                log::debug!("could not cancel request for block {}", self.index);
+            }
+        }
+    }
+}
+
+/// A request for a block that resolves with the block's `Data` message.
+///
+/// Created with [`Channel::get`](crate::Channel::get). The request is sent
+/// when the future is first polled. If the future is dropped after the request
+/// was sent but before the data arrived, a `Cancel` message is sent. If the
+/// outbound queue of the channel is full at that moment, the `Cancel` is
+/// dropped, and the data may still arrive on the channel.
+///
+/// With [`Get::timeout`], a request that is not answered in time is
+/// cancelled, and retried on the channels that were added with
+/// [`Get::or`], e.g. channels for the same feed with other peers.
+///
+/// ```no_run
+/// # async fn get(a: &hypercore_protocol::Channel, b: &hypercore_protocol::Channel) -> hypercore_protocol::Result<()> {
+/// use std::time::Duration;
+///
+/// let data = a.get(5).timeout(Duration::from_secs(5)).or(b).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[must_use = "futures do nothing unless polled"]
+pub struct Get {
+    index: u64,
+    timeout: Option<Duration>,
+    attempt: Option<Attempt>,
+    requesters: VecDeque<Requester>,
+}
+
+impl fmt::Debug for Get {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Get")
+            .field("index", &self.index)
+            .field("timeout", &self.timeout)
+            .field(
+                "channels",
+                &(self.requesters.len() + self.attempt.iter().count()),
+            )
+            .finish()
+    }
+}
+
+impl Get {
+    pub(crate) fn new(requester: Requester, index: u64) -> Self {
+        let mut requesters = VecDeque::new();
+        requesters.push_back(requester);
+        Self {
+            index,
+            timeout: None,
+            attempt: None,
+            requesters,
+        }
+    }
+
+    /// Fail with [`Error::Timeout`] if a channel does not answer within
+    /// `timeout`. The timeout applies to each channel separately.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry on `channel` if the previous channels timed out or were closed.
+    pub fn or(mut self, channel: impl AsRef<ChannelSender>) -> Self {
+        self.requesters.push_back(channel.as_ref().requester());
+        self
+    }
+}
+
+impl Future for Get {
+    type Output = Result<Data>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let attempt = match this.attempt.as_mut() {
+                Some(attempt) => attempt,
+                None => {
+                    // Attempts that fail only continue if there are more requesters.
+                    let requester = this
+                        .requesters
+                        .pop_front()
+                        .expect("Get polled after completion");
+                    match Attempt::start(requester, this.index, this.timeout) {
+                        Ok(attempt) => this.attempt.insert(attempt),
+                        Err(e) if this.requesters.is_empty() => return Poll::Ready(Err(e)),
+                        Err(_) => continue,
+                    }
+                }
+            };
+            match attempt.poll(cx) {
+                Poll::Ready(Ok(data)) => {
+                    this.attempt = None;
+                    return Poll::Ready(Ok(data));
+                }
+                Poll::Ready(Err(e)) => {
+                    // Dropping the attempt cancels its request.
+                    this.attempt = None;
+                    if this.requesters.is_empty() {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
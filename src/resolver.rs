@@ -0,0 +1,80 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::protocol::{DiscoveryKey, Key};
+
+/// Look up the keys of channels that the remote opens.
+///
+/// If the remote opens a channel that was not opened locally, the resolver
+/// is asked for the key of the channel's discovery key. If it returns a key,
+/// the channel is opened right away and emitted as [`Event::Channel`] once
+/// the remote's capability is verified. If it returns `None`, the
+/// [`Event::DiscoveryKey`] is emitted, and the channel can be opened with
+/// [`Protocol::open`] or rejected with [`Protocol::reject`].
+///
+/// ```
+/// use hypercore_protocol::{discovery_key, DiscoveryKey, Key, ProtocolBuilder, Resolver};
+/// use std::collections::HashMap;
+///
+/// #[derive(Debug)]
+/// struct Feeds(HashMap<DiscoveryKey, Key>);
+///
+/// #[async_trait::async_trait]
+/// impl Resolver for Feeds {
+///     async fn resolve(&self, discovery_key: &DiscoveryKey) -> Option<Key> {
+///         self.0.get(discovery_key).copied()
+///     }
+/// }
+///
+/// let key = [1u8; 32];
+/// let mut feeds = HashMap::new();
+/// feeds.insert(discovery_key(&key), key);
+/// let builder = ProtocolBuilder::new(true).set_resolver(Feeds(feeds));
+/// ```
+///
+/// [`Event::Channel`]: crate::Event::Channel
+/// [`Event::DiscoveryKey`]: crate::Event::DiscoveryKey
+/// [`Protocol::open`]: crate::Protocol::open
+/// [`Protocol::reject`]: crate::Protocol::reject
+#[async_trait]
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// Return the key that belongs to `discovery_key`, or `None` if it is unknown.
+    async fn resolve(&self, discovery_key: &DiscoveryKey) -> Option<Key>;
+}
+
+type ResolveFuture = Pin<Box<dyn Future<Output = (DiscoveryKey, Option<Key>)> + Send>>;
+
+/// The resolutions that are in progress.
+#[derive(Default)]
+pub(crate) struct Resolutions(Vec<ResolveFuture>);
+
+impl fmt::Debug for Resolutions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolutions({})", self.0.len())
+    }
+}
+
+impl Resolutions {
+    /// Start to resolve `discovery_key`.
+    pub(crate) fn push(&mut self, resolver: Arc<dyn Resolver>, discovery_key: DiscoveryKey) {
+        self.0.push(Box::pin(async move {
+            let key = resolver.resolve(&discovery_key).await;
+            (discovery_key, key)
+        }));
+    }
+
+    /// Poll for the next completed resolution, in any order.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<(DiscoveryKey, Option<Key>)> {
+        for i in 0..self.0.len() {
+            if let Poll::Ready(resolved) = self.0[i].as_mut().poll(cx) {
+                drop(self.0.swap_remove(i));
+                return Poll::Ready(resolved);
+            }
+        }
+        Poll::Pending
+    }
+}
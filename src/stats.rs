@@ -0,0 +1,185 @@
+use crate::message::{Encoder, Message};
+#[cfg(feature = "v10")]
+use crate::v10;
+use std::time::Duration;
+
+/// Number of messages of one type and their encoded size in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageCounter {
+    /// Number of messages.
+    pub messages: u64,
+    /// Encoded size of the messages, without frame headers.
+    pub bytes: u64,
+}
+
+impl MessageCounter {
+    fn add(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Message counters per message type.
+///
+/// Messages of `Version::V10` are counted with the
+/// closest message type, for example `Synchronize` as `status` and
+/// `Bitfield` and `Range` as `have`.
+#[allow(missing_docs)]
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    pub open: MessageCounter,
+    pub options: MessageCounter,
+    pub status: MessageCounter,
+    pub have: MessageCounter,
+    pub unhave: MessageCounter,
+    pub want: MessageCounter,
+    pub unwant: MessageCounter,
+    pub request: MessageCounter,
+    pub cancel: MessageCounter,
+    pub data: MessageCounter,
+    pub close: MessageCounter,
+    pub extension: MessageCounter,
+}
+
+impl MessageStats {
+    /// The counters of all message types added up.
+    pub fn total(&self) -> MessageCounter {
+        let counters = [
+            self.open,
+            self.options,
+            self.status,
+            self.have,
+            self.unhave,
+            self.want,
+            self.unwant,
+            self.request,
+            self.cancel,
+            self.data,
+            self.close,
+            self.extension,
+        ];
+        counters
+            .iter()
+            .fold(MessageCounter::default(), |total, counter| MessageCounter {
+                messages: total.messages + counter.messages,
+                bytes: total.bytes + counter.bytes,
+            })
+    }
+
+    pub(crate) fn record(&mut self, message: &Message) {
+        let bytes = message.encoded_len();
+        let counter = match message {
+            Message::Open(_) => &mut self.open,
+            Message::Options(_) => &mut self.options,
+            Message::Status(_) => &mut self.status,
+            Message::Have(_) => &mut self.have,
+            Message::Unhave(_) => &mut self.unhave,
+            Message::Want(_) => &mut self.want,
+            Message::Unwant(_) => &mut self.unwant,
+            Message::Request(_) => &mut self.request,
+            Message::Cancel(_) => &mut self.cancel,
+            Message::Data(_) => &mut self.data,
+            Message::Close(_) => &mut self.close,
+            Message::Extension(_) => &mut self.extension,
+            #[cfg(feature = "v10")]
+            Message::V10(message) => self.v10_counter(message),
+        };
+        counter.add(bytes);
+    }
+
+    /// The counter of the closest legacy message type.
+    #[cfg(feature = "v10")]
+    fn v10_counter(&mut self, message: &v10::Message) -> &mut MessageCounter {
+        match message {
+            v10::Message::Open(_) => &mut self.open,
+            v10::Message::Reject(_) | v10::Message::Close(_) => &mut self.close,
+            v10::Message::Synchronize(_) => &mut self.status,
+            v10::Message::Request(_) => &mut self.request,
+            v10::Message::Cancel(_) => &mut self.cancel,
+            v10::Message::Data(_) | v10::Message::NoData(_) => &mut self.data,
+            v10::Message::Want(_) => &mut self.want,
+            v10::Message::Unwant(_) => &mut self.unwant,
+            v10::Message::Bitfield(_) | v10::Message::Range(_) => &mut self.have,
+            v10::Message::Extension(_) => &mut self.extension,
+        }
+    }
+}
+
+/// The size of the block in a `Data` message, if it has one.
+fn block_len(message: &Message) -> Option<usize> {
+    match message {
+        Message::Data(data) => data.value.as_ref().map(|value| value.len()),
+        #[cfg(feature = "v10")]
+        Message::V10(v10::Message::Data(data)) => {
+            data.block.as_ref().map(|block| block.value.len())
+        }
+        _ => None,
+    }
+}
+
+/// Counters of a [`Channel`](crate::Channel).
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Messages sent on the channel.
+    pub sent: MessageStats,
+    /// Messages received on the channel.
+    pub received: MessageStats,
+    /// Number of blocks sent in `Data` messages.
+    pub blocks_uploaded: u64,
+    /// Number of blocks received in `Data` messages.
+    pub blocks_downloaded: u64,
+    /// Size of the blocks sent in `Data` messages.
+    pub bytes_uploaded: u64,
+    /// Size of the blocks received in `Data` messages.
+    pub bytes_downloaded: u64,
+}
+
+impl ChannelStats {
+    pub(crate) fn record_sent(&mut self, message: &Message) {
+        self.sent.record(message);
+        if let Some(len) = block_len(message) {
+            self.blocks_uploaded += 1;
+            self.bytes_uploaded += len as u64;
+        }
+    }
+
+    pub(crate) fn record_received(&mut self, message: &Message) {
+        self.received.record(message);
+        if let Some(len) = block_len(message) {
+            self.blocks_downloaded += 1;
+            self.bytes_downloaded += len as u64;
+        }
+    }
+}
+
+/// A snapshot of the counters of a [`Protocol`](crate::Protocol).
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolStats {
+    /// Bytes read from the connection, including handshake and frame headers.
+    pub bytes_read: u64,
+    /// Bytes written to the connection, including handshake and frame headers.
+    pub bytes_written: u64,
+    /// Frames read, including handshake messages and keepalives.
+    pub frames_read: u64,
+    /// Frames queued for writing, including handshake messages and keepalives.
+    pub frames_written: u64,
+    /// Messages sent on all channels.
+    pub sent: MessageStats,
+    /// Messages received on all channels.
+    pub received: MessageStats,
+    /// Messages that channels sent but were not yet taken by the protocol.
+    pub queued_messages: usize,
+    /// Frames that wait for space in the write buffers.
+    pub queued_frames: usize,
+    /// Encoded bytes that were not yet written.
+    pub queued_bytes: usize,
+    /// Number of open channels.
+    pub open_channels: usize,
+    /// Round trip time of the last keepalive that the remote answered. Only
+    /// remotes of this implementation that speak version 1 answer keepalives,
+    /// for other remotes this stays `None`.
+    pub rtt: Option<Duration>,
+}
@@ -0,0 +1,187 @@
+use futures_lite::ready;
+use futures_timer::Delay;
+use instant::Instant;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Smallest number of bytes to wait for before reading or writing, to avoid
+/// waking up for every few bytes on slow rates.
+const MIN_GRANT: usize = 1024;
+
+/// A token bucket that limits the number of bytes per second.
+///
+/// Clones share the same bucket, so a limiter can cap a single protocol or
+/// be shared to cap the total rate of many protocols. The bucket holds up to
+/// one second worth of bytes. Reads and writes wait until bytes are
+/// available, so data is delayed but never dropped.
+///
+/// ```
+/// use hypercore_protocol::{ProtocolBuilder, RateLimiter};
+///
+/// // 1 MB/s for all connections, and 256 kB/s for this one.
+/// let global = RateLimiter::new(1_000_000);
+/// let builder = ProtocolBuilder::initiator()
+///     .add_upload_limit(global.clone())
+///     .add_upload_limit(RateLimiter::new(256_000));
+/// // The limit can be changed at any time.
+/// global.set_rate(2_000_000);
+/// ```
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// Create a limiter that allows `bytes_per_second` bytes per second.
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        })))
+    }
+
+    /// Change the rate. This applies to all protocols that use this limiter.
+    pub fn set_rate(&self, bytes_per_second: u64) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill();
+        bucket.rate = bytes_per_second.max(1) as f64;
+        bucket.tokens = bucket.tokens.min(bucket.rate);
+    }
+
+    /// The current rate in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.0.lock().unwrap().rate as u64
+    }
+
+    /// Return the number of bytes up to `wanted` that are available now, or
+    /// how long to wait until enough bytes are available.
+    fn available(&self, wanted: usize) -> std::result::Result<usize, Duration> {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill();
+        let needed = wanted.min(MIN_GRANT).min(bucket.rate as usize).max(1) as f64;
+        if bucket.tokens >= needed {
+            Ok((bucket.tokens as usize).min(wanted))
+        } else {
+            let missing = needed - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / bucket.rate))
+        }
+    }
+
+    /// Take `n` bytes. Other protocols may have taken bytes since
+    /// `available` was called, so the bucket may go into debt.
+    fn take(&self, n: usize) {
+        self.0.lock().unwrap().tokens -= n as f64;
+    }
+
+    /// Return bytes that were taken but not used.
+    fn refund(&self, n: usize) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.tokens = (bucket.tokens + n as f64).min(bucket.rate);
+    }
+}
+
+/// Applies a set of rate limiters to the reads or writes of a protocol.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    limiters: Vec<RateLimiter>,
+    delay: Option<Delay>,
+}
+
+impl Throttle {
+    pub(crate) fn new(limiters: Vec<RateLimiter>) -> Self {
+        Self {
+            limiters,
+            delay: None,
+        }
+    }
+
+    /// Wait until bytes are available from all limiters, and take up to
+    /// `wanted` bytes. Bytes that are not used have to be returned with
+    /// [`Throttle::refund`].
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        if self.limiters.is_empty() || wanted == 0 {
+            return Poll::Ready(wanted);
+        }
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            let mut grant = wanted;
+            let mut wait = Duration::from_secs(0);
+            for limiter in self.limiters.iter() {
+                match limiter.available(wanted) {
+                    Ok(n) => grant = grant.min(n),
+                    Err(duration) => wait = wait.max(duration),
+                }
+            }
+            if wait > Duration::from_secs(0) {
+                self.delay = Some(Delay::new(wait));
+                continue;
+            }
+            for limiter in self.limiters.iter() {
+                limiter.take(grant);
+            }
+            return Poll::Ready(grant);
+        }
+    }
+
+    /// Return bytes that were acquired but not read or written.
+    pub(crate) fn refund(&mut self, n: usize) {
+        if n > 0 {
+            for limiter in self.limiters.iter() {
+                limiter.refund(n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(10_000);
+        // The bucket starts full.
+        assert_eq!(limiter.available(20_000), Ok(10_000));
+        limiter.take(10_000);
+        // An empty bucket has to wait for the minimum grant.
+        let wait = limiter.available(5_000).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(103));
+        // Refunds and rate changes are capped at one second worth of bytes.
+        limiter.refund(50_000);
+        assert_eq!(limiter.available(20_000), Ok(10_000));
+        limiter.set_rate(2_000);
+        assert_eq!(limiter.rate(), 2_000);
+        assert_eq!(limiter.available(20_000), Ok(2_000));
+    }
+}
@@ -0,0 +1,77 @@
+//! Optional instrumentation with the `tracing` crate.
+//!
+//! With the `tracing` feature, each protocol runs in a span with the initiator
+//! flag and the remote public key, and each channel has a child span with its
+//! discovery key. Without the feature, spans are zero-sized and
+//! [`trace_event`] expands to nothing.
+
+/// Emit a `tracing` event at the given level if the `tracing` feature is enabled.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::$level!($($arg)+);
+        }
+    };
+}
+pub(crate) use trace_event;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// Stand-in for `tracing::Span` if the `tracing` feature is disabled.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug)]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Create the span of a protocol. The remote key is recorded after the handshake.
+pub(crate) fn protocol_span(is_initiator: bool) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!(
+        "protocol",
+        initiator = is_initiator,
+        remote_key = tracing::field::Empty
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = is_initiator;
+        Span
+    }
+}
+
+/// Create the span of a channel as a child of the protocol span.
+pub(crate) fn channel_span(parent: &Span, discovery_key: &[u8]) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!(
+        parent: parent,
+        "channel",
+        discovery_key = %crate::util::pretty_hash(discovery_key)
+    );
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (parent, discovery_key);
+        Span
+    }
+}
+
+/// Record the remote public key on a protocol span.
+pub(crate) fn record_remote_key(span: &Span, remote_key: &[u8]) {
+    #[cfg(feature = "tracing")]
+    span.record(
+        "remote_key",
+        tracing::field::display(crate::util::pretty_hash(remote_key)),
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (span, remote_key);
+}
@@ -1,8 +1,8 @@
 use blake2_rfc::blake2b::Blake2b;
 use std::convert::TryInto;
-use std::io::{Error, ErrorKind};
 
 use crate::constants::DISCOVERY_NS_BUF;
+use crate::error::Error;
 use crate::DiscoveryKey;
 
 /// Calculate the discovery key of a key.
@@ -18,9 +18,7 @@ pub fn pretty_hash(key: &[u8]) -> String {
     pretty_hash::fmt(key).unwrap_or_else(|_| "<invalid>".into())
 }
 
-pub fn map_channel_err<T>(err: async_channel::SendError<T>) -> Error {
-    Error::new(
-        ErrorKind::BrokenPipe,
-        format!("Cannot forward on channel: {}", err),
-    )
+/// Map a send on a closed async channel to [`Error::ChannelClosed`].
+pub fn map_channel_err<T>(_err: async_channel::SendError<T>) -> Error {
+    Error::ChannelClosed
 }
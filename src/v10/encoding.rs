@@ -0,0 +1,295 @@
+//! The compact encoding used by the hypercore v10 wire protocol.
+//!
+//! Unsigned integers are encoded in one byte if they are smaller than `0xfd`.
+//! Larger values are prefixed with `0xfd`, `0xfe` or `0xff` and followed by
+//! a 16, 32 or 64 bit little endian integer. Buffers and strings are prefixed
+//! with their length.
+
+use crate::error::{Error, Result};
+
+/// A value that can be encoded with the compact encoding.
+pub trait CompactEncoding: Sized {
+    /// Append the encoded value to `enc`.
+    fn encode(&self, enc: &mut Encoder);
+
+    /// Decode a value from `dec`.
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self>;
+
+    /// Encode the value into a new buffer.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        self.encode(&mut enc);
+        enc.into_vec()
+    }
+
+    /// Decode a value from a buffer. Trailing bytes are an error.
+    fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut dec = Decoder::new(buf);
+        let value = Self::decode(&mut dec)?;
+        dec.finish()?;
+        Ok(value)
+    }
+}
+
+/// Appends compact encoded values to a buffer.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// Create an empty encoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of bytes encoded so far.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Check if nothing was encoded yet.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Get the encoded bytes.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Encode an unsigned integer.
+    pub fn uint(&mut self, n: u64) {
+        if n < 0xfd {
+            self.buf.push(n as u8);
+        } else if n <= 0xffff {
+            self.buf.push(0xfd);
+            self.buf.extend_from_slice(&(n as u16).to_le_bytes());
+        } else if n <= 0xffff_ffff {
+            self.buf.push(0xfe);
+            self.buf.extend_from_slice(&(n as u32).to_le_bytes());
+        } else {
+            self.buf.push(0xff);
+            self.buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+
+    /// Encode a fixed size 24 bit little endian integer.
+    pub fn uint24(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes()[..3]);
+    }
+
+    /// Encode a fixed size 32 bit little endian integer.
+    pub fn uint32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Encode a boolean as a single byte.
+    pub fn bool(&mut self, b: bool) {
+        self.buf.push(b as u8);
+    }
+
+    /// Encode a length prefixed buffer.
+    pub fn buffer(&mut self, buf: &[u8]) {
+        self.uint(buf.len() as u64);
+        self.raw(buf);
+    }
+
+    /// Encode a length prefixed UTF-8 string.
+    pub fn string(&mut self, s: &str) {
+        self.buffer(s.as_bytes());
+    }
+
+    /// Encode 32 bytes without a length prefix.
+    pub fn fixed32(&mut self, buf: &[u8; 32]) {
+        self.raw(buf);
+    }
+
+    /// Encode a length prefixed array of 32 bit integers.
+    pub fn uint32_array(&mut self, values: &[u32]) {
+        self.uint(values.len() as u64);
+        for value in values {
+            self.uint32(*value);
+        }
+    }
+
+    /// Encode a length prefixed array of values.
+    pub fn array<T: CompactEncoding>(&mut self, values: &[T]) {
+        self.uint(values.len() as u64);
+        for value in values {
+            value.encode(self);
+        }
+    }
+
+    /// Append bytes without a length prefix.
+    pub fn raw(&mut self, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+}
+
+/// Reads compact encoded values from a buffer.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder that starts at the beginning of `buf`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    /// The number of bytes that were not decoded yet.
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    /// Check if all bytes were decoded.
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Fail if there are bytes left.
+    pub fn finish(&self) -> Result<()> {
+        match self.remaining() {
+            0 => Ok(()),
+            n => Err(Error::Decode(format!("{} trailing bytes", n).into())),
+        }
+    }
+
+    /// Decode an unsigned integer.
+    pub fn uint(&mut self) -> Result<u64> {
+        let n = match self.take(1)?[0] {
+            0xfd => u64::from(u16::from_le_bytes(self.take_array()?)),
+            0xfe => u64::from(u32::from_le_bytes(self.take_array()?)),
+            0xff => u64::from_le_bytes(self.take_array()?),
+            n => u64::from(n),
+        };
+        Ok(n)
+    }
+
+    /// Decode a fixed size 24 bit little endian integer.
+    pub fn uint24(&mut self) -> Result<u32> {
+        let bytes = self.take(3)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    }
+
+    /// Decode a fixed size 32 bit little endian integer.
+    pub fn uint32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    /// Decode a boolean.
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => Err(Error::Decode(format!("Invalid boolean {}", n).into())),
+        }
+    }
+
+    /// Decode a length prefixed buffer.
+    pub fn buffer(&mut self) -> Result<Vec<u8>> {
+        let len = self.len_prefix(1)?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Decode a length prefixed UTF-8 string.
+    pub fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.buffer()?)
+            .map_err(|_| Error::Decode("String is not valid UTF-8".into()))
+    }
+
+    /// Decode 32 bytes without a length prefix.
+    pub fn fixed32(&mut self) -> Result<[u8; 32]> {
+        self.take_array()
+    }
+
+    /// Decode a length prefixed array of 32 bit integers.
+    pub fn uint32_array(&mut self) -> Result<Vec<u32>> {
+        let len = self.len_prefix(4)?;
+        (0..len).map(|_| self.uint32()).collect()
+    }
+
+    /// Decode a length prefixed array of values.
+    pub fn array<T: CompactEncoding>(&mut self) -> Result<Vec<T>> {
+        let len = self.len_prefix(1)?;
+        (0..len).map(|_| T::decode(self)).collect()
+    }
+
+    /// Take all remaining bytes.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.offset..];
+        self.offset = self.buf.len();
+        rest
+    }
+
+    /// Decode a length prefix and check that the remaining bytes can hold
+    /// that many items of at least `item_size` bytes, so that hostile input
+    /// cannot cause large allocations.
+    fn len_prefix(&mut self, item_size: usize) -> Result<usize> {
+        let len = self.uint()?;
+        if len > (self.remaining() / item_size) as u64 {
+            return Err(Error::Decode("Length prefix exceeds buffer".into()));
+        }
+        Ok(len as usize)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(Error::Decode("Unexpected end of buffer".into()));
+        }
+        let bytes = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uint() {
+        let values = [0, 1, 0xfc, 0xfd, 0xffff, 0x10000, 0xffff_ffff, u64::MAX];
+        let lens = [1, 1, 1, 3, 3, 5, 5, 9];
+        for (value, len) in values.iter().zip(lens.iter()) {
+            let mut enc = Encoder::new();
+            enc.uint(*value);
+            let buf = enc.into_vec();
+            assert_eq!(buf.len(), *len, "length of {}", value);
+            let mut dec = Decoder::new(&buf);
+            assert_eq!(dec.uint().unwrap(), *value);
+            assert!(dec.is_empty());
+        }
+        assert!(Decoder::new(&[0xfe, 1, 2]).uint().is_err());
+    }
+
+    #[test]
+    fn buffers_and_strings() {
+        let mut enc = Encoder::new();
+        enc.string("hypercore");
+        enc.buffer(&[1, 2, 3]);
+        enc.uint24(0x01_02_03);
+        enc.uint32_array(&[1, u32::MAX]);
+        let buf = enc.into_vec();
+        let mut dec = Decoder::new(&buf);
+        assert_eq!(dec.string().unwrap(), "hypercore");
+        assert_eq!(dec.buffer().unwrap(), vec![1, 2, 3]);
+        assert_eq!(dec.uint24().unwrap(), 0x01_02_03);
+        assert_eq!(dec.uint32_array().unwrap(), vec![1, u32::MAX]);
+        dec.finish().unwrap();
+
+        // A length prefix that is larger than the buffer is rejected.
+        assert!(Decoder::new(&[0xfe, 0xff, 0xff, 0xff, 0xff])
+            .buffer()
+            .is_err());
+    }
+}
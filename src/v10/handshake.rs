@@ -0,0 +1,231 @@
+//! The Noise handshake of v10.
+//!
+//! v10 uses the `Noise_XX_Ed25519_ChaChaPoly_BLAKE2b` handshake of
+//! hyperswarm secret-stream: the XX pattern with Ed25519 keys, where the
+//! Diffie-Hellman function is a scalar multiplication on the Edwards curve.
+
+use blake2_rfc::blake2b::blake2b;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+use snow::params::{CipherChoice, DHChoice, HashChoice, NoiseParams};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+use snow::{Builder, Error as SnowError, HandshakeState};
+
+const HANDSHAKE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2b";
+const HANDSHAKE_NAME: &str = "Noise_XX_Ed25519_ChaChaPoly_BLAKE2b";
+const KEY_LEN: usize = 32;
+
+/// Length of the first handshake message of the initiator.
+pub(crate) const FIRST_MESSAGE_LEN: usize = 32;
+
+/// Build the handshake state. The Ed25519 key pair is derived from `seed`.
+pub(crate) fn build_handshake_state(
+    is_initiator: bool,
+    seed: &[u8],
+) -> std::result::Result<HandshakeState, SnowError> {
+    // snow has no Ed25519 support: parse the params of a Curve25519 handshake,
+    // resolve the DH function to Ed25519 and hash the name of the v10 handshake.
+    let mut params: NoiseParams = HANDSHAKE_PATTERN.parse()?;
+    params.name = HANDSHAKE_NAME.to_string();
+    let builder = Builder::with_resolver(params, Box::new(Resolver));
+    let builder = builder.local_private_key(seed);
+    if is_initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+}
+
+/// The Ed25519 public key of `seed`.
+pub(crate) fn public_key(seed: &[u8]) -> Vec<u8> {
+    let mut dh = Ed25519::default();
+    dh.set(seed);
+    dh.public.to_vec()
+}
+
+/// The id of the secret-stream of the initiator or the responder.
+pub(crate) fn stream_id(handshake_hash: &[u8], is_initiator: bool) -> [u8; 32] {
+    let namespace = namespace("hyperswarm/secret-stream", if is_initiator { 0 } else { 1 });
+    hash32(handshake_hash, &namespace)
+}
+
+/// The capability for a feed `key`, which proves that the initiator or the
+/// responder knows the key.
+pub(crate) fn capability(handshake_hash: &[u8], is_initiator: bool, key: &[u8]) -> [u8; 32] {
+    // Replication capabilities of the initiator and responder are the second
+    // and third hypercore namespace.
+    let namespace = namespace("hypercore", if is_initiator { 1 } else { 2 });
+    let mut data = namespace.to_vec();
+    data.extend_from_slice(key);
+    hash32(handshake_hash, &data)
+}
+
+/// A namespaced hash, like `namespace` of hypercore-crypto.
+fn namespace(name: &str, id: u8) -> [u8; 32] {
+    let mut data = hash32(&[], name.as_bytes()).to_vec();
+    data.push(id);
+    hash32(&[], &data)
+}
+
+fn hash32(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(blake2b(32, key, data).as_bytes());
+    hash
+}
+
+/// Resolves the DH function to [`Ed25519`], and everything else with the
+/// default resolver.
+struct Resolver;
+
+impl CryptoResolver for Resolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        match choice {
+            DHChoice::Curve25519 => Some(Box::new(Ed25519::default())),
+            _ => None,
+        }
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+/// Diffie-Hellman on the Edwards curve, like `crypto_scalarmult_ed25519_noclamp`
+/// of libsodium. The private key is an Ed25519 seed, which is expanded to a
+/// scalar like for signing.
+#[derive(Default)]
+struct Ed25519 {
+    seed: [u8; KEY_LEN],
+    scalar: Scalar,
+    public: [u8; KEY_LEN],
+}
+
+impl Dh for Ed25519 {
+    fn name(&self) -> &'static str {
+        "Ed25519"
+    }
+
+    fn pub_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn priv_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn set(&mut self, privkey: &[u8]) {
+        self.seed.copy_from_slice(&privkey[..KEY_LEN]);
+        let secret = SecretKey::from_bytes(&self.seed).unwrap();
+        let expanded = ExpandedSecretKey::from(&secret);
+        let mut scalar = [0u8; KEY_LEN];
+        scalar.copy_from_slice(&expanded.to_bytes()[..KEY_LEN]);
+        self.scalar = Scalar::from_bits(scalar);
+        self.public = PublicKey::from(&expanded).to_bytes();
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        let mut seed = [0u8; KEY_LEN];
+        rng.fill_bytes(&mut seed);
+        self.set(&seed);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.seed
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let point = CompressedEdwardsY::from_slice(&pubkey[..KEY_LEN])
+            .decompress()
+            .ok_or(())?;
+        if point.is_small_order() {
+            return Err(());
+        }
+        let shared = (self.scalar * point).compress();
+        out[..KEY_LEN].copy_from_slice(shared.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with crypto_sign_seed_keypair and
+    // crypto_scalarmult_ed25519_noclamp of libsodium 1.0.18, from the seeds
+    // [1; 32] and [2; 32].
+    const PUBLIC_1: &str = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+    const PUBLIC_2: &str = "8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b8fc9b394";
+    const SHARED: &str = "5612509e82825296cbaaae306ffe1088cbbe200de8ba23dad06f67e607f861df";
+
+    fn dh(seed: u8) -> Ed25519 {
+        let mut dh = Ed25519::default();
+        dh.set(&[seed; 32]);
+        dh
+    }
+
+    #[test]
+    fn ed25519_matches_libsodium() {
+        let (dh1, dh2) = (dh(1), dh(2));
+        assert_eq!(hex::encode(dh1.pubkey()), PUBLIC_1);
+        assert_eq!(hex::encode(dh2.pubkey()), PUBLIC_2);
+        let mut shared = [0u8; 32];
+        dh1.dh(dh2.pubkey(), &mut shared).unwrap();
+        assert_eq!(hex::encode(shared), SHARED);
+        dh2.dh(dh1.pubkey(), &mut shared).unwrap();
+        assert_eq!(hex::encode(shared), SHARED);
+        assert!(dh1.dh(&[0u8; 32], &mut shared).is_err());
+    }
+
+    #[test]
+    fn namespaces() {
+        // The namespaces of hyperswarm secret-stream, from libsodium.
+        assert_eq!(
+            hex::encode(namespace("hyperswarm/secret-stream", 0)),
+            "a931a0155b5c09e6d28628236af83c4b8a6af9af60986edeede9dc5d63192bf7"
+        );
+        assert_eq!(
+            hex::encode(namespace("hyperswarm/secret-stream", 1)),
+            "742c9d833d430af4c48a8705e91631eecf295442bbca18996e597097723b1061"
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        let mut initiator = build_handshake_state(true, &[1u8; 32]).unwrap();
+        let mut responder = build_handshake_state(false, &[2u8; 32]).unwrap();
+        let (mut buf, mut payload) = (vec![0u8; 512], vec![0u8; 512]);
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        assert_eq!(len, FIRST_MESSAGE_LEN);
+        responder.read_message(&buf[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut buf).unwrap();
+        initiator.read_message(&buf[..len], &mut payload).unwrap();
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        responder.read_message(&buf[..len], &mut payload).unwrap();
+        assert_eq!(
+            initiator.get_handshake_hash(),
+            responder.get_handshake_hash()
+        );
+        assert_eq!(
+            responder.get_remote_static().unwrap(),
+            &public_key(&[1u8; 32])[..]
+        );
+        assert_eq!(
+            initiator.get_remote_static().unwrap(),
+            &public_key(&[2u8; 32])[..]
+        );
+    }
+}
@@ -0,0 +1,885 @@
+//! Messages of the hypercore v10 wire protocol.
+//!
+//! Messages are multiplexed over a connection as in protomux: each message
+//! starts with the channel id and the message type. Channel id `0` is used
+//! for control messages that open and close channels.
+
+use super::encoding::{CompactEncoding, Decoder, Encoder};
+use crate::error::{Error, Result};
+
+/// The protocol name that hypercore channels are opened with.
+pub const PROTOCOL_NAME: &str = "hypercore/alpha";
+
+const CONTROL_CHANNEL: u64 = 0;
+
+const TYPE_BATCH: u64 = 0;
+const TYPE_OPEN: u64 = 1;
+const TYPE_REJECT: u64 = 2;
+const TYPE_CLOSE: u64 = 3;
+
+/// A node of a merkle tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The index of the node in the flat tree.
+    pub index: u64,
+    /// The byte size of the blocks below the node.
+    pub size: u64,
+    /// The hash of the node.
+    pub hash: [u8; 32],
+}
+
+/// Handshake data sent with [`Open`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// Whether the peer supports seek requests.
+    pub seeks: bool,
+    /// Proof that the peer knows the key of the feed.
+    pub capability: [u8; 32],
+}
+
+/// Open a channel for a feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Open {
+    /// The protocol of the channel, [`PROTOCOL_NAME`] for hypercore.
+    pub protocol: String,
+    /// The discovery key of the feed.
+    pub discovery_key: Vec<u8>,
+    /// The handshake of the channel.
+    pub handshake: Option<Handshake>,
+}
+
+/// Reject a channel that the remote opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reject;
+
+/// Close a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Close;
+
+/// Tell the remote about the state of the local feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Synchronize {
+    /// The fork id of the local feed.
+    pub fork: u64,
+    /// The length of the local feed.
+    pub length: u64,
+    /// The length of the remote feed that the peer knows of.
+    pub remote_length: u64,
+    /// Whether the peer downloads from the remote.
+    pub downloading: bool,
+    /// Whether the peer uploads to the remote.
+    pub uploading: bool,
+    /// Whether the peer can prove a newer length to the remote.
+    pub can_upgrade: bool,
+}
+
+/// Request a block or a tree hash with its proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestBlock {
+    /// The index of the block or tree node.
+    pub index: u64,
+    /// The number of proof nodes the requester already has.
+    pub nodes: u64,
+}
+
+/// Request the block that contains a byte offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSeek {
+    /// The byte offset.
+    pub bytes: u64,
+}
+
+/// Request a proof for a newer length of the feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestUpgrade {
+    /// The length of the feed that the requester has.
+    pub start: u64,
+    /// The number of blocks to upgrade by.
+    pub length: u64,
+}
+
+/// Request data from the remote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// The id of the request, which the [`Data`] or [`NoData`] answer refers to.
+    pub id: u64,
+    /// The fork id of the requested feed.
+    pub fork: u64,
+    /// Request a block.
+    pub block: Option<RequestBlock>,
+    /// Request a tree hash.
+    pub hash: Option<RequestBlock>,
+    /// Request the block at a byte offset.
+    pub seek: Option<RequestSeek>,
+    /// Request a proof for a newer length.
+    pub upgrade: Option<RequestUpgrade>,
+}
+
+/// Cancel a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancel {
+    /// The id of the request.
+    pub request: u64,
+}
+
+/// A block with its proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataBlock {
+    /// The index of the block.
+    pub index: u64,
+    /// The block.
+    pub value: Vec<u8>,
+    /// The proof nodes.
+    pub nodes: Vec<Node>,
+}
+
+/// A tree hash with its proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataHash {
+    /// The index of the tree node.
+    pub index: u64,
+    /// The proof nodes.
+    pub nodes: Vec<Node>,
+}
+
+/// The proof for a seek request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSeek {
+    /// The byte offset.
+    pub bytes: u64,
+    /// The proof nodes.
+    pub nodes: Vec<Node>,
+}
+
+/// The proof for a newer length of the feed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUpgrade {
+    /// The length that the upgrade starts from.
+    pub start: u64,
+    /// The number of blocks of the upgrade.
+    pub length: u64,
+    /// The proof nodes.
+    pub nodes: Vec<Node>,
+    /// The nodes for the rest of the tree.
+    pub additional_nodes: Vec<Node>,
+    /// The signature of the new tree root.
+    pub signature: Vec<u8>,
+}
+
+/// The response to a [`Request`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Data {
+    /// The id of the [`Request`].
+    pub request: u64,
+    /// The fork id of the feed.
+    pub fork: u64,
+    /// The requested block.
+    pub block: Option<DataBlock>,
+    /// The requested tree hash.
+    pub hash: Option<DataHash>,
+    /// The proof for a seek.
+    pub seek: Option<DataSeek>,
+    /// The proof for an upgrade.
+    pub upgrade: Option<DataUpgrade>,
+}
+
+/// The remote cannot answer a [`Request`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoData {
+    /// The id of the [`Request`].
+    pub request: u64,
+}
+
+/// Ask the remote to announce the blocks it has in a range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Want {
+    /// The first block.
+    pub start: u64,
+    /// The number of blocks.
+    pub length: u64,
+}
+
+/// Revert a [`Want`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unwant {
+    /// The first block.
+    pub start: u64,
+    /// The number of blocks.
+    pub length: u64,
+}
+
+/// Announce blocks with a bitfield. Bit `i` marks block `start + i`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    /// The first block.
+    pub start: u64,
+    /// The bits, 32 blocks per word.
+    pub bitfield: Vec<u32>,
+}
+
+/// Announce that a range of blocks was added or dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    /// Whether the blocks were dropped instead of added.
+    pub drop: bool,
+    /// The first block.
+    pub start: u64,
+    /// The number of blocks.
+    pub length: u64,
+}
+
+/// An extension message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    /// The name of the extension.
+    pub name: String,
+    /// The message.
+    pub message: Vec<u8>,
+}
+
+/// A v10 protocol message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Open a channel.
+    Open(Open),
+    /// Reject a channel.
+    Reject(Reject),
+    /// Close a channel.
+    Close(Close),
+    /// See [`Synchronize`].
+    Synchronize(Synchronize),
+    /// See [`Request`].
+    Request(Request),
+    /// See [`Cancel`].
+    Cancel(Cancel),
+    /// See [`Data`].
+    Data(Data),
+    /// See [`NoData`].
+    NoData(NoData),
+    /// See [`Want`].
+    Want(Want),
+    /// See [`Unwant`].
+    Unwant(Unwant),
+    /// See [`Bitfield`].
+    Bitfield(Bitfield),
+    /// See [`Range`].
+    Range(Range),
+    /// See [`Extension`].
+    Extension(Extension),
+}
+
+impl Message {
+    /// Check if this message is sent on the control channel.
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Open(_) | Self::Reject(_) | Self::Close(_))
+    }
+
+    /// Wire type of this message. Types of control and channel messages
+    /// overlap.
+    pub fn typ(&self) -> u64 {
+        match self {
+            Self::Open(_) => TYPE_OPEN,
+            Self::Reject(_) => TYPE_REJECT,
+            Self::Close(_) => TYPE_CLOSE,
+            Self::Synchronize(_) => 0,
+            Self::Request(_) => 1,
+            Self::Cancel(_) => 2,
+            Self::Data(_) => 3,
+            Self::NoData(_) => 4,
+            Self::Want(_) => 5,
+            Self::Unwant(_) => 6,
+            Self::Bitfield(_) => 7,
+            Self::Range(_) => 8,
+            Self::Extension(_) => 9,
+        }
+    }
+
+    /// Encode the body of the message, without the channel and type.
+    pub(crate) fn body(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        self.encode_body(&mut enc);
+        enc.into_vec()
+    }
+
+    fn encode_body(&self, enc: &mut Encoder) {
+        match self {
+            Self::Open(m) => m.encode(enc),
+            Self::Reject(_) | Self::Close(_) => {}
+            Self::Synchronize(m) => m.encode(enc),
+            Self::Request(m) => m.encode(enc),
+            Self::Cancel(m) => enc.uint(m.request),
+            Self::Data(m) => m.encode(enc),
+            Self::NoData(m) => enc.uint(m.request),
+            Self::Want(m) => {
+                enc.uint(m.start);
+                enc.uint(m.length);
+            }
+            Self::Unwant(m) => {
+                enc.uint(m.start);
+                enc.uint(m.length);
+            }
+            Self::Bitfield(m) => {
+                enc.uint(m.start);
+                enc.uint32_array(&m.bitfield);
+            }
+            Self::Range(m) => m.encode(enc),
+            Self::Extension(m) => {
+                enc.string(&m.name);
+                enc.raw(&m.message);
+            }
+        }
+    }
+
+    fn decode_channel(dec: &mut Decoder<'_>, typ: u64) -> Result<Self> {
+        let message = match typ {
+            0 => Self::Synchronize(Synchronize::decode(dec)?),
+            1 => Self::Request(Request::decode(dec)?),
+            2 => Self::Cancel(Cancel {
+                request: dec.uint()?,
+            }),
+            3 => Self::Data(Data::decode(dec)?),
+            4 => Self::NoData(NoData {
+                request: dec.uint()?,
+            }),
+            5 => Self::Want(Want {
+                start: dec.uint()?,
+                length: dec.uint()?,
+            }),
+            6 => Self::Unwant(Unwant {
+                start: dec.uint()?,
+                length: dec.uint()?,
+            }),
+            7 => Self::Bitfield(Bitfield {
+                start: dec.uint()?,
+                bitfield: dec.uint32_array()?,
+            }),
+            8 => Self::Range(Range::decode(dec)?),
+            9 => Self::Extension(Extension {
+                name: dec.string()?,
+                message: dec.rest().to_vec(),
+            }),
+            _ => {
+                return Err(Error::Decode(
+                    format!("Invalid message type {}", typ).into(),
+                ))
+            }
+        };
+        Ok(message)
+    }
+}
+
+/// A message on a channel.
+///
+/// For control messages, `channel` is the id of the channel that is opened,
+/// rejected or closed. It is encoded in the body of the control message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMessage {
+    /// The id of the channel.
+    pub channel: u64,
+    /// The message.
+    pub message: Message,
+}
+
+impl ChannelMessage {
+    /// Create a new message.
+    pub fn new(channel: u64, message: Message) -> Self {
+        Self { channel, message }
+    }
+
+    /// Consume self and return (channel, Message).
+    pub fn into_split(self) -> (u64, Message) {
+        (self.channel, self.message)
+    }
+
+    /// Encode the message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        self.encode_into(&mut enc, true);
+        enc.into_vec()
+    }
+
+    /// Decode all messages in a frame. A frame contains a single message or
+    /// a batch of messages.
+    pub fn decode_frame(buf: &[u8]) -> Result<Vec<Self>> {
+        let mut dec = Decoder::new(buf);
+        let channel = dec.uint()?;
+        let typ = dec.uint()?;
+        if channel == CONTROL_CHANNEL && typ == TYPE_BATCH {
+            return decode_batch(&mut dec);
+        }
+        let message = Self::decode_with_header(&mut dec, channel, typ)?;
+        dec.finish()?;
+        Ok(vec![message])
+    }
+
+    fn encode_into(&self, enc: &mut Encoder, with_channel: bool) {
+        if self.message.is_control() {
+            if with_channel {
+                enc.uint(CONTROL_CHANNEL);
+            }
+            enc.uint(self.message.typ());
+            enc.uint(self.channel);
+        } else {
+            if with_channel {
+                enc.uint(self.channel);
+            }
+            enc.uint(self.message.typ());
+        }
+        self.message.encode_body(enc);
+    }
+
+    fn decode_with_header(dec: &mut Decoder<'_>, channel: u64, typ: u64) -> Result<Self> {
+        if channel != CONTROL_CHANNEL {
+            let message = Message::decode_channel(dec, typ)?;
+            return Ok(Self { channel, message });
+        }
+        let channel = dec.uint()?;
+        let message = match typ {
+            TYPE_OPEN => Message::Open(Open::decode(dec)?),
+            TYPE_REJECT => Message::Reject(Reject),
+            TYPE_CLOSE => Message::Close(Close),
+            _ => {
+                return Err(Error::Decode(
+                    format!("Invalid control type {}", typ).into(),
+                ))
+            }
+        };
+        Ok(Self { channel, message })
+    }
+}
+
+/// Encode messages into a single batch frame.
+pub fn encode_batch(messages: &[ChannelMessage]) -> Vec<u8> {
+    let mut enc = Encoder::new();
+    enc.uint(CONTROL_CHANNEL);
+    enc.uint(TYPE_BATCH);
+    let mut current = None;
+    for message in messages {
+        let channel = wire_channel(message);
+        match current {
+            None => enc.uint(channel),
+            Some(current) if current != channel => {
+                enc.uint(0);
+                enc.uint(channel);
+            }
+            _ => {}
+        }
+        current = Some(channel);
+        let mut item = Encoder::new();
+        message.encode_into(&mut item, false);
+        enc.buffer(&item.into_vec());
+    }
+    enc.into_vec()
+}
+
+fn decode_batch(dec: &mut Decoder<'_>) -> Result<Vec<ChannelMessage>> {
+    let mut messages = vec![];
+    let mut channel = dec.uint()?;
+    while !dec.is_empty() {
+        let item = dec.buffer()?;
+        if item.is_empty() {
+            channel = dec.uint()?;
+            continue;
+        }
+        let mut item = Decoder::new(&item);
+        let typ = item.uint()?;
+        if channel == CONTROL_CHANNEL && typ == TYPE_BATCH {
+            return Err(Error::Decode("Nested batch".into()));
+        }
+        messages.push(ChannelMessage::decode_with_header(&mut item, channel, typ)?);
+        item.finish()?;
+    }
+    Ok(messages)
+}
+
+fn wire_channel(message: &ChannelMessage) -> u64 {
+    if message.message.is_control() {
+        CONTROL_CHANNEL
+    } else {
+        message.channel
+    }
+}
+
+fn encode_flags(flags: &[bool]) -> u64 {
+    flags
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, flag)| acc | (*flag as u64) << i)
+}
+
+fn flag(flags: u64, i: u64) -> bool {
+    flags & 1 << i != 0
+}
+
+impl CompactEncoding for Node {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(self.index);
+        enc.uint(self.size);
+        enc.fixed32(&self.hash);
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            index: dec.uint()?,
+            size: dec.uint()?,
+            hash: dec.fixed32()?,
+        })
+    }
+}
+
+impl CompactEncoding for Open {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.string(&self.protocol);
+        enc.buffer(&self.discovery_key);
+        if let Some(handshake) = &self.handshake {
+            enc.uint(encode_flags(&[handshake.seeks]));
+            enc.fixed32(&handshake.capability);
+        }
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        let protocol = dec.string()?;
+        let discovery_key = dec.buffer()?;
+        let handshake = if dec.is_empty() {
+            None
+        } else {
+            let flags = dec.uint()?;
+            Some(Handshake {
+                seeks: flag(flags, 0),
+                capability: dec.fixed32()?,
+            })
+        };
+        Ok(Self {
+            protocol,
+            discovery_key,
+            handshake,
+        })
+    }
+}
+
+impl CompactEncoding for Synchronize {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(encode_flags(&[
+            self.can_upgrade,
+            self.uploading,
+            self.downloading,
+        ]));
+        enc.uint(self.fork);
+        enc.uint(self.length);
+        enc.uint(self.remote_length);
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        let flags = dec.uint()?;
+        Ok(Self {
+            can_upgrade: flag(flags, 0),
+            uploading: flag(flags, 1),
+            downloading: flag(flags, 2),
+            fork: dec.uint()?,
+            length: dec.uint()?,
+            remote_length: dec.uint()?,
+        })
+    }
+}
+
+impl CompactEncoding for RequestBlock {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(self.index);
+        enc.uint(self.nodes);
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        Ok(Self {
+            index: dec.uint()?,
+            nodes: dec.uint()?,
+        })
+    }
+}
+
+impl CompactEncoding for Request {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(encode_flags(&[
+            self.block.is_some(),
+            self.hash.is_some(),
+            self.seek.is_some(),
+            self.upgrade.is_some(),
+        ]));
+        enc.uint(self.id);
+        enc.uint(self.fork);
+        if let Some(block) = &self.block {
+            block.encode(enc);
+        }
+        if let Some(hash) = &self.hash {
+            hash.encode(enc);
+        }
+        if let Some(seek) = &self.seek {
+            enc.uint(seek.bytes);
+        }
+        if let Some(upgrade) = &self.upgrade {
+            enc.uint(upgrade.start);
+            enc.uint(upgrade.length);
+        }
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        let flags = dec.uint()?;
+        let id = dec.uint()?;
+        let fork = dec.uint()?;
+        let block = optional(dec, flag(flags, 0), RequestBlock::decode)?;
+        let hash = optional(dec, flag(flags, 1), RequestBlock::decode)?;
+        let seek = optional(dec, flag(flags, 2), |dec| {
+            Ok(RequestSeek { bytes: dec.uint()? })
+        })?;
+        let upgrade = optional(dec, flag(flags, 3), |dec| {
+            Ok(RequestUpgrade {
+                start: dec.uint()?,
+                length: dec.uint()?,
+            })
+        })?;
+        Ok(Self {
+            id,
+            fork,
+            block,
+            hash,
+            seek,
+            upgrade,
+        })
+    }
+}
+
+impl CompactEncoding for Data {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(encode_flags(&[
+            self.block.is_some(),
+            self.hash.is_some(),
+            self.seek.is_some(),
+            self.upgrade.is_some(),
+        ]));
+        enc.uint(self.request);
+        enc.uint(self.fork);
+        if let Some(block) = &self.block {
+            enc.uint(block.index);
+            enc.buffer(&block.value);
+            enc.array(&block.nodes);
+        }
+        if let Some(hash) = &self.hash {
+            enc.uint(hash.index);
+            enc.array(&hash.nodes);
+        }
+        if let Some(seek) = &self.seek {
+            enc.uint(seek.bytes);
+            enc.array(&seek.nodes);
+        }
+        if let Some(upgrade) = &self.upgrade {
+            enc.uint(upgrade.start);
+            enc.uint(upgrade.length);
+            enc.array(&upgrade.nodes);
+            enc.array(&upgrade.additional_nodes);
+            enc.buffer(&upgrade.signature);
+        }
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        let flags = dec.uint()?;
+        let request = dec.uint()?;
+        let fork = dec.uint()?;
+        let block = optional(dec, flag(flags, 0), |dec| {
+            Ok(DataBlock {
+                index: dec.uint()?,
+                value: dec.buffer()?,
+                nodes: dec.array()?,
+            })
+        })?;
+        let hash = optional(dec, flag(flags, 1), |dec| {
+            Ok(DataHash {
+                index: dec.uint()?,
+                nodes: dec.array()?,
+            })
+        })?;
+        let seek = optional(dec, flag(flags, 2), |dec| {
+            Ok(DataSeek {
+                bytes: dec.uint()?,
+                nodes: dec.array()?,
+            })
+        })?;
+        let upgrade = optional(dec, flag(flags, 3), |dec| {
+            Ok(DataUpgrade {
+                start: dec.uint()?,
+                length: dec.uint()?,
+                nodes: dec.array()?,
+                additional_nodes: dec.array()?,
+                signature: dec.buffer()?,
+            })
+        })?;
+        Ok(Self {
+            request,
+            fork,
+            block,
+            hash,
+            seek,
+            upgrade,
+        })
+    }
+}
+
+impl CompactEncoding for Range {
+    fn encode(&self, enc: &mut Encoder) {
+        enc.uint(encode_flags(&[self.drop, self.length == 1]));
+        enc.uint(self.start);
+        if self.length != 1 {
+            enc.uint(self.length);
+        }
+    }
+
+    fn decode(dec: &mut Decoder<'_>) -> Result<Self> {
+        let flags = dec.uint()?;
+        let start = dec.uint()?;
+        let length = if flag(flags, 1) { 1 } else { dec.uint()? };
+        Ok(Self {
+            drop: flag(flags, 0),
+            start,
+            length,
+        })
+    }
+}
+
+fn optional<T, F>(dec: &mut Decoder<'_>, present: bool, decode: F) -> Result<Option<T>>
+where
+    F: FnOnce(&mut Decoder<'_>) -> Result<T>,
+{
+    if present {
+        decode(dec).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(index: u64) -> Node {
+        Node {
+            index,
+            size: index * 10,
+            hash: [index as u8; 32],
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let messages = vec![
+            Message::Open(Open {
+                protocol: PROTOCOL_NAME.into(),
+                discovery_key: vec![1u8; 32],
+                handshake: Some(Handshake {
+                    seeks: true,
+                    capability: [2u8; 32],
+                }),
+            }),
+            Message::Reject(Reject),
+            Message::Close(Close),
+            Message::Synchronize(Synchronize {
+                fork: 1,
+                length: 300,
+                remote_length: 20,
+                downloading: true,
+                uploading: false,
+                can_upgrade: true,
+            }),
+            Message::Request(Request {
+                id: 1,
+                fork: 0,
+                block: Some(RequestBlock { index: 5, nodes: 2 }),
+                hash: None,
+                seek: Some(RequestSeek { bytes: 1000 }),
+                upgrade: Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            }),
+            Message::Cancel(Cancel { request: 1 }),
+            Message::Data(Data {
+                request: 1,
+                fork: 0,
+                block: Some(DataBlock {
+                    index: 5,
+                    value: vec![7u8; 100],
+                    nodes: vec![node(1), node(2)],
+                }),
+                hash: Some(DataHash {
+                    index: 3,
+                    nodes: vec![],
+                }),
+                seek: None,
+                upgrade: Some(DataUpgrade {
+                    start: 0,
+                    length: 10,
+                    nodes: vec![node(3)],
+                    additional_nodes: vec![node(4)],
+                    signature: vec![9u8; 64],
+                }),
+            }),
+            Message::NoData(NoData { request: 2 }),
+            Message::Want(Want {
+                start: 0,
+                length: 1024,
+            }),
+            Message::Unwant(Unwant {
+                start: 0,
+                length: 1024,
+            }),
+            Message::Bitfield(Bitfield {
+                start: 0,
+                bitfield: vec![0xffff_ffff, 1],
+            }),
+            Message::Range(Range {
+                drop: false,
+                start: 10,
+                length: 1,
+            }),
+            Message::Range(Range {
+                drop: true,
+                start: 10,
+                length: 5,
+            }),
+            Message::Extension(Extension {
+                name: "ext".into(),
+                message: vec![1, 2, 3],
+            }),
+        ];
+        for message in messages.iter() {
+            let channel_message = ChannelMessage::new(3, message.clone());
+            let buf = channel_message.encode();
+            let decoded = ChannelMessage::decode_frame(&buf).unwrap();
+            assert_eq!(decoded, vec![channel_message]);
+        }
+
+        let batch: Vec<_> = messages
+            .into_iter()
+            .enumerate()
+            .map(|(i, message)| ChannelMessage::new(1 + i as u64 % 2, message))
+            .collect();
+        let buf = encode_batch(&batch);
+        assert_eq!(ChannelMessage::decode_frame(&buf).unwrap(), batch);
+    }
+
+    #[test]
+    fn wire_format() {
+        let message = ChannelMessage::new(
+            1,
+            Message::Range(Range {
+                drop: false,
+                start: 300,
+                length: 1,
+            }),
+        );
+        assert_eq!(message.encode(), vec![1, 8, 2, 0xfd, 0x2c, 0x01]);
+
+        let message = ChannelMessage::new(2, Message::Close(Close));
+        assert_eq!(message.encode(), vec![0, 3, 2]);
+
+        assert!(ChannelMessage::decode_frame(&[1, 42]).is_err());
+        assert!(ChannelMessage::decode_frame(&[1, 5, 0]).is_err());
+    }
+}
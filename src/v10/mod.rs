@@ -0,0 +1,141 @@
+//! The hypercore v10 wire protocol (requires the `v10` feature).
+//!
+//! **Experimental.** The v10 support is incomplete and has not been tested
+//! against the JavaScript implementation, so it may change in any release.
+//! The tests check the cryptographic primitives and a transcript against
+//! vectors of libsodium, but not against a recorded JavaScript session.
+//!
+//! Hypercore v10 replaces the protobuf schema with the compact encoding from
+//! [`encoding`], multiplexes channels as in protomux and adds fork ids and
+//! `Synchronize`, `Range`, `Bitfield` and `Upgrade` messages. The message types in
+//! [`message`] mirror the shape of the legacy [`Message`](crate::Message) and
+//! [`ChannelMessage`] types.
+//!
+//! On the connection, each message is sent in a frame with a 24 bit little
+//! endian length prefix, see [`encode_frame`] and [`frame_len`]. The frames
+//! are encrypted with the secret-stream transport of hyperswarm: a Noise
+//! handshake with Ed25519 keys, followed by a libsodium XChaCha20-Poly1305
+//! secretstream in each direction.
+//!
+//! With the `v10-unstable` feature, a [`Protocol`](crate::Protocol) speaks
+//! v10 if [`Version::V10`] is enabled with
+//! [`ProtocolBuilder::set_versions`]. Without it, the protocol fails with
+//! [`Error::InvalidOptions`]. Channels of a v10 connection send and receive
+//! the messages of this module as [`Message::V10`]; opening, closing and
+//! extensions work as with [`Version::V1`]. Compared to [`Version::V1`], v10
+//! connections have some limitations:
+//!
+//! * Extensions on the main protocol stream are not supported, and channel
+//!   extensions are never reported as active by the remote.
+//! * [`Channel::get`] and the [`replication`](crate::replication) module
+//!   only work with [`Version::V1`].
+//!
+//! [`Version::V10`]: crate::Version::V10
+//! [`Error::InvalidOptions`]: crate::Error::InvalidOptions
+//! [`Version::V1`]: crate::Version::V1
+//! [`ProtocolBuilder::set_versions`]: crate::ProtocolBuilder::set_versions
+//! [`Message::V10`]: crate::Message::V10
+//! [`Channel::get`]: crate::Channel::get
+
+use crate::error::{Error, Result};
+use crate::message::Framing;
+
+pub mod encoding;
+pub(crate) mod handshake;
+pub mod message;
+pub(crate) mod secretstream;
+
+pub use encoding::CompactEncoding;
+pub use message::{ChannelMessage, Message};
+
+/// Length of the length prefix of a frame.
+pub const FRAME_HEADER_LEN: usize = 3;
+
+/// Maximum size of the body of a frame.
+pub const MAX_FRAME_SIZE: usize = 0xff_ffff;
+
+/// Prefix `body` with its length.
+pub fn encode_frame(body: &[u8]) -> Result<Vec<u8>> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(Error::OversizeFrame(body.len()));
+    }
+    let mut enc = encoding::Encoder::new();
+    enc.uint24(body.len() as u32);
+    enc.raw(body);
+    Ok(enc.into_vec())
+}
+
+/// Get the length of the frame at the start of `buf`, including the length
+/// prefix. Returns `None` if `buf` is shorter than the length prefix.
+pub fn frame_len(buf: &[u8]) -> Option<usize> {
+    let mut dec = encoding::Decoder::new(buf.get(..FRAME_HEADER_LEN)?);
+    let body_len = dec.uint24().ok()? as usize;
+    Some(FRAME_HEADER_LEN + body_len)
+}
+
+/// Recognize the framing of a remote from the first bytes it sent. The first
+/// frame of a v10 initiator is its 32 byte handshake message, while the first
+/// frame of a [`Version::V1`](crate::Version::V1) initiator is longer because
+/// its handshake payload contains a nonce. Returns `None` if `buf` is too
+/// short to tell.
+pub(crate) fn detect_framing(buf: &[u8]) -> Option<Framing> {
+    let first_frame = [handshake::FIRST_MESSAGE_LEN as u8, 0, 0];
+    let prefix = buf.get(..FRAME_HEADER_LEN)?;
+    if prefix == first_frame {
+        Some(Framing::Uint24)
+    } else {
+        Some(Framing::Varint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let frame = encode_frame(&[1u8; 300]).unwrap();
+        assert_eq!(&frame[..3], &[0x2c, 0x01, 0x00]);
+        assert_eq!(frame_len(&frame), Some(303));
+        assert_eq!(frame_len(&frame[..2]), None);
+        assert!(encode_frame(&vec![0u8; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(detect_framing(&[32, 0]), None);
+        assert_eq!(detect_framing(&[32, 0, 0, 1]), Some(Framing::Uint24));
+        assert_eq!(detect_framing(&[58, 0, 0, 1]), Some(Framing::Varint));
+    }
+
+    #[test]
+    fn detect_v1_handshakes() {
+        use crate::message::{Encoder, Frame};
+        use crate::noise::{Encryption, Handshake};
+        use crate::Version;
+
+        // The first message of a V1 initiator is its 32 byte ephemeral key
+        // followed by a payload with a 24 byte nonce, so its varint length
+        // prefix can never be the 32 of a v10 handshake message.
+        let encryptions = [
+            None,
+            Some(Encryption::Stream),
+            Some(Encryption::Authenticated),
+        ];
+        let versions: [&[Version]; 3] = [&[], &[Version::V1], &[Version::V1, Version::V10]];
+        for encryption in encryptions.iter() {
+            for versions in versions.iter() {
+                for _ in 0..16 {
+                    let mut handshake =
+                        Handshake::new(true, None, Version::V1, versions, *encryption).unwrap();
+                    let body = handshake.start().unwrap().unwrap().to_vec();
+                    assert!(body.len() > handshake::FIRST_MESSAGE_LEN);
+                    let frame = Frame::Raw(body.into());
+                    let mut buf = vec![0u8; frame.encoded_len()];
+                    frame.encode(&mut buf).unwrap();
+                    assert_eq!(detect_framing(&buf), Some(Framing::Varint));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::error::{Error, Result};
+use crate::message::{Framing, Message};
+
+/// A wire protocol version.
+///
+/// The version decides how the handshake, the framing and the messages are
+/// encoded on the connection. The transport encryption of [`Version::V1`]
+/// is set independently with
+/// [`ProtocolBuilder::set_encryption`](crate::ProtocolBuilder::set_encryption).
+///
+/// The initiator speaks the highest version it accepts. The responder
+/// recognizes the version from the first handshake message and fails with
+/// [`Error::VersionMismatch`] if it does not accept it. Peers that speak
+/// [`Version::V1`] advertise their versions in the payload of the Noise
+/// handshake and use the highest version that both support. A peer that
+/// does not advertise any versions (like other hypercore-protocol
+/// implementations) is assumed to speak [`Version::V1`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    /// The protocol of hypercore-protocol 7: a Noise handshake with
+    /// Curve25519 keys, varint length prefixed frames and protobuf messages.
+    V1,
+    /// The protocol of hypercore 10: a Noise handshake with Ed25519 keys,
+    /// secret-stream encryption and the messages of the [`v10`](crate::v10)
+    /// module. Protocols only speak it with the `v10-unstable` feature, see
+    /// the [`v10`](crate::v10) module.
+    #[cfg(feature = "v10")]
+    #[cfg_attr(not(feature = "v10-unstable"), doc(hidden))]
+    V10,
+}
+
+impl Version {
+    pub(crate) fn as_u64(&self) -> u64 {
+        match self {
+            Self::V1 => 1,
+            #[cfg(feature = "v10")]
+            Self::V10 => 10,
+        }
+    }
+
+    fn from_u64(version: u64) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            _ => None,
+        }
+    }
+
+    /// The framing of the connection.
+    pub(crate) fn framing(&self) -> Framing {
+        match self {
+            Self::V1 => Framing::Varint,
+            #[cfg(feature = "v10")]
+            Self::V10 => Framing::Uint24,
+        }
+    }
+
+    /// Check if channels can send `message` on a connection of this version.
+    pub(crate) fn supports(&self, message: &Message) -> bool {
+        match (self, message) {
+            #[cfg(feature = "v10")]
+            (Self::V1, Message::V10(_)) => false,
+            (Self::V1, _) => true,
+            #[cfg(feature = "v10")]
+            (Self::V10, Message::Close(_)) => true,
+            #[cfg(feature = "v10")]
+            (Self::V10, Message::V10(message)) => !message.is_control(),
+            #[cfg(feature = "v10")]
+            (Self::V10, _) => false,
+        }
+    }
+}
+
+/// Pick the highest version that both peers support. Unknown remote versions
+/// are ignored.
+pub(crate) fn negotiate(local: &[Version], remote: &[u64]) -> Result<Version> {
+    let remote: Vec<Version> = if remote.is_empty() {
+        vec![Version::V1]
+    } else {
+        remote
+            .iter()
+            .filter_map(|v| Version::from_u64(*v))
+            .collect()
+    };
+    local
+        .iter()
+        .filter(|v| remote.contains(v))
+        .max()
+        .copied()
+        .ok_or(Error::VersionMismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_versions() {
+        assert_eq!(negotiate(&[Version::V1], &[]).unwrap(), Version::V1);
+        assert_eq!(negotiate(&[Version::V1], &[1, 7]).unwrap(), Version::V1);
+        assert!(matches!(
+            negotiate(&[Version::V1], &[7]),
+            Err(Error::VersionMismatch)
+        ));
+        assert!(matches!(negotiate(&[], &[1]), Err(Error::VersionMismatch)));
+    }
+}
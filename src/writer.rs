@@ -1,13 +1,34 @@
-use crate::message::{EncodeError, Encoder, Frame};
-use crate::noise::{Cipher, HandshakeResult};
+use crate::capture::{Capture, Direction, FrameKind};
+#[cfg(feature = "v10")]
+use crate::constants::MAX_MESSAGE_SIZE;
+#[cfg(feature = "v10")]
+use crate::error::Error;
+use crate::error::Result;
+use crate::message::{EncodeError, Encoder, Frame, Framing};
+use crate::noise::{Cipher, Encryption, FrameCipher, HandshakeResult, TAG_LEN};
+use crate::throttle::Throttle;
+#[cfg(feature = "v10")]
+use crate::v10::{
+    self,
+    secretstream::{StreamTx, ABYTES},
+};
 use futures_lite::{ready, AsyncWrite};
+use futures_timer::Delay;
 use std::collections::VecDeque;
 use std::fmt;
-use std::io::Result;
+use std::future::Future;
+use std::io::{self, IoSlice};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+/// Size of a single write buffer. Frames that are larger get a buffer of
+/// their own.
 const BUF_SIZE: usize = 1024 * 64;
+/// Number of buffers that are passed to a single vectored write.
+const MAX_BUFS: usize = 4;
+/// Number of frames that may wait for buffer space.
+const MAX_QUEUED_FRAMES: usize = 64;
 
 #[derive(Debug)]
 pub enum Step {
@@ -18,11 +39,31 @@ pub enum Step {
 
 pub struct WriteState {
     queue: VecDeque<Frame>,
-    buf: Vec<u8>,
-    current_frame: Option<Frame>,
+    /// Encoded frames that wait to be written, in order. Frames are only
+    /// appended to the last buffer.
+    bufs: VecDeque<Vec<u8>>,
+    /// Written buffers that are kept to be reused.
+    spare: Vec<Vec<u8>>,
+    /// Offset of the not-yet-written bytes in the first buffer.
     start: usize,
-    end: usize,
+    /// Whether bytes were written since the last flush.
+    needs_flush: bool,
+    /// Maximum time that frames are held back to be coalesced with later frames.
+    cork: Option<Duration>,
+    cork_timer: Option<Delay>,
+    /// Limits the rate at which bytes are written.
+    throttle: Throttle,
+    /// Number of bytes written to the connection.
+    bytes_written: u64,
+    /// Number of frames encoded for writing, including keepalives.
+    frames_written: u64,
+    /// Records the frames before they are encrypted.
+    capture: Option<Capture>,
     cipher: Option<Cipher>,
+    frame_cipher: Option<FrameCipher>,
+    #[cfg(feature = "v10")]
+    secret_stream: Option<StreamTx>,
+    framing: Framing,
     step: Step,
 }
 
@@ -31,24 +72,35 @@ impl fmt::Debug for WriteState {
         f.debug_struct("WriteState")
             .field("queue (len)", &self.queue.len())
             .field("step", &self.step)
-            .field("buf (len)", &self.buf.len())
-            .field("current_frame", &self.current_frame)
-            .field("start", &self.start)
-            .field("end", &self.end)
+            .field("bufs (len)", &self.bufs.len())
+            .field("pending", &self.pending())
+            .field("cork", &self.cork)
             .field("cipher", &self.cipher.is_some())
+            .field("frame_cipher", &self.frame_cipher.is_some())
+            .field("framing", &self.framing)
             .finish()
     }
 }
 
 impl WriteState {
-    pub fn new() -> Self {
+    pub fn new(throttle: Throttle) -> Self {
         Self {
             queue: VecDeque::new(),
-            buf: vec![0u8; BUF_SIZE],
-            current_frame: None,
+            bufs: VecDeque::new(),
+            spare: vec![],
             start: 0,
-            end: 0,
+            needs_flush: false,
+            cork: None,
+            cork_timer: None,
+            throttle,
+            bytes_written: 0,
+            frames_written: 0,
+            capture: None,
             cipher: None,
+            frame_cipher: None,
+            #[cfg(feature = "v10")]
+            secret_stream: None,
+            framing: Framing::Varint,
             step: Step::Processing,
         }
     }
@@ -60,56 +112,243 @@ impl WriteState {
         self.queue.push_back(frame.into())
     }
 
-    pub fn try_queue_direct<T: Encoder>(
-        &mut self,
-        frame: &T,
-    ) -> std::result::Result<bool, EncodeError> {
+    /// Number of frames that wait for space in the buffers.
+    pub fn queued_frames(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Number of encoded bytes that were not yet written.
+    pub fn queued_bytes(&self) -> usize {
+        self.pending()
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.frames_written
+    }
+
+    /// Whether there is room to queue more frames.
+    pub fn can_queue_frame(&self) -> bool {
+        self.queue.len() < MAX_QUEUED_FRAMES
+    }
+
+    /// Hold back small writes for at most `cork` to coalesce them with later
+    /// frames. `None` writes frames as soon as the queue is drained.
+    pub fn set_cork(&mut self, cork: Option<Duration>) {
+        self.cork = cork;
+        if cork.is_none() {
+            self.cork_timer = None;
+        }
+    }
+
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    pub fn try_queue_direct(&mut self, frame: &Frame) -> std::result::Result<bool, EncodeError> {
+        #[cfg(feature = "v10")]
+        if self.framing == Framing::Uint24 {
+            return self.try_queue_uint24(frame);
+        }
+        if self.frame_cipher.is_some() {
+            return self.try_queue_encrypted(frame);
+        }
         let len = frame.encoded_len();
-        if self.buf.len() < len {
-            self.buf.resize(len, 0u8);
+        if !self.reserve(len) {
+            return Ok(false);
         }
-        if len > self.remaining() {
+        let buf = self.bufs.back_mut().unwrap();
+        let start = buf.len();
+        buf.resize(start + len, 0u8);
+        let len = frame.encode(&mut buf[start..])?;
+        buf.truncate(start + len);
+        if let Some(capture) = self.capture.as_ref() {
+            let mut body_len = 0u64;
+            let header_len = varinteger::decode(&buf[start..], &mut body_len);
+            capture.record(
+                Direction::Outbound,
+                frame_kind(frame),
+                &buf[start + header_len..],
+            );
+        }
+        if let Some(ref mut cipher) = self.cipher {
+            cipher.apply(&mut buf[start..]);
+        }
+        self.frames_written += 1;
+        Ok(true)
+    }
+
+    /// Encode a frame and encrypt its body with the frame cipher. The length
+    /// prefix includes the authentication tag.
+    fn try_queue_encrypted(&mut self, frame: &Frame) -> std::result::Result<bool, EncodeError> {
+        let mut plain = vec![0u8; frame.encoded_len()];
+        frame.encode(&mut plain)?;
+        let mut body_len = 0u64;
+        let header_len = varinteger::decode(&plain, &mut body_len);
+        let body = &plain[header_len..];
+
+        let encrypted_len = body.len() + TAG_LEN;
+        let len = varinteger::length(encrypted_len as u64) + encrypted_len;
+        if !self.reserve(len) {
             return Ok(false);
         }
-        let len = frame.encode(&mut self.buf[self.end..])?;
-        self.advance(len);
+        let buf = self.bufs.back_mut().unwrap();
+        let start = buf.len();
+        buf.resize(start + len, 0u8);
+        let header_len = varinteger::encode(encrypted_len as u64, &mut buf[start..]);
+        let frame_cipher = self.frame_cipher.as_mut().unwrap();
+        frame_cipher.encrypt(body, &mut buf[start + header_len..]);
+        if let Some(capture) = self.capture.as_ref() {
+            capture.record(Direction::Outbound, frame_kind(frame), body);
+        }
+        self.frames_written += 1;
         Ok(true)
     }
 
-    pub fn can_park_frame(&self) -> bool {
-        self.current_frame.is_none()
+    /// Encode a frame with a 24 bit length prefix, and encrypt its body with
+    /// the secret-stream once it is set up. Empty keepalive frames are not
+    /// encrypted.
+    #[cfg(feature = "v10")]
+    fn try_queue_uint24(&mut self, frame: &Frame) -> std::result::Result<bool, EncodeError> {
+        let body = frame.encode_body()?;
+        let encrypt = self.secret_stream.is_some() && !body.is_empty();
+        let body_len = if encrypt {
+            body.len() + ABYTES
+        } else {
+            body.len()
+        };
+        if body_len > MAX_MESSAGE_SIZE as usize + ABYTES {
+            return Err(EncodeError::new(body_len));
+        }
+        let len = v10::FRAME_HEADER_LEN + body_len;
+        if !self.reserve(len) {
+            return Ok(false);
+        }
+        let buf = self.bufs.back_mut().unwrap();
+        let start = buf.len();
+        buf.resize(start + len, 0u8);
+        let (header, out) = buf[start..].split_at_mut(v10::FRAME_HEADER_LEN);
+        header.copy_from_slice(&(body_len as u32).to_le_bytes()[..v10::FRAME_HEADER_LEN]);
+        match self.secret_stream.as_mut() {
+            Some(secret_stream) if encrypt => secret_stream.encrypt(&body, out),
+            _ => out.copy_from_slice(&body),
+        }
+        if let Some(capture) = self.capture.as_ref() {
+            capture.record(Direction::Outbound, frame_kind(frame), &body);
+        }
+        self.frames_written += 1;
+        Ok(true)
     }
 
-    pub fn park_frame<F>(&mut self, frame: F)
-    where
-        F: Into<Frame>,
-    {
-        if self.current_frame.is_none() {
-            self.current_frame = Some(frame.into())
+    /// Make sure the last buffer has room for `len` bytes. Returns false if
+    /// all buffers are full.
+    fn reserve(&mut self, len: usize) -> bool {
+        if let Some(buf) = self.bufs.back() {
+            if buf.is_empty() || buf.len() + len <= BUF_SIZE {
+                return true;
+            }
         }
+        if self.bufs.len() >= MAX_BUFS {
+            return false;
+        }
+        let buf = self
+            .spare
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUF_SIZE));
+        self.bufs.push_back(buf);
+        true
     }
 
-    fn advance(&mut self, n: usize) {
-        let end = self.end + n;
-        if let Some(ref mut cipher) = self.cipher {
-            cipher.apply(&mut self.buf[self.end..end]);
+    /// Mark `n` bytes as written and recycle buffers that were written completely.
+    fn consume(&mut self, mut n: usize) {
+        while n > 0 {
+            let buf = self.bufs.front().unwrap();
+            let remaining = buf.len() - self.start;
+            if n < remaining {
+                self.start += n;
+                return;
+            }
+            n -= remaining;
+            self.start = 0;
+            let mut buf = self.bufs.pop_front().unwrap();
+            // Don't keep buffers around that were grown for large frames.
+            if buf.capacity() <= BUF_SIZE && self.spare.len() < MAX_BUFS {
+                buf.clear();
+                self.spare.push(buf);
+            }
         }
-        self.end = end;
     }
 
-    pub fn upgrade_with_handshake(&mut self, handshake: &HandshakeResult) -> Result<()> {
-        let cipher = Cipher::from_handshake_tx(handshake)?;
-        self.cipher = Some(cipher);
+    pub fn upgrade_with_handshake(
+        &mut self,
+        handshake: &HandshakeResult,
+        encryption: Encryption,
+    ) -> Result<()> {
+        match encryption {
+            Encryption::Stream => self.cipher = Some(Cipher::from_handshake_tx(handshake)?),
+            Encryption::Authenticated => {
+                self.frame_cipher = Some(FrameCipher::from_handshake_tx(handshake))
+            }
+        }
         Ok(())
     }
-    fn remaining(&self) -> usize {
-        self.buf.len() - self.end
+
+    /// Encrypt all following frames with the secret-stream of v10. The
+    /// header of the secret-stream is queued as the next frame.
+    #[cfg(feature = "v10")]
+    pub fn upgrade_with_secret_stream(&mut self, handshake: &HandshakeResult) -> Result<()> {
+        let (secret_stream, header) = StreamTx::new(handshake);
+        if !self.try_queue_direct(&Frame::Raw(header.into()))? {
+            return Err(Error::Handshake(
+                "No buffer space for the secret-stream header".into(),
+            ));
+        }
+        self.secret_stream = Some(secret_stream);
+        Ok(())
     }
 
     fn pending(&self) -> usize {
-        self.end - self.start
+        self.bufs.iter().map(|buf| buf.len()).sum::<usize>() - self.start
+    }
+
+    /// Encode as many queued frames as fit into the buffers.
+    fn process(&mut self) -> std::result::Result<(), EncodeError> {
+        while let Some(frame) = self.queue.pop_front() {
+            if !self.try_queue_direct(&frame)? {
+                self.queue.push_front(frame);
+                break;
+            }
+        }
+        Ok(())
     }
 
+    /// Whether a write should wait for the cork timer to coalesce more frames.
+    fn poll_cork(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.cork {
+            Some(cork) if self.pending() < BUF_SIZE => {
+                let timer = self.cork_timer.get_or_insert_with(|| Delay::new(cork));
+                ready!(Pin::new(timer).poll(cx));
+                self.cork_timer = None;
+                Poll::Ready(())
+            }
+            _ => {
+                self.cork_timer = None;
+                Poll::Ready(())
+            }
+        }
+    }
+
+    /// Write all queued frames and flush the writer once the queue is drained.
+    ///
+    /// Frames are coalesced into as few writes as possible, and the buffers
+    /// are passed to the writer in a single vectored write.
     pub fn poll_send<W>(&mut self, cx: &mut Context<'_>, mut writer: &mut W) -> Poll<Result<()>>
     where
         W: AsyncWrite + Unpin,
@@ -117,36 +356,65 @@ impl WriteState {
         loop {
             self.step = match self.step {
                 Step::Processing => {
-                    if self.current_frame.is_none() && !self.queue.is_empty() {
-                        self.current_frame = self.queue.pop_front();
-                    }
-
-                    if let Some(frame) = self.current_frame.take() {
-                        if !self.try_queue_direct(&frame)? {
-                            self.current_frame = Some(frame);
-                        }
-                    }
+                    self.process()?;
                     if self.pending() == 0 {
-                        return Poll::Ready(Ok(()));
+                        if !self.needs_flush {
+                            return Poll::Ready(Ok(()));
+                        }
+                        Step::Flushing
+                    } else {
+                        ready!(self.poll_cork(cx));
+                        Step::Writing
                     }
-                    Step::Writing
                 }
                 Step::Writing => {
-                    let n = ready!(
-                        Pin::new(&mut writer).poll_write(cx, &self.buf[self.start..self.end])
-                    )?;
-                    self.start += n;
-                    if self.start == self.end {
-                        self.start = 0;
-                        self.end = 0;
+                    let len = ready!(self.throttle.poll_acquire(cx, self.pending()));
+                    let mut slices = [IoSlice::new(&[]); MAX_BUFS];
+                    let mut count = 0;
+                    let mut remaining = len;
+                    for (i, buf) in self.bufs.iter().enumerate() {
+                        let start = if i == 0 { self.start } else { 0 };
+                        let end = buf.len().min(start + remaining);
+                        if end > start {
+                            slices[count] = IoSlice::new(&buf[start..end]);
+                            count += 1;
+                            remaining -= end - start;
+                        }
+                    }
+                    let result = Pin::new(&mut writer).poll_write_vectored(cx, &slices[..count]);
+                    let written = match &result {
+                        Poll::Ready(Ok(n)) => *n,
+                        _ => 0,
+                    };
+                    self.throttle.refund(len - written);
+                    let n = ready!(result)?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+                    }
+                    self.consume(n);
+                    self.bytes_written += n as u64;
+                    self.needs_flush = true;
+                    if self.pending() == 0 {
+                        Step::Processing
+                    } else {
+                        Step::Writing
                     }
-                    Step::Flushing
                 }
                 Step::Flushing => {
                     ready!(Pin::new(&mut writer).poll_flush(cx))?;
+                    self.needs_flush = false;
                     Step::Processing
                 }
             }
         }
     }
 }
+
+fn frame_kind(frame: &Frame) -> FrameKind {
+    match frame {
+        Frame::Raw(_) => FrameKind::Handshake,
+        Frame::Message(_) | Frame::Ping(_) | Frame::Pong(_) => FrameKind::Message,
+        #[cfg(feature = "v10")]
+        Frame::V10(_) => FrameKind::Message,
+    }
+}
@@ -2,8 +2,10 @@ use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task::{self, JoinHandle};
 use futures_lite::io::{AsyncRead, AsyncWrite};
+use hypercore_protocol::schema::Want;
 use hypercore_protocol::{Channel, DiscoveryKey, Duplex, Event, Protocol, ProtocolBuilder};
 use std::io;
+use std::time::Duration;
 
 pub type MemoryProtocol = Protocol<Duplex<sluice::pipe::PipeReader, sluice::pipe::PipeWriter>>;
 pub async fn create_pair_memory() -> io::Result<(MemoryProtocol, MemoryProtocol)> {
@@ -27,7 +29,7 @@ pub async fn create_pair_tcp() -> io::Result<(TcpProtocol, TcpProtocol)> {
 
 pub fn next_event<IO>(
     mut proto: Protocol<IO>,
-) -> impl Future<Output = (Protocol<IO>, io::Result<Event>)>
+) -> impl Future<Output = (Protocol<IO>, hypercore_protocol::Result<Event>)>
 where
     IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
@@ -77,6 +79,64 @@ where
     })
 }
 
+/// Drive a protocol stream until `count` channels arrive.
+pub fn drive_until_channels<IO>(
+    mut proto: Protocol<IO>,
+    count: usize,
+) -> JoinHandle<io::Result<(Protocol<IO>, Vec<Channel>)>>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    task::spawn(async move {
+        let mut channels = vec![];
+        while let Some(event) = proto.next().await {
+            if let Event::Channel(channel) = event? {
+                channels.push(channel);
+                if channels.len() == count {
+                    return Ok((proto, channels));
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Protocol closed before all channels were opened",
+        ))
+    })
+}
+
+/// Drive a protocol for a duration and return the first error.
+pub async fn drive_for<IO>(
+    mut proto: Protocol<IO>,
+    duration: Duration,
+) -> hypercore_protocol::Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let drive = async move {
+        while let Some(event) = proto.next().await {
+            event?;
+        }
+        Ok(())
+    };
+    match async_std::future::timeout(duration, drive).await {
+        Ok(result) => result,
+        Err(_elapsed) => Ok(()),
+    }
+}
+
+pub fn want(start: u64, length: u64) -> Want {
+    Want {
+        start,
+        length: Some(length),
+    }
+}
+
+#[cfg(feature = "v10")]
+pub fn v10_want(start: u64, length: u64) -> hypercore_protocol::Message {
+    use hypercore_protocol::v10::{message::Want, Message};
+    hypercore_protocol::Message::V10(Message::Want(Want { start, length }))
+}
+
 pub mod tcp {
     use async_std::net::{TcpListener, TcpStream};
     use async_std::prelude::*;
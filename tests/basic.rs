@@ -3,10 +3,19 @@
 use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task;
+use futures_lite::future;
 use futures_lite::io::{AsyncRead, AsyncWrite};
-use hypercore_protocol::{discovery_key, Channel, Event, Message, Protocol, ProtocolBuilder};
+use hypercore_protocol::{
+    discovery_key, Authorizer, Channel, CloseReason, Duplex, Encryption, Error, Event, Keypair,
+    Message, Options, Protocol, ProtocolBuilder, RateLimiter, Resolver, Result, Version,
+};
 use hypercore_protocol::{schema::*, DiscoveryKey};
-use std::io;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 mod _util;
 use _util::*;
@@ -21,8 +30,8 @@ async fn basic_protocol() -> anyhow::Result<()> {
     let (mut proto_a, event_a) = next_a.await;
     let (proto_b, event_b) = next_b.await;
 
-    assert!(matches!(event_a, Ok(Event::Handshake(_))));
-    assert!(matches!(event_b, Ok(Event::Handshake(_))));
+    assert!(matches!(event_a, Ok(Event::Handshake(_, _))));
+    assert!(matches!(event_b, Ok(Event::Handshake(_, _))));
 
     assert_eq!(proto_a.public_key(), proto_b.remote_public_key());
     assert_eq!(proto_b.public_key(), proto_a.remote_public_key());
@@ -153,17 +162,17 @@ async fn open_close_channels() -> anyhow::Result<()> {
     assert_eq!(channels_a.len(), 1);
     assert_eq!(channels_b.len(), 1);
 
-    let res = channel_a1.want(want(1)).await;
+    let res = channel_a1.want(want(0, 1)).await;
     assert!(matches!(res, Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted));
 
-    let res = channel_b1.want(want(2)).await;
+    let res = channel_b1.want(want(0, 2)).await;
     assert!(matches!(res, Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted));
 
     // Test that channel 2 still works
-    let res = channel_a2.want(want(10)).await;
+    let res = channel_a2.want(want(0, 10)).await;
     assert!(matches!(res, Ok(())));
 
-    let res = channel_b2.want(want(20)).await;
+    let res = channel_b2.want(want(0, 20)).await;
     assert!(matches!(res, Ok(())));
 
     // Check that the message arrives.
@@ -181,17 +190,1066 @@ async fn open_close_channels() -> anyhow::Result<()> {
     let msg_a = channel_a2.next().await;
     let msg_b = channel_b2.next().await;
 
-    assert_eq!(msg_a, Some(Message::Want(want(20))));
-    assert_eq!(msg_b, Some(Message::Want(want(10))));
+    assert_eq!(msg_a, Some(Message::Want(want(0, 20))));
+    assert_eq!(msg_b, Some(Message::Want(want(0, 10))));
 
     eprintln!("all good!");
 
     Ok(())
 }
 
-fn want(len: u64) -> Want {
-    Want {
-        start: 0,
-        length: Some(len),
+#[async_std::test]
+async fn static_keypair() -> anyhow::Result<()> {
+    let keypair_a = Keypair::generate();
+    let keypair_b = Keypair::from_bytes(&Keypair::generate().to_bytes())?;
+
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_keypair(keypair_a.clone())
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_keypair(keypair_b.clone())
+        .connect_rw(br, bw);
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+
+    assert_eq!(
+        event_a?,
+        Event::Handshake(*keypair_b.public_key(), Version::V1)
+    );
+    assert_eq!(
+        event_b?,
+        Event::Handshake(*keypair_a.public_key(), Version::V1)
+    );
+    assert_eq!(proto_a.public_key(), Some(&keypair_a.public_key()[..]));
+    assert_eq!(proto_b.public_key(), Some(&keypair_b.public_key()[..]));
+    Ok(())
+}
+
+#[test]
+fn keypair_from_bytes() {
+    let keypair = Keypair::generate();
+    let bytes = keypair.to_bytes();
+    assert_eq!(Keypair::from_bytes(&bytes).unwrap(), keypair);
+    assert_eq!(
+        Keypair::from_secret_key(keypair.secret_key()).unwrap(),
+        keypair
+    );
+
+    let mut invalid = bytes;
+    invalid[63] ^= 1;
+    assert!(Keypair::from_bytes(&invalid).is_err());
+    assert!(Keypair::from_bytes(&bytes[..32]).is_err());
+}
+
+#[derive(Debug)]
+struct Allowlist(Vec<[u8; 32]>);
+
+#[async_trait::async_trait]
+impl Authorizer for Allowlist {
+    async fn authorize(&self, remote_public_key: &[u8; 32], _payload: &[u8]) -> bool {
+        self.0.contains(remote_public_key)
     }
 }
+
+#[async_std::test]
+async fn authorizer() -> anyhow::Result<()> {
+    let keypair_a = Keypair::generate();
+    let keypair_b = Keypair::generate();
+
+    // b only accepts a.
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_keypair(keypair_a.clone())
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_keypair(keypair_b.clone())
+        .set_authorizer(Allowlist(vec![*keypair_a.public_key()]))
+        .connect_rw(br, bw);
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (_proto_a, event_a) = next_a.await;
+    let (_proto_b, event_b) = next_b.await;
+    assert_eq!(
+        event_a?,
+        Event::Handshake(*keypair_b.public_key(), Version::V1)
+    );
+    assert_eq!(
+        event_b?,
+        Event::Handshake(*keypair_a.public_key(), Version::V1)
+    );
+
+    // a does not accept b.
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_keypair(keypair_a.clone())
+        .set_authorizer(Allowlist(vec![]))
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_keypair(keypair_b.clone())
+        .connect_rw(br, bw);
+    let next_a = next_event(proto_a);
+    let _next_b = next_event(proto_b);
+    let (_proto_a, event_a) = next_a.await;
+    assert!(matches!(event_a, Err(Error::Unauthorized)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn close_command() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+
+    let key = [4u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+
+    proto_a.commands().close(discovery_key(&key)).await?;
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+    assert_eq!(event_a?, Event::Close(discovery_key(&key)));
+    assert_eq!(event_b?, Event::Close(discovery_key(&key)));
+    assert_eq!(proto_a.channels().count(), 0);
+    assert_eq!(proto_b.channels().count(), 0);
+
+    assert!(channel_a.closed());
+    assert!(channel_b.closed());
+    assert_eq!(channel_a.next().await, None);
+    assert_eq!(
+        channel_b.next().await,
+        Some(Message::Close(Close {
+            discovery_key: None
+        }))
+    );
+    assert_eq!(channel_b.next().await, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn close_command_remote_only() -> anyhow::Result<()> {
+    let (mut proto_a, proto_b) = create_pair_memory().await?;
+
+    let key = [5u8; 32];
+    proto_a.open(key).await?;
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+    assert!(matches!(event_a, Ok(Event::Handshake(_, _))));
+    assert!(matches!(event_b, Ok(Event::Handshake(_, _))));
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_b, event_b) = next_b.await;
+    assert_eq!(event_b?, Event::DiscoveryKey(discovery_key(&key)));
+
+    // b never opened the channel, so the close message carries the discovery key.
+    proto_b.commands().close(discovery_key(&key)).await?;
+
+    let (_, event_b) = next_event(proto_b).await;
+    let (proto_a, event_a) = next_a.await;
+    assert_eq!(event_b?, Event::Close(discovery_key(&key)));
+    assert_eq!(event_a?, Event::Close(discovery_key(&key)));
+    assert_eq!(proto_a.channels().count(), 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn reject_remote_channel() -> anyhow::Result<()> {
+    let (mut proto_a, proto_b) = create_pair_memory().await?;
+
+    let key = [5u8; 32];
+    proto_a.open(key).await?;
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+    assert!(matches!(event_a, Ok(Event::Handshake(_, _))));
+    assert!(matches!(event_b, Ok(Event::Handshake(_, _))));
+
+    let next_a = next_event(proto_a);
+    let (mut proto_b, event_b) = next_event(proto_b).await;
+    assert_eq!(event_b?, Event::DiscoveryKey(discovery_key(&key)));
+    proto_b.reject(discovery_key(&key)).await?;
+    let next_b = next_event(proto_b);
+
+    // a is told that the channel was closed.
+    let (mut proto_a, event_a) = next_a.await;
+    assert_eq!(event_a?, Event::Close(discovery_key(&key)));
+    assert_eq!(proto_a.channels().count(), 0);
+
+    // b forgot the rejected channel, so it is announced again.
+    proto_a.open(key).await?;
+    let _next_a = next_event(proto_a);
+    let (proto_b, event_b) = next_b.await;
+    assert_eq!(event_b?, Event::DiscoveryKey(discovery_key(&key)));
+    assert_eq!(proto_b.channels().count(), 1);
+    Ok(())
+}
+
+#[async_std::test]
+async fn reject_keeps_close_id_reserved() -> anyhow::Result<()> {
+    let (mut proto_a, proto_b) = create_pair_memory().await?;
+
+    let key = [5u8; 32];
+    proto_a.open(key).await?;
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+    assert!(matches!(event_a, Ok(Event::Handshake(_, _))));
+    assert!(matches!(event_b, Ok(Event::Handshake(_, _))));
+
+    let (mut proto_b, event_b) = next_event(proto_b).await;
+    assert_eq!(event_b?, Event::DiscoveryKey(discovery_key(&key)));
+    // The close message of the rejected channel takes the first free ID.
+    proto_b.reject(discovery_key(&key)).await?;
+
+    // A channel that b opens later does not get the ID of the close message.
+    let mut proto_a = proto_a;
+    let key = [6u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (_, channel_a) = next_a.await?;
+    let (_, channel_b) = next_b.await?;
+    assert_eq!(channel_a.id(), 2);
+    assert_eq!(channel_b.id(), 2);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Feeds(Vec<[u8; 32]>);
+
+#[async_trait::async_trait]
+impl Resolver for Feeds {
+    async fn resolve(&self, dkey: &DiscoveryKey) -> Option<[u8; 32]> {
+        task::sleep(Duration::from_millis(10)).await;
+        self.0
+            .iter()
+            .find(|key| discovery_key(*key) == *dkey)
+            .copied()
+    }
+}
+
+#[async_std::test]
+async fn resolver() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true).connect_rw(ar, aw);
+    let known = [6u8; 32];
+    let unknown = [7u8; 32];
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_resolver(Feeds(vec![known]))
+        .connect_rw(br, bw);
+
+    proto_a.open(unknown).await?;
+    proto_a.open(known).await?;
+    let next_a = drive_until_channel(proto_a);
+
+    // b opens the known channel without being asked.
+    let mut events = vec![];
+    let channel_b = loop {
+        match proto_b.next().await.unwrap()? {
+            Event::Channel(channel) => break channel,
+            event => events.push(event),
+        }
+    };
+    let mut channel_b = channel_b;
+    assert_eq!(channel_b.key(), &known);
+    // The unknown channel is left to the application.
+    assert!(events.contains(&Event::DiscoveryKey(discovery_key(&unknown))));
+    assert!(!events.contains(&Event::DiscoveryKey(discovery_key(&known))));
+
+    let _proto_b = task::spawn(async move { while proto_b.next().await.is_some() {} });
+    let (mut proto_a, mut channel_a) = next_a.await?;
+    assert_eq!(channel_a.key(), &known);
+    let _proto_a = task::spawn(async move { while proto_a.next().await.is_some() {} });
+    channel_a.want(want(0, 10)).await?;
+    assert_eq!(channel_b.next().await, Some(Message::Want(want(0, 10))));
+    Ok(())
+}
+
+#[async_std::test]
+async fn protocol_close() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+
+    let key = [6u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (mut proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+
+    channel_a.want(want(0, 10)).await?;
+    proto_a.close();
+
+    let events_a = task::spawn(async move { proto_a.collect::<Vec<_>>().await });
+    let events_b = task::spawn(async move { proto_b.collect::<Vec<_>>().await });
+    let events_a: Vec<Event> = events_a.await.into_iter().collect::<Result<_>>()?;
+    let events_b: Vec<Event> = events_b.await.into_iter().collect::<Result<_>>()?;
+
+    assert_eq!(
+        events_a,
+        vec![
+            Event::Close(discovery_key(&key)),
+            Event::Closed(CloseReason::Local)
+        ]
+    );
+    assert_eq!(
+        events_b,
+        vec![
+            Event::Close(discovery_key(&key)),
+            Event::Closed(CloseReason::RemoteClosed)
+        ]
+    );
+
+    // Messages sent before closing are still delivered.
+    assert_eq!(channel_b.next().await, Some(Message::Want(want(0, 10))));
+    assert_eq!(
+        channel_b.next().await,
+        Some(Message::Close(Close {
+            discovery_key: None
+        }))
+    );
+    assert_eq!(channel_b.next().await, None);
+    assert_eq!(channel_a.next().await, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn keepalive() -> anyhow::Result<()> {
+    let timeout = Some(Duration::from_millis(200));
+    let keepalive = Some(Duration::from_millis(50));
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_timeout(timeout)
+        .set_keepalive(keepalive)
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_timeout(timeout)
+        .set_keepalive(keepalive)
+        .connect_rw(br, bw);
+
+    // The keepalive pings prevent the protocols from timing out.
+    let drive_a = task::spawn(drive_for(proto_a, Duration::from_millis(600)));
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_millis(600)));
+    drive_a.await?;
+    drive_b.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn keepalive_rtt() -> anyhow::Result<()> {
+    let keepalive = Some(Duration::from_millis(50));
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_keepalive(keepalive)
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_keepalive(keepalive)
+        .connect_rw(br, bw);
+    assert_eq!(proto_a.stats().rtt, None);
+
+    // b answers the keepalive pings of a.
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_millis(500)));
+    let drive_a = async {
+        while let Some(event) = proto_a.next().await {
+            event?;
+        }
+        Ok::<(), Error>(())
+    };
+    let _ = async_std::future::timeout(Duration::from_millis(300), drive_a).await;
+    let rtt = proto_a.stats().rtt.expect("no keepalive was answered");
+    assert!(rtt < Duration::from_millis(300), "rtt {:?}", rtt);
+    drive_b.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn timeout_without_keepalive() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_timeout(Some(Duration::from_millis(100)))
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false)
+        .set_keepalive(None)
+        .connect_rw(br, bw);
+
+    let drive_a = task::spawn(drive_for(proto_a, Duration::from_secs(5)));
+    let _drive_b = task::spawn(drive_for(proto_b, Duration::from_secs(5)));
+    assert!(matches!(drive_a.await, Err(Error::Timeout)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn handshake_timeout() -> anyhow::Result<()> {
+    // The remote end of the pipe never answers.
+    let (ar, _bw) = sluice::pipe::pipe();
+    let (_br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_handshake_timeout(Some(Duration::from_millis(100)))
+        .connect_rw(ar, aw);
+    let result = drive_for(proto_a, Duration::from_secs(5)).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn open_channel_without_noise() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_noise(false)
+        .set_encrypted(false)
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_noise(false)
+        .set_encrypted(false)
+        .connect_rw(br, bw);
+
+    // Without a handshake there are no capabilities to verify.
+    let key = [7u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (_, channel_a) = next_a.await?;
+    let (_, channel_b) = next_b.await?;
+    assert_eq!(channel_a.discovery_key(), channel_b.discovery_key());
+    Ok(())
+}
+
+#[async_std::test]
+async fn channel_backpressure() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true).connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_channel_capacity(2)
+        .connect_rw(br, bw);
+
+    let key = [5u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    let drive_a = task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    // Send more messages than fit into b's inbound queue while b does not read.
+    // Once the queue is full, b stops reading and the sender has to wait.
+    let mut send = task::spawn(async move {
+        for i in 0..1000 {
+            channel_a.want(want(0, i)).await?;
+        }
+        Result::Ok(channel_a)
+    });
+    task::sleep(Duration::from_millis(100)).await;
+    assert!(
+        future::poll_once(&mut send).await.is_none(),
+        "sender did not wait for b"
+    );
+    let received = channel_b.stats().received.total().messages;
+    task::sleep(Duration::from_millis(100)).await;
+    assert_eq!(channel_b.stats().received.total().messages, received);
+    assert!(future::poll_once(&mut send).await.is_none());
+
+    // All messages arrive in order once b reads them.
+    for i in 0..1000 {
+        assert_eq!(channel_b.next().await, Some(Message::Want(want(0, i))));
+    }
+    send.await?;
+    drive_a.await?;
+    drive_b.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn authenticated_encryption() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_encryption(Encryption::Authenticated)
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_encryption(Encryption::Authenticated)
+        .connect_rw(br, bw);
+
+    let key = [6u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    channel_a.want(want(0, 10)).await?;
+    assert_eq!(channel_b.next().await, Some(Message::Want(want(0, 10))));
+    channel_b.want(want(0, 20)).await?;
+    assert_eq!(channel_a.next().await, Some(Message::Want(want(0, 20))));
+    Ok(())
+}
+
+#[async_std::test]
+async fn encryption_mismatch() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let proto_a = ProtocolBuilder::new(true)
+        .set_encryption(Encryption::Authenticated)
+        .connect_rw(ar, aw);
+    let proto_b = ProtocolBuilder::new(false).connect_rw(br, bw);
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (_, event_a) = next_a.await;
+    let (_, event_b) = next_b.await;
+    assert!(matches!(event_a, Err(Error::EncryptionMismatch)));
+    assert!(matches!(event_b, Err(Error::EncryptionMismatch)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn authenticated_encryption_detects_tampering() -> anyhow::Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // Route the bytes from a to b through a task that can flip a bit.
+    let (mut mitm_r, aw) = sluice::pipe::pipe();
+    let (br, mut mitm_w) = sluice::pipe::pipe();
+    let (ar, bw) = sluice::pipe::pipe();
+    let tamper = Arc::new(AtomicBool::new(false));
+    let tamper_clone = tamper.clone();
+    task::spawn(async move {
+        let mut buf = vec![0u8; 1024 * 64];
+        loop {
+            let n = mitm_r.read(&mut buf).await?;
+            if n == 0 {
+                return io::Result::Ok(());
+            }
+            if tamper_clone.swap(false, Ordering::SeqCst) {
+                buf[n - 1] ^= 1;
+            }
+            mitm_w.write_all(&buf[..n]).await?;
+            mitm_w.flush().await?;
+        }
+    });
+
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_encryption(Encryption::Authenticated)
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_encryption(Encryption::Authenticated)
+        .connect_rw(br, bw);
+    let key = [6u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (mut proto_b, _channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+
+    tamper.store(true, Ordering::SeqCst);
+    channel_a.want(want(0, 10)).await?;
+    let result = loop {
+        match proto_b.next().await {
+            Some(Ok(_event)) => continue,
+            Some(Err(e)) => break Err(e),
+            None => break Ok(()),
+        }
+    };
+    assert!(matches!(result, Err(Error::Decrypt)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn empty_versions() -> anyhow::Result<()> {
+    let (ar, aw) = sluice::pipe::pipe();
+    let mut options = Options::new(true);
+    options.versions = vec![];
+    let proto = Protocol::new(Duplex::new(ar, aw), options);
+    let (_, event) = next_event(proto).await;
+    assert!(matches!(event, Err(Error::InvalidOptions(_))));
+    Ok(())
+}
+
+#[cfg(all(feature = "v10", not(feature = "v10-unstable")))]
+#[async_std::test]
+async fn v10_requires_unstable_feature() -> anyhow::Result<()> {
+    let (ar, aw) = sluice::pipe::pipe();
+    let proto = ProtocolBuilder::new(true)
+        .set_versions(&[Version::V1, Version::V10])
+        .connect_rw(ar, aw);
+    let (_, event) = next_event(proto).await;
+    assert!(matches!(event, Err(Error::InvalidOptions(_))));
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "At least one version is required")]
+fn set_empty_versions() {
+    ProtocolBuilder::new(true).set_versions(&[]);
+}
+
+/// Counters of a `CountingWriter`.
+#[derive(Default)]
+struct WriteCounters {
+    writes: AtomicUsize,
+    slices: AtomicUsize,
+    flushes: AtomicUsize,
+}
+
+impl WriteCounters {
+    fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    fn slices(&self) -> usize {
+        self.slices.load(Ordering::SeqCst)
+    }
+
+    fn flushes(&self) -> usize {
+        self.flushes.load(Ordering::SeqCst)
+    }
+}
+
+/// Writer that counts the writes and flushes to the inner writer, and
+/// writes at most `max_write` bytes at once.
+struct CountingWriter<W> {
+    inner: W,
+    counters: Arc<WriteCounters>,
+    max_write: usize,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W, counters: Arc<WriteCounters>) -> Self {
+        Self {
+            inner,
+            counters,
+            max_write: usize::MAX,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.counters.writes.fetch_add(1, Ordering::SeqCst);
+        self.counters.slices.fetch_add(1, Ordering::SeqCst);
+        let len = buf.len().min(self.max_write);
+        Pin::new(&mut self.inner).poll_write(cx, &buf[..len])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.counters.writes.fetch_add(1, Ordering::SeqCst);
+        self.counters.slices.fetch_add(bufs.len(), Ordering::SeqCst);
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len > self.max_write {
+            let buf = bufs.iter().find(|buf| !buf.is_empty()).unwrap();
+            let len = buf.len().min(self.max_write);
+            return Pin::new(&mut self.inner).poll_write(cx, &buf[..len]);
+        }
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.counters.flushes.fetch_add(1, Ordering::SeqCst);
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[async_std::test]
+async fn corked_writes_are_coalesced() -> anyhow::Result<()> {
+    let counters = Arc::new(WriteCounters::default());
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let aw = CountingWriter::new(aw, counters.clone());
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_cork(Some(Duration::from_millis(100)))
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false).connect_rw(br, bw);
+
+    let key = [8u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    // A burst of small messages is held back and written at once.
+    let before = counters.writes();
+    for i in 0..200 {
+        channel_a.want(want(0, i)).await?;
+    }
+    for i in 0..200 {
+        assert_eq!(channel_b.next().await, Some(Message::Want(want(0, i))));
+    }
+    let count = counters.writes() - before;
+    assert!(count <= 2, "expected coalesced writes, got {}", count);
+    assert!(counters.slices() >= counters.writes());
+    Ok(())
+}
+
+#[async_std::test]
+async fn flush_when_queue_drains() -> anyhow::Result<()> {
+    let counters = Arc::new(WriteCounters::default());
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut aw = CountingWriter::new(aw, counters.clone());
+    aw.max_write = 64;
+    let mut proto_a = ProtocolBuilder::new(true).connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false).connect_rw(br, bw);
+
+    let key = [8u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    // The message takes many writes, but the writer is flushed only once
+    // all of them are done.
+    let writes = counters.writes();
+    let flushes = counters.flushes();
+    let data = Data {
+        index: 0,
+        value: Some(vec![0u8; 4000].into()),
+        nodes: vec![],
+        signature: None,
+    };
+    channel_a.data(data.clone()).await?;
+    assert_eq!(channel_b.next().await, Some(Message::Data(data)));
+    task::sleep(Duration::from_millis(50)).await;
+    assert!(counters.writes() - writes >= 4000 / 64);
+    assert_eq!(counters.flushes() - flushes, 1);
+    Ok(())
+}
+
+#[async_std::test]
+async fn rate_limit() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let upload = RateLimiter::new(1024 * 1024);
+    let mut proto_a = ProtocolBuilder::new(true)
+        .add_upload_limit(upload.clone())
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false).connect_rw(br, bw);
+
+    let key = [9u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(3)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(3)));
+
+    // After the first second worth of bytes, a writes at most 20 kB/s.
+    upload.set_rate(20_000);
+    let send = task::spawn(async move {
+        for i in 0..10 {
+            let data = Data {
+                index: i,
+                value: Some(vec![0u8; 4000].into()),
+                nodes: vec![],
+                signature: None,
+            };
+            channel_a.data(data).await?;
+        }
+        Result::Ok(channel_a)
+    });
+    let start = std::time::Instant::now();
+    for i in 0..10 {
+        match channel_b.next().await {
+            Some(Message::Data(data)) => assert_eq!(data.index, i),
+            message => panic!("Unexpected message {:?}", message),
+        }
+    }
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed > Duration::from_millis(800) && elapsed < Duration::from_millis(2500),
+        "expected throttled transfer, took {:?}",
+        elapsed
+    );
+    send.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn stats() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [10u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (mut proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_millis(500)));
+
+    let data = Data {
+        index: 0,
+        value: Some(vec![1u8; 100].into()),
+        nodes: vec![],
+        signature: None,
+    };
+    channel_a.want(want(0, 10)).await?;
+    channel_a.data(data).await?;
+    // Drive a until both messages are written.
+    let _ = async_std::future::timeout(Duration::from_millis(200), proto_a.next()).await;
+    assert!(matches!(channel_b.next().await, Some(Message::Want(_))));
+    assert!(matches!(channel_b.next().await, Some(Message::Data(_))));
+
+    let stats = proto_a.stats();
+    assert_eq!(stats.sent.open.messages, 1);
+    assert_eq!(stats.sent.want.messages, 1);
+    assert_eq!(stats.sent.data.messages, 1);
+    assert_eq!(stats.received.open.messages, 1);
+    assert_eq!(stats.open_channels, 1);
+    assert!(stats.bytes_written > 100);
+    assert!(stats.frames_written >= 5);
+    assert_eq!(stats.queued_bytes, 0);
+
+    let stats_a = channel_a.stats();
+    assert_eq!(stats_a.sent.total().messages, 3);
+    assert_eq!(stats_a.blocks_uploaded, 1);
+    assert_eq!(stats_a.bytes_uploaded, 100);
+    let stats_b = channel_b.stats();
+    assert_eq!(stats_b.received.open.messages, 1);
+    assert_eq!(stats_b.received.want, stats_a.sent.want);
+    assert_eq!(stats_b.blocks_downloaded, 1);
+    assert_eq!(stats_b.bytes_downloaded, 100);
+    drive_b.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn get_blocks() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let (key1, key2) = ([11u8; 32], [12u8; 32]);
+    for key in [key1, key2].iter() {
+        proto_a.open(*key).await?;
+        proto_b.open(*key).await?;
+    }
+    let next_a = drive_until_channels(proto_a, 2);
+    let next_b = drive_until_channels(proto_b, 2);
+    let (proto_a, mut channels_a) = next_a.await?;
+    let (proto_b, channels_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(2)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(2)));
+    channels_a.sort_by_key(|channel| *channel.key());
+
+    // b answers all requests on the second channel, and no requests on the first.
+    let cancelled = Arc::new(Mutex::new(vec![]));
+    for mut channel in channels_b {
+        let cancelled = cancelled.clone();
+        task::spawn(async move {
+            while let Some(message) = channel.next().await {
+                match message {
+                    Message::Request(request) if *channel.key() == key2 => {
+                        let data = Data {
+                            index: request.index,
+                            value: Some(vec![request.index as u8].into()),
+                            nodes: vec![],
+                            signature: None,
+                        };
+                        channel.data(data).await.unwrap();
+                    }
+                    Message::Cancel(cancel) => cancelled
+                        .lock()
+                        .unwrap()
+                        .push((*channel.key(), cancel.index)),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    let (channel_a1, channel_a2) = (&channels_a[0], &channels_a[1]);
+    let data = channel_a2.get(3).await?;
+    assert_eq!(data.index, 3);
+    assert_eq!(data.value.as_deref(), Some(&[3u8][..]));
+
+    // An unanswered request times out and is cancelled.
+    let result = channel_a1.get(4).timeout(Duration::from_millis(100)).await;
+    assert!(matches!(result, Err(Error::Timeout)));
+
+    // It is retried on the next channel.
+    let data = channel_a1
+        .get(5)
+        .timeout(Duration::from_millis(100))
+        .or(channel_a2)
+        .await?;
+    assert_eq!(data.index, 5);
+
+    // Dropping a request cancels it.
+    let get = channel_a1.get(6);
+    let _ = async_std::future::timeout(Duration::from_millis(50), get).await;
+
+    task::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        *cancelled.lock().unwrap(),
+        vec![(key1, 4), (key1, 5), (key1, 6)]
+    );
+    // Answered requests do not show up on the channel stream.
+    let mut channel_a2 = channels_a.pop().unwrap();
+    let next = async_std::future::timeout(Duration::from_millis(50), channel_a2.next()).await;
+    assert!(next.is_err());
+    Ok(())
+}
+
+#[async_std::test]
+async fn get_not_sent_is_not_cancelled() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [14u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_b, Duration::from_secs(2)));
+
+    // b answers all requests and records the cancelled ones.
+    let cancelled = Arc::new(Mutex::new(vec![]));
+    let cancelled_b = cancelled.clone();
+    task::spawn(async move {
+        while let Some(message) = channel_b.next().await {
+            match message {
+                Message::Request(request) => {
+                    let data = Data {
+                        index: request.index,
+                        value: Some(vec![request.index as u8].into()),
+                        nodes: vec![],
+                        signature: None,
+                    };
+                    channel_b.data(data).await.unwrap();
+                }
+                Message::Cancel(cancel) => cancelled_b.lock().unwrap().push(cancel.index),
+                _ => {}
+            }
+        }
+    });
+
+    // a is not driven yet, so its outbound queue fills up.
+    while future::poll_once(channel_a.want(want(0, 0)))
+        .await
+        .is_some()
+    {}
+    // The request cannot be sent, and is dropped once the queue drained.
+    let mut get = channel_a.get(7);
+    assert!(future::poll_once(&mut get).await.is_none());
+    task::spawn(drive_for(proto_a, Duration::from_secs(2)));
+    task::sleep(Duration::from_millis(50)).await;
+    drop(get);
+
+    // The block can be requested again.
+    let data = channel_a.get(7).await?;
+    assert_eq!(data.index, 7);
+    task::sleep(Duration::from_millis(50)).await;
+    assert!(cancelled.lock().unwrap().is_empty());
+    Ok(())
+}
+
+#[async_std::test]
+async fn channel_sink() -> anyhow::Result<()> {
+    use futures::{stream, SinkExt};
+
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [13u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    // More messages than fit into the outbound queue.
+    let messages = (0..200).map(|i| Ok(Message::Want(want(0, i))));
+    channel_a.send_all(&mut stream::iter(messages)).await?;
+    for i in 0..200 {
+        assert_eq!(channel_b.next().await, Some(Message::Want(want(0, i))));
+    }
+
+    channel_a.close().await?;
+    let result = channel_a
+        .send_all(&mut stream::iter(vec![Ok(Message::Want(want(0, 1)))]))
+        .await;
+    assert!(matches!(result, Err(Error::ChannelClosed)));
+    Ok(())
+}
+
+#[async_std::test]
+async fn send_only_channel() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [14u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, channel_b) = next_b.await?;
+    let drive_a = task::spawn(drive_for(proto_a, Duration::from_millis(500)));
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_millis(500)));
+
+    // b only sends, the messages of a are discarded.
+    let (sender_b, receiver_b) = channel_b.split();
+    drop(receiver_b);
+    for i in 0..10 {
+        channel_a.want(want(0, i)).await?;
+        sender_b.want(want(1, i)).await?;
+    }
+    for i in 0..10 {
+        assert_eq!(channel_a.next().await, Some(Message::Want(want(1, i))));
+    }
+    drive_a.await?;
+    drive_b.await?;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+#![allow(dead_code, unused_imports)]
+
+use async_std::prelude::*;
+use async_std::task;
+use hypercore_protocol::capture::{read_capture, Capture, Direction, FrameKind, Replay};
+use hypercore_protocol::schema::*;
+use hypercore_protocol::{discovery_key, Event, Message, ProtocolBuilder};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod _util;
+use _util::*;
+
+/// Writer that collects the capture in memory.
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_std::test]
+async fn capture_and_replay() -> anyhow::Result<()> {
+    let buf = SharedBuf::default();
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_capture(Capture::new(buf.clone()))
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false).connect_rw(br, bw);
+
+    let key = [3u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    let _task_a = task::spawn(async move { proto_a.collect::<Vec<_>>().await });
+    let _task_b = task::spawn(async move { proto_b.collect::<Vec<_>>().await });
+
+    channel_a.want(want(5, 1)).await?;
+    channel_b.want(want(6, 1)).await?;
+    assert_eq!(channel_b.next().await, Some(Message::Want(want(5, 1))));
+    assert_eq!(channel_a.next().await, Some(Message::Want(want(6, 1))));
+
+    let capture = buf.0.lock().unwrap().clone();
+    let frames = read_capture(&capture[..])?;
+    for direction in [Direction::Outbound, Direction::Inbound].iter() {
+        assert!(frames
+            .iter()
+            .any(|f| f.direction == *direction && f.kind == FrameKind::Handshake));
+    }
+    let sent: Vec<_> = frames
+        .iter()
+        .filter(|f| f.direction == Direction::Outbound && f.kind == FrameKind::Message)
+        .collect();
+    assert!(matches!(sent[0].decode()?, (1, Message::Open(_))));
+    assert_eq!(sent[1].decode()?, (1, Message::Want(want(5, 1))));
+    assert!(sent[1]
+        .to_string()
+        .ends_with("-> [1] Want(Want { start: 5, length: Some(1) })"));
+
+    // Replay what the remote sent into a fresh protocol.
+    let replay = Replay::new(&frames, Direction::Inbound);
+    let mut proto = ProtocolBuilder::new(true)
+        .set_noise(false)
+        .set_encrypted(false)
+        .connect(replay);
+    proto.open(key).await?;
+    let (_proto, mut channel) = drive_until_channel(proto).await?;
+    assert_eq!(channel.discovery_key(), &discovery_key(&key));
+    assert_eq!(channel.next().await, Some(Message::Want(want(6, 1))));
+    Ok(())
+}
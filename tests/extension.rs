@@ -6,7 +6,10 @@ use async_std::task::{self, JoinHandle};
 use futures_lite::io::{AsyncRead, AsyncWrite};
 // use futures_lite::{AsyncReadExt, AsyncWriteExt};
 use hypercore_protocol::schema::*;
-use hypercore_protocol::{discovery_key, Channel, Event, Message, Protocol, ProtocolBuilder};
+use hypercore_protocol::{
+    discovery_key, Channel, Error, Event, ExtensionEvent, InactivePolicy, Message, Protocol,
+    ProtocolBuilder,
+};
 use std::io;
 
 mod _util;
@@ -70,17 +73,179 @@ async fn stream_extension() -> anyhow::Result<()> {
         while let Some(message) = ext_b.next().await {
             assert_eq!(message, b"hello".to_vec());
             // eprintln!("B received: {:?}", String::from_utf8(message));
-            ext_b.send(b"ack".to_vec()).await;
+            ext_b.send(b"ack".to_vec()).await.unwrap();
         }
     });
 
-    ext_a.send(b"hello".to_vec()).await;
+    ext_a.send(b"hello".to_vec()).await?;
     let response = ext_a.next().await;
-    assert_eq!(response, Some(b"ack".to_vec()));
+    assert_eq!(response.as_deref(), Some(&b"ack"[..]));
     // eprintln!("A received: {:?}", response.map(String::from_utf8));
     Ok(())
 }
 
+#[async_std::test]
+async fn stream_extension_events() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+
+    let mut ext_a = proto_a.register_extension("ext").await;
+    ext_a.set_inactive_policy(InactivePolicy::Fail);
+    assert!(!ext_a.is_active());
+    let result = ext_a.send(b"hello".to_vec()).await;
+    assert!(matches!(result, Err(Error::ExtensionInactive(_))));
+
+    let mut ext_b = proto_b.register_extension("ext").await;
+    drive(proto_b);
+
+    // The change is emitted both on the protocol and on the extension.
+    loop {
+        let (proto, event) = next_event(proto_a).await;
+        proto_a = proto;
+        if let Event::Extension(name, event) = event? {
+            assert_eq!(name, "ext");
+            assert_eq!(event, ExtensionEvent::RemoteEnabled);
+            break;
+        }
+    }
+    drive(proto_a);
+    assert_eq!(
+        ext_a.next_event().await,
+        Some(ExtensionEvent::RemoteEnabled)
+    );
+    assert!(ext_a.is_active());
+
+    ext_a.send(b"hello".to_vec()).await?;
+    assert_eq!(ext_b.next().await.as_deref(), Some(&b"hello"[..]));
+    Ok(())
+}
+
+#[async_std::test]
+async fn channel_extension_buffer() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [1u8; 32];
+
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, channel_a) = next_a.await?;
+    let (proto_b, channel_b) = next_b.await?;
+    let (sender_a, receiver_a) = channel_a.split();
+    let (sender_b, receiver_b) = channel_b.split();
+    drive(proto_a);
+    drive(proto_b);
+    drive(receiver_a);
+    drive(receiver_b);
+
+    let mut ext_a = sender_a.register_extension("ext").await;
+    ext_a.set_inactive_policy(InactivePolicy::Buffer(2));
+    ext_a.send(b"one".to_vec()).await?;
+    ext_a.send(b"two".to_vec()).await?;
+    let result = ext_a.send(b"three".to_vec()).await;
+    assert!(matches!(result, Err(Error::ExtensionInactive(_))));
+
+    // The buffered messages are sent once the remote registers the extension.
+    let mut ext_b = sender_b.register_extension("ext").await;
+    assert_eq!(
+        ext_a.next_event().await,
+        Some(ExtensionEvent::RemoteEnabled)
+    );
+    assert_eq!(ext_b.next().await.as_deref(), Some(&b"one"[..]));
+    assert_eq!(ext_b.next().await.as_deref(), Some(&b"two"[..]));
+    ext_a.send(b"four".to_vec()).await?;
+    assert_eq!(ext_b.next().await.as_deref(), Some(&b"four"[..]));
+
+    // The remote had the extension before, so it is enabled right away.
+    assert_eq!(
+        ext_b.next_event().await,
+        Some(ExtensionEvent::RemoteEnabled)
+    );
+
+    sender_b
+        .options(Options {
+            extensions: vec![],
+            ack: None,
+        })
+        .await?;
+    assert_eq!(
+        ext_a.next_event().await,
+        Some(ExtensionEvent::RemoteDisabled)
+    );
+    assert!(!ext_a.is_active());
+    Ok(())
+}
+
+#[async_std::test]
+async fn stream_extension_late_registration() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+
+    let mut remote_b = proto_b.register_extension("b").await;
+    let mut remote_c = proto_b.register_extension("c").await;
+
+    // Registering "b" moves "c" from ID 0 to ID 1, also for messages that
+    // are not yet written.
+    let ext_c = proto_a.register_extension("c").await;
+    ext_c.send(b"one".to_vec()).await?;
+    let ext_b = proto_a.register_extension("b").await;
+    ext_c.send(b"two".to_vec()).await?;
+    ext_b.send(b"three".to_vec()).await?;
+
+    drive(proto_a);
+    drive(proto_b);
+    assert_eq!(remote_c.next().await.as_deref(), Some(&b"one"[..]));
+    assert_eq!(remote_c.next().await.as_deref(), Some(&b"two"[..]));
+    assert_eq!(remote_b.next().await.as_deref(), Some(&b"three"[..]));
+    Ok(())
+}
+
+#[async_std::test]
+async fn channel_extension_late_registration() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [1u8; 32];
+
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, channel_a) = next_a.await?;
+    let (proto_b, channel_b) = next_b.await?;
+    let (sender_a, receiver_a) = channel_a.split();
+    let (sender_b, receiver_b) = channel_b.split();
+    drive(proto_a);
+    drive(proto_b);
+    drive(receiver_a);
+    drive(receiver_b);
+
+    let mut ext_c = sender_a.register_extension("c").await;
+    let mut remote_c = sender_b.register_extension("c").await;
+    assert_eq!(
+        ext_c.next_event().await,
+        Some(ExtensionEvent::RemoteEnabled)
+    );
+    ext_c.send(b"one".to_vec()).await?;
+    assert_eq!(remote_c.next().await.as_deref(), Some(&b"one"[..]));
+
+    // Both sides register "a" after the negotiation, which renumbers "c".
+    let mut ext_a = sender_a.register_extension("a").await;
+    let mut remote_a = sender_b.register_extension("a").await;
+    ext_c.send(b"two".to_vec()).await?;
+    ext_a.send(b"three".to_vec()).await?;
+    assert_eq!(remote_c.next().await.as_deref(), Some(&b"two"[..]));
+    assert_eq!(remote_a.next().await.as_deref(), Some(&b"three"[..]));
+
+    assert_eq!(
+        ext_a.next_event().await,
+        Some(ExtensionEvent::RemoteEnabled)
+    );
+    remote_c.send(b"four".to_vec()).await?;
+    remote_a.send(b"five".to_vec()).await?;
+    assert_eq!(ext_c.next().await.as_deref(), Some(&b"four"[..]));
+    assert_eq!(ext_a.next().await.as_deref(), Some(&b"five"[..]));
+    Ok(())
+}
+
 #[async_std::test]
 async fn channel_extension() -> anyhow::Result<()> {
     // env_logger::init();
@@ -107,17 +272,116 @@ async fn channel_extension() -> anyhow::Result<()> {
         while let Some(message) = ext_b.next().await {
             // eprintln!("B received: {:?}", String::from_utf8(message));
             assert_eq!(message, b"hello".to_vec());
-            ext_b.send(b"ack".to_vec()).await;
+            ext_b.send(b"ack".to_vec()).await.unwrap();
         }
     });
 
-    ext_a.send(b"hello".to_vec()).await;
+    ext_a.send(b"hello".to_vec()).await?;
     let response = ext_a.next().await;
-    assert_eq!(response, Some(b"ack".to_vec()));
+    assert_eq!(response.as_deref(), Some(&b"ack"[..]));
     // eprintln!("A received: {:?}", response.map(String::from_utf8));
     Ok(())
 }
 
+#[async_std::test]
+async fn split_channel_extension() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [1u8; 32];
+
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, channel_a) = next_a.await?;
+    let (proto_b, channel_b) = next_b.await?;
+    let (sender_a, receiver_a) = channel_a.split();
+    let (sender_b, mut receiver_b) = channel_b.split();
+
+    let ext_a = sender_a.register_extension("ext").await;
+    let mut ext_b = sender_b.register_extension("ext").await;
+
+    drive(proto_a);
+    drive(proto_b);
+    drive(receiver_a);
+
+    // A clone of the sender sends from another task.
+    let sender = sender_a.clone();
+    task::spawn(async move {
+        ext_a.send(b"hello".to_vec()).await.unwrap();
+        let want = Want {
+            start: 1,
+            length: Some(1),
+        };
+        sender.want(want).await.unwrap();
+    });
+
+    // Extension messages are not emitted on the receiver.
+    let mut messages = vec![];
+    while let Some(message) = receiver_b.next().await {
+        let is_want = matches!(message, Message::Want(_));
+        messages.push(message);
+        if is_want {
+            break;
+        }
+    }
+    assert!(matches!(messages[0], Message::Options(_)));
+    assert!(matches!(messages.last(), Some(Message::Want(_))));
+    assert!(!messages
+        .iter()
+        .any(|message| matches!(message, Message::Extension(_))));
+    let message = ext_b.next().await;
+    assert_eq!(message.as_deref(), Some(&b"hello"[..]));
+    Ok(())
+}
+
+#[async_std::test]
+async fn channel_extension_sink() -> anyhow::Result<()> {
+    use futures::{stream, SinkExt};
+
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [1u8; 32];
+
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+
+    let mut ext_a = channel_a.register_extension("ext").await;
+    let mut ext_b = channel_b.register_extension("ext").await;
+
+    drive(proto_a);
+    drive(proto_b);
+    drive(channel_a);
+    drive(channel_b);
+
+    let messages = (0..100u8).map(|i| Ok(vec![i; 3]));
+    ext_a.send_all(&mut stream::iter(messages)).await?;
+    for i in 0..100u8 {
+        let message = ext_b.next().await;
+        assert_eq!(message.as_deref(), Some(&[i; 3][..]));
+    }
+    Ok(())
+}
+
+#[async_std::test]
+async fn extension_sink_after_protocol_closed() -> anyhow::Result<()> {
+    use futures::SinkExt;
+
+    let (mut proto_a, _proto_b) = create_pair_memory().await?;
+    let mut ext_a = proto_a.register_extension("ext").await;
+    drop(proto_a);
+
+    let result = SinkExt::send(&mut ext_a, b"hello".to_vec()).await;
+    assert!(matches!(result, Err(Error::ChannelClosed)));
+    let result = SinkExt::send(&mut ext_a, vec![0u8; 5 * 1024 * 1024]).await;
+    assert!(matches!(result, Err(Error::OversizeFrame(_))));
+    Ok(())
+}
+
 #[async_std::test]
 async fn channel_extension_async_read_write() -> anyhow::Result<()> {
     // env_logger::init();
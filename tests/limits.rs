@@ -0,0 +1,206 @@
+#![allow(dead_code, unused_imports)]
+
+use async_std::prelude::*;
+use async_std::task;
+use hypercore_protocol::schema::*;
+use hypercore_protocol::{discovery_key, Error, Event, Message, ProtocolBuilder, Result};
+use std::time::Duration;
+
+mod _util;
+use _util::*;
+
+/// Encode a message frame with the channel ID `channel` and message type `typ`.
+fn frame(channel: u64, typ: u64, message: impl prost::Message) -> Vec<u8> {
+    let header = channel << 4 | typ;
+    let mut body = vec![0u8; varinteger::length(header)];
+    varinteger::encode(header, &mut body);
+    message.encode(&mut body).unwrap();
+    let mut frame = vec![0u8; varinteger::length(body.len() as u64)];
+    varinteger::encode(body.len() as u64, &mut frame);
+    frame.extend_from_slice(&body);
+    frame
+}
+
+fn open(channel: u64, key: u8) -> Vec<u8> {
+    let open = Open {
+        discovery_key: discovery_key(&[key; 32]).to_vec(),
+        capability: None,
+    };
+    frame(channel, 0, open)
+}
+
+/// Send the frames of a hostile remote to a protocol and collect its events
+/// until the stream ends or fails.
+async fn run_hostile(builder: ProtocolBuilder, frames: Vec<Vec<u8>>) -> Vec<Result<Event>> {
+    let (reader, mut writer) = sluice::pipe::pipe();
+    let (_remote_reader, local_writer) = sluice::pipe::pipe();
+    let mut proto = builder
+        .set_noise(false)
+        .set_encrypted(false)
+        .connect_rw(reader, local_writer);
+    for frame in frames {
+        writer.write_all(&frame).await.unwrap();
+    }
+    drop(writer);
+    let mut events = vec![];
+    while let Some(event) = proto.next().await {
+        let failed = event.is_err();
+        events.push(event);
+        if failed {
+            break;
+        }
+    }
+    events
+}
+
+fn limit_exceeded(events: &[Result<Event>]) -> bool {
+    matches!(events.last(), Some(Err(Error::LimitExceeded(_))))
+}
+
+#[async_std::test]
+async fn huge_remote_channel_id() {
+    let events = run_hostile(ProtocolBuilder::new(false), vec![open(1 << 40, 1)]).await;
+    assert!(limit_exceeded(&events));
+
+    let builder = ProtocolBuilder::new(false).set_max_remote_channel_id(10);
+    let events = run_hostile(builder, vec![open(10, 1)]).await;
+    assert_eq!(
+        events[0].as_ref().unwrap(),
+        &Event::DiscoveryKey(discovery_key(&[1; 32]))
+    );
+    assert!(!limit_exceeded(&events));
+    let builder = ProtocolBuilder::new(false).set_max_remote_channel_id(10);
+    let events = run_hostile(builder, vec![open(11, 1)]).await;
+    assert!(limit_exceeded(&events));
+}
+
+#[async_std::test]
+async fn too_many_channels() {
+    let builder = ProtocolBuilder::new(false).set_max_channels(3);
+    let frames = (1..=3).map(|i| open(i, i as u8)).collect();
+    let events = run_hostile(builder, frames).await;
+    assert!(!limit_exceeded(&events));
+
+    let builder = ProtocolBuilder::new(false).set_max_channels(3);
+    let frames = (1..=4).map(|i| open(i, i as u8)).collect();
+    let events = run_hostile(builder, frames).await;
+    assert!(limit_exceeded(&events));
+}
+
+#[async_std::test]
+async fn too_many_pending_opens() {
+    let builder = ProtocolBuilder::new(false).set_max_pending_opens(2);
+    let frames = (1..=3).map(|i| open(i, i as u8)).collect();
+    let events = run_hostile(builder, frames).await;
+    assert!(limit_exceeded(&events));
+
+    // Opening the same channel again does not count twice.
+    let builder = ProtocolBuilder::new(false).set_max_pending_opens(2);
+    let frames = vec![open(1, 1), open(2, 2), open(3, 2)];
+    let events = run_hostile(builder, frames).await;
+    assert!(!limit_exceeded(&events));
+}
+
+#[async_std::test]
+async fn too_many_extensions() {
+    let extensions = (0..1000).map(|i| format!("ext-{}", i)).collect();
+    let options = Options {
+        extensions,
+        ack: None,
+    };
+    let events = run_hostile(ProtocolBuilder::new(false), vec![frame(0, 1, options)]).await;
+    assert!(limit_exceeded(&events));
+}
+
+#[async_std::test]
+async fn early_messages() -> anyhow::Result<()> {
+    let (reader, mut writer) = sluice::pipe::pipe();
+    let (_remote_reader, local_writer) = sluice::pipe::pipe();
+    let mut proto = ProtocolBuilder::new(false)
+        .set_noise(false)
+        .set_encrypted(false)
+        .set_channel_capacity(3)
+        .connect_rw(reader, local_writer);
+
+    // The remote sends messages right after its open, before the channel is
+    // opened locally.
+    let mut frames = open(1, 1);
+    for i in 0..5 {
+        frames.extend(frame(1, 5, want(i, 1)));
+    }
+    writer.write_all(&frames).await?;
+    let key = [1u8; 32];
+    let event = proto.next().await.unwrap()?;
+    assert_eq!(event, Event::DiscoveryKey(discovery_key(&key)));
+    proto.open(key).await?;
+    let mut channel = loop {
+        if let Event::Channel(channel) = proto.next().await.unwrap()? {
+            break channel;
+        }
+    };
+    task::spawn(async move { while proto.next().await.is_some() {} });
+    writer.write_all(&frame(1, 5, want(10, 1))).await?;
+
+    // Reading paused once the channel capacity was reached, so all messages
+    // are delivered in order.
+    for i in 0..5 {
+        assert_eq!(channel.next().await, Some(Message::Want(want(i, 1))));
+    }
+    assert_eq!(channel.next().await, Some(Message::Want(want(10, 1))));
+    Ok(())
+}
+
+#[async_std::test]
+async fn flood_pending_channel() -> anyhow::Result<()> {
+    let (reader, mut writer) = sluice::pipe::pipe();
+    let (_remote_reader, local_writer) = sluice::pipe::pipe();
+    let mut proto = ProtocolBuilder::new(false)
+        .set_noise(false)
+        .set_encrypted(false)
+        .set_max_pending_bytes(100_000)
+        .connect_rw(reader, local_writer);
+
+    // The remote floods a channel that is never opened locally.
+    let data = |index| Data {
+        index,
+        value: Some(vec![0u8; 10_000].into()),
+        nodes: vec![],
+        signature: None,
+    };
+    let flood = task::spawn(async move {
+        writer.write_all(&open(1, 1)).await?;
+        for index in 0..1000 {
+            writer.write_all(&frame(1, 9, data(index))).await?;
+        }
+        std::io::Result::Ok(writer)
+    });
+
+    let key = [1u8; 32];
+    let event = proto.next().await.unwrap()?;
+    assert_eq!(event, Event::DiscoveryKey(discovery_key(&key)));
+    let drive = async {
+        while let Some(event) = proto.next().await {
+            event?;
+        }
+        Result::Ok(())
+    };
+    let _ = async_std::future::timeout(Duration::from_millis(200), drive).await;
+
+    // Reading paused once the buffered messages reached the limit.
+    let received = proto.stats().received.data.messages;
+    assert!(received <= 10, "received {} messages", received);
+
+    // Opening the channel delivers the buffered messages and reading goes on.
+    proto.open(key).await?;
+    let mut channel = loop {
+        if let Event::Channel(channel) = proto.next().await.unwrap()? {
+            break channel;
+        }
+    };
+    task::spawn(async move { while proto.next().await.is_some() {} });
+    for index in 0..1000 {
+        assert_eq!(channel.next().await, Some(Message::Data(data(index))));
+    }
+    flood.await?;
+    Ok(())
+}
@@ -0,0 +1,495 @@
+#![allow(dead_code, unused_imports)]
+
+use async_std::prelude::*;
+use async_std::task;
+use async_trait::async_trait;
+use hypercore_protocol::bitfield::{Bitfield, MAX_BITFIELD_SIZE};
+use hypercore_protocol::replication::{Feed, Proof, ReplicationOptions, Replicator};
+use hypercore_protocol::schema::{Data, Have, Request, Status, Unhave, Want};
+use hypercore_protocol::{Channel, Error, Message, Result};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod _util;
+use _util::*;
+
+/// A feed that stores blocks in memory. The "signature" of a block is the
+/// reversed block, which is enough to test verification.
+#[derive(Debug, Clone, Default)]
+struct MemoryFeed {
+    blocks: Arc<Mutex<Vec<Option<Vec<u8>>>>>,
+    corrupt: bool,
+    has_calls: Arc<AtomicUsize>,
+}
+
+impl MemoryFeed {
+    fn with_blocks(len: usize) -> Self {
+        let feed = Self::default();
+        for i in 0..len {
+            feed.append(format!("block {}", i).as_bytes());
+        }
+        feed
+    }
+
+    fn append(&self, data: &[u8]) -> u64 {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.push(Some(data.to_vec()));
+        blocks.len() as u64
+    }
+
+    fn set(&self, index: usize, data: &[u8]) {
+        let mut blocks = self.blocks.lock().unwrap();
+        if blocks.len() <= index {
+            blocks.resize(index + 1, None);
+        }
+        blocks[index] = Some(data.to_vec());
+    }
+
+    fn downloaded(&self) -> Vec<u64> {
+        let blocks = self.blocks.lock().unwrap();
+        (0..blocks.len() as u64)
+            .filter(|i| blocks[*i as usize].is_some())
+            .collect()
+    }
+}
+
+fn sign(data: &[u8]) -> Vec<u8> {
+    data.iter().rev().cloned().collect()
+}
+
+#[async_trait]
+impl Feed for MemoryFeed {
+    async fn len(&mut self) -> u64 {
+        self.blocks.lock().unwrap().len() as u64
+    }
+
+    async fn has(&mut self, index: u64) -> bool {
+        self.has_calls.fetch_add(1, Ordering::SeqCst);
+        let blocks = self.blocks.lock().unwrap();
+        matches!(blocks.get(index as usize), Some(Some(_)))
+    }
+
+    async fn has_ranges(&mut self, range: Range<u64>) -> Vec<Range<u64>> {
+        let blocks = self.blocks.lock().unwrap();
+        let end = range.end.min(blocks.len() as u64);
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for index in range.start..end {
+            if blocks[index as usize].is_none() {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == index => last.end = index + 1,
+                _ => ranges.push(index..index + 1),
+            }
+        }
+        ranges
+    }
+
+    async fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>> {
+        let blocks = self.blocks.lock().unwrap();
+        Ok(blocks.get(index as usize).cloned().flatten())
+    }
+
+    async fn proof(&mut self, index: u64) -> Result<Proof> {
+        let data = self.get(index).await?.unwrap_or_default();
+        let signature = if self.corrupt { data } else { sign(&data) };
+        Ok(Proof {
+            nodes: vec![],
+            signature: Some(signature),
+        })
+    }
+
+    async fn put(&mut self, index: u64, data: Option<&[u8]>, proof: Proof) -> Result<()> {
+        let data = data.unwrap_or_default();
+        if proof.signature != Some(sign(data)) {
+            return Err(Error::Feed("Invalid signature".into()));
+        }
+        self.set(index as usize, data);
+        Ok(())
+    }
+}
+
+/// A feed that only answers [`Feed::has_ranges`], with a fixed set of runs.
+struct RunsFeed(Vec<Range<u64>>);
+
+#[async_trait]
+impl Feed for RunsFeed {
+    async fn len(&mut self) -> u64 {
+        self.0.last().map_or(0, |run| run.end)
+    }
+
+    async fn has(&mut self, _index: u64) -> bool {
+        unimplemented!()
+    }
+
+    async fn has_ranges(&mut self, _range: Range<u64>) -> Vec<Range<u64>> {
+        self.0.clone()
+    }
+
+    async fn get(&mut self, _index: u64) -> Result<Option<Vec<u8>>> {
+        unimplemented!()
+    }
+
+    async fn proof(&mut self, _index: u64) -> Result<Proof> {
+        unimplemented!()
+    }
+
+    async fn put(&mut self, _index: u64, _data: Option<&[u8]>, _proof: Proof) -> Result<()> {
+        unimplemented!()
+    }
+}
+
+async fn open_channels() -> anyhow::Result<(Channel, Channel)> {
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [7u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (mut proto_a, channel_a) = next_a.await?;
+    let (mut proto_b, channel_b) = next_b.await?;
+    task::spawn(async move { while let Some(_event) = proto_a.next().await {} });
+    task::spawn(async move { while let Some(_event) = proto_b.next().await {} });
+    Ok((channel_a, channel_b))
+}
+
+#[async_std::test]
+async fn replicate_full() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::with_blocks(40);
+    let feed_b = MemoryFeed::default();
+
+    let task_a = task::spawn(Replicator::new(channel_a, feed_a.clone()).run());
+    let task_b = task::spawn(Replicator::new(channel_b, feed_b.clone()).run());
+    let feed_a = task_a.timeout(Duration::from_secs(5)).await??;
+    let feed_b = task_b.timeout(Duration::from_secs(5)).await??;
+
+    assert_eq!(feed_b.downloaded(), (0..40).collect::<Vec<_>>());
+    assert_eq!(
+        *feed_a.blocks.lock().unwrap(),
+        *feed_b.blocks.lock().unwrap()
+    );
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_both_ways() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::with_blocks(3);
+    let feed_b = MemoryFeed::default();
+    feed_b.set(5, b"only in b");
+
+    let task_a = task::spawn(Replicator::new(channel_a, feed_a.clone()).run());
+    let task_b = task::spawn(Replicator::new(channel_b, feed_b.clone()).run());
+    task_a.timeout(Duration::from_secs(5)).await??;
+    task_b.timeout(Duration::from_secs(5)).await??;
+
+    assert_eq!(feed_a.downloaded(), vec![0, 1, 2, 5]);
+    assert_eq!(feed_b.downloaded(), vec![0, 1, 2, 5]);
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_from_sparse_feed() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::default();
+    for index in &[0, 1, 3, 6, 17] {
+        feed_a.set(*index, format!("block {}", index).as_bytes());
+    }
+    let feed_b = MemoryFeed::default();
+
+    let task_a = task::spawn(Replicator::new(channel_a, feed_a).run());
+    let task_b = task::spawn(Replicator::new(channel_b, feed_b.clone()).run());
+    task_a.timeout(Duration::from_secs(5)).await??;
+    task_b.timeout(Duration::from_secs(5)).await??;
+
+    assert_eq!(feed_b.downloaded(), vec![0, 1, 3, 6, 17]);
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_sparse() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::with_blocks(20);
+    let feed_b = MemoryFeed::default();
+
+    let options = ReplicationOptions {
+        sparse: true,
+        ..Default::default()
+    };
+    let replicator_b = Replicator::with_options(channel_b, feed_b.clone(), options);
+    replicator_b.handle().download(5..8).await?;
+    replicator_b.handle().download(12..13).await?;
+
+    let task_a = task::spawn(Replicator::new(channel_a, feed_a).run());
+    let task_b = task::spawn(replicator_b.run());
+    task_a.timeout(Duration::from_secs(5)).await??;
+    task_b.timeout(Duration::from_secs(5)).await??;
+
+    assert_eq!(feed_b.downloaded(), vec![5, 6, 7, 12]);
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_live() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::with_blocks(2);
+    let feed_b = MemoryFeed::default();
+
+    let options = ReplicationOptions {
+        live: true,
+        ..Default::default()
+    };
+    let replicator_a = Replicator::with_options(channel_a, feed_a.clone(), options.clone());
+    let replicator_b = Replicator::with_options(channel_b, feed_b.clone(), options);
+    let handle_a = replicator_a.handle();
+    let handle_b = replicator_b.handle();
+    let task_a = task::spawn(replicator_a.run());
+    let task_b = task::spawn(replicator_b.run());
+
+    wait_for_len(&feed_b, 2).await?;
+    feed_a.append(b"live 1");
+    let len = feed_a.append(b"live 2");
+    handle_a.announce(2..len).await?;
+    wait_for_len(&feed_b, 4).await?;
+    assert_eq!(feed_b.downloaded(), vec![0, 1, 2, 3]);
+
+    handle_b.close().await?;
+    task_b.timeout(Duration::from_secs(5)).await??;
+    task_a.timeout(Duration::from_secs(5)).await??;
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_invalid_data() -> anyhow::Result<()> {
+    let (channel_a, channel_b) = open_channels().await?;
+    let mut feed_a = MemoryFeed::with_blocks(1);
+    feed_a.corrupt = true;
+
+    task::spawn(Replicator::new(channel_a, feed_a).run());
+    let result = Replicator::new(channel_b, MemoryFeed::default())
+        .run()
+        .timeout(Duration::from_secs(5))
+        .await?;
+    assert!(matches!(result, Err(Error::Feed(_))));
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_answers_want_with_range_query() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    let feed_a = MemoryFeed::with_blocks(10_000);
+    let options = ReplicationOptions {
+        download: false,
+        ..Default::default()
+    };
+    task::spawn(Replicator::with_options(channel_a, feed_a.clone(), options).run());
+
+    channel_b
+        .want(Want {
+            start: 0,
+            length: Some(u64::MAX),
+        })
+        .await?;
+    let haves = next_haves(&mut channel_b).await?;
+    assert_eq!(haves, vec![have(0, 10_000)]);
+    assert_eq!(feed_a.has_calls.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_chunks_haves() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    let max = MAX_BITFIELD_SIZE as u64 * 8;
+    let feed_a = RunsFeed(vec![0..1, 2..3, max..max + 1]);
+    let options = ReplicationOptions {
+        download: false,
+        ..Default::default()
+    };
+    task::spawn(Replicator::with_options(channel_a, feed_a, options).run());
+
+    channel_b
+        .want(Want {
+            start: 0,
+            length: None,
+        })
+        .await?;
+    let haves = next_haves(&mut channel_b).await?;
+    assert_eq!(haves.len(), 2);
+    let runs = Bitfield::decode_ranges(haves[0].bitfield.as_ref().unwrap())?;
+    assert_eq!((haves[0].start, runs), (0, vec![0..1, 2..3]));
+    assert_eq!(haves[1], have(max, 1));
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_splits_large_bitfields() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    // Every eighth block, so the bitfield is a literal byte per eight blocks
+    // and larger than a message.
+    let runs: Vec<_> = (0..5_000_000u64).map(|i| i * 8..i * 8 + 1).collect();
+    let feed_a = RunsFeed(runs.clone());
+    let options = ReplicationOptions {
+        download: false,
+        ..Default::default()
+    };
+    task::spawn(Replicator::with_options(channel_a, feed_a, options).run());
+
+    channel_b
+        .want(Want {
+            start: 0,
+            length: None,
+        })
+        .await?;
+    let haves = next_haves(&mut channel_b).await?;
+    assert!(haves.len() > 1);
+    let mut received = vec![];
+    for have in haves {
+        let start = have.start;
+        let bitfield = have.bitfield.unwrap();
+        assert!(bitfield.len() < 4 * 1024 * 1024);
+        let ranges = Bitfield::decode_ranges(&bitfield)?;
+        received.extend(ranges.into_iter().map(|r| r.start + start..r.end + start));
+    }
+    assert_eq!(received, runs);
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_answers_missing_block_with_unhave() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    task::spawn(Replicator::new(channel_a, MemoryFeed::with_blocks(1)).run());
+
+    channel_b.request(request(5)).await?;
+    let unhave = async {
+        while let Some(message) = channel_b.next().await {
+            if let Message::Unhave(unhave) = message {
+                return Some(unhave);
+            }
+        }
+        None
+    };
+    let unhave = unhave.timeout(Duration::from_secs(5)).await?;
+    assert_eq!(
+        unhave,
+        Some(Unhave {
+            start: 5,
+            length: None
+        })
+    );
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_requests_again_after_timeout() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    let options = ReplicationOptions {
+        request_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let task_a =
+        task::spawn(Replicator::with_options(channel_a, MemoryFeed::default(), options).run());
+
+    channel_b.have(have(0, 1)).await?;
+    channel_b
+        .status(Status {
+            uploading: Some(true),
+            downloading: Some(false),
+        })
+        .await?;
+    let mut requests = 0;
+    while let Some(message) = channel_b.next().timeout(Duration::from_secs(5)).await? {
+        if let Message::Request(msg) = message {
+            assert_eq!(msg, request(0));
+            requests += 1;
+            if requests == 2 {
+                break;
+            }
+        }
+    }
+    assert_eq!(requests, 2);
+
+    // After the remote tells that it does not have the block, a is done.
+    channel_b
+        .unhave(Unhave {
+            start: 0,
+            length: None,
+        })
+        .await?;
+    task_a.timeout(Duration::from_secs(5)).await??;
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_have_out_of_range() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    let task_a = task::spawn(Replicator::new(channel_a, MemoryFeed::default()).run());
+
+    let mut bitfield = Bitfield::new();
+    bitfield.set_range(0..16, true);
+    channel_b.have_bitfield(u64::MAX - 8, &bitfield).await?;
+    let result = task_a.timeout(Duration::from_secs(5)).await?;
+    assert!(matches!(result, Err(Error::Decode(_))));
+    Ok(())
+}
+
+#[async_std::test]
+async fn replicate_data_out_of_range() -> anyhow::Result<()> {
+    let (channel_a, mut channel_b) = open_channels().await?;
+    let task_a = task::spawn(Replicator::new(channel_a, MemoryFeed::default()).run());
+
+    channel_b
+        .data(Data {
+            index: u64::MAX,
+            value: Some(b"last".to_vec().into()),
+            nodes: vec![],
+            signature: None,
+        })
+        .await?;
+    let result = task_a.timeout(Duration::from_secs(5)).await?;
+    assert!(matches!(result, Err(Error::Decode(_))));
+    Ok(())
+}
+
+fn have(start: u64, length: u64) -> Have {
+    Have {
+        start,
+        length: Some(length),
+        bitfield: None,
+        ack: None,
+    }
+}
+
+fn request(index: u64) -> Request {
+    Request {
+        index,
+        bytes: None,
+        hash: None,
+        nodes: None,
+    }
+}
+
+/// Collect the Haves that answer a Want, up to the Status that ends them.
+async fn next_haves(channel: &mut Channel) -> anyhow::Result<Vec<Have>> {
+    let mut haves = vec![];
+    while let Some(message) = channel.next().timeout(Duration::from_secs(5)).await? {
+        match message {
+            Message::Have(have) => haves.push(have),
+            Message::Status(status) if status.uploading.is_some() => break,
+            _ => {}
+        }
+    }
+    Ok(haves)
+}
+
+async fn wait_for_len(feed: &MemoryFeed, len: usize) -> anyhow::Result<()> {
+    let wait = async {
+        while feed.downloaded().len() < len {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    wait.timeout(Duration::from_secs(5)).await?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+#![cfg(feature = "tracing")]
+#![allow(dead_code, unused_imports)]
+
+use async_std::prelude::*;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+mod _util;
+use _util::*;
+
+/// Subscriber that collects the names of spans and the messages of events.
+#[derive(Clone, Default)]
+struct Collector {
+    spans: Arc<Mutex<Vec<String>>>,
+    events: Arc<Mutex<Vec<String>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct MessageVisitor<'a>(&'a mut Option<String>);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            *self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl Subscriber for Collector {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let name = span.metadata().name().to_string();
+        self.spans.lock().unwrap().push(name);
+        Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut message = None;
+        event.record(&mut MessageVisitor(&mut message));
+        if let Some(message) = message {
+            self.events.lock().unwrap().push(message);
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[async_std::test]
+async fn spans_and_events() -> anyhow::Result<()> {
+    let collector = Collector::default();
+    tracing::subscriber::set_global_default(collector.clone())?;
+
+    let (mut proto_a, mut proto_b) = create_pair_memory().await?;
+    let key = [3u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (_proto_a, _channel_a) = next_a.await?;
+    let (_proto_b, _channel_b) = next_b.await?;
+
+    let spans = collector.spans.lock().unwrap().clone();
+    assert_eq!(spans.iter().filter(|name| *name == "protocol").count(), 2);
+    assert_eq!(spans.iter().filter(|name| *name == "channel").count(), 2);
+    let events = collector.events.lock().unwrap().clone();
+    assert_eq!(
+        events.iter().filter(|m| *m == "handshake complete").count(),
+        2
+    );
+    assert_eq!(events.iter().filter(|m| *m == "channel open").count(), 2);
+    Ok(())
+}
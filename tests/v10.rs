@@ -0,0 +1,171 @@
+#![cfg(feature = "v10-unstable")]
+#![allow(dead_code, unused_imports)]
+
+use async_std::prelude::*;
+use async_std::task;
+use futures_lite::io::{AsyncRead, AsyncWrite};
+use hypercore_protocol::schema::*;
+use hypercore_protocol::v10;
+use hypercore_protocol::{
+    discovery_key, CloseReason, Error, Event, Message, Protocol, ProtocolBuilder, Result, Version,
+};
+use std::time::Duration;
+
+mod _util;
+use _util::*;
+
+fn create_pair_versions(a: &[Version], b: &[Version]) -> (MemoryProtocol, MemoryProtocol) {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let a = ProtocolBuilder::new(true)
+        .set_versions(a)
+        .connect_rw(ar, aw);
+    let b = ProtocolBuilder::new(false)
+        .set_versions(b)
+        .connect_rw(br, bw);
+    (a, b)
+}
+
+#[async_std::test]
+async fn v10_channel() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_versions(&[Version::V10], &[Version::V10]);
+    let key = [3u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    assert_eq!(proto_a.version(), Some(Version::V10));
+    assert_eq!(proto_b.version(), Some(Version::V10));
+    assert_eq!(channel_a.version(), Version::V10);
+    assert_eq!(proto_a.remote_public_key(), proto_b.public_key());
+    let drive_b = task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+
+    channel_a.send(v10_want(10, 1)).await?;
+    assert_eq!(channel_b.next().await, Some(v10_want(10, 1)));
+    channel_b.send(v10_want(20, 1)).await?;
+    assert_eq!(channel_a.next().await, Some(v10_want(20, 1)));
+
+    // Messages of Version::V1 cannot be sent.
+    let result = channel_a.want(Want {
+        start: 0,
+        length: None,
+    });
+    assert!(matches!(
+        result.await,
+        Err(Error::UnsupportedMessage(Version::V10))
+    ));
+
+    channel_a.close().await?;
+    assert_eq!(
+        channel_b.next().await,
+        Some(Message::Close(Close::default()))
+    );
+    assert!(channel_b.next().await.is_none());
+    drive_b.await?;
+    Ok(())
+}
+
+#[async_std::test]
+async fn v10_extension() -> anyhow::Result<()> {
+    let (mut proto_a, mut proto_b) = create_pair_versions(&[Version::V10], &[Version::V10]);
+    let key = [4u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    let ext_a = channel_a.register_extension("ext").await;
+    let mut ext_b = channel_b.register_extension("ext").await;
+    ext_a.send(b"hello".to_vec()).await?;
+    assert_eq!(ext_b.next().await.as_deref(), Some(&b"hello"[..]));
+    Ok(())
+}
+
+#[async_std::test]
+async fn v10_reject() -> anyhow::Result<()> {
+    let (mut proto_a, proto_b) = create_pair_versions(&[Version::V10], &[Version::V10]);
+    let key = [5u8; 32];
+    proto_a.open(key).await?;
+
+    let next_a = next_event(proto_a);
+    let next_b = next_event(proto_b);
+    let (proto_a, event_a) = next_a.await;
+    let (proto_b, event_b) = next_b.await;
+    assert!(matches!(event_a, Ok(Event::Handshake(_, Version::V10))));
+    assert!(matches!(event_b, Ok(Event::Handshake(_, Version::V10))));
+
+    let next_a = next_event(proto_a);
+    let (mut proto_b, event_b) = next_event(proto_b).await;
+    assert_eq!(event_b?, Event::DiscoveryKey(discovery_key(&key)));
+    proto_b.reject(discovery_key(&key)).await?;
+    let _next_b = next_event(proto_b);
+
+    let (proto_a, event_a) = next_a.await;
+    assert_eq!(event_a?, Event::Close(discovery_key(&key)));
+    assert_eq!(proto_a.channels().count(), 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn responder_accepts_both_versions() -> anyhow::Result<()> {
+    let both = [Version::V1, Version::V10];
+    for version in [Version::V1, Version::V10].iter() {
+        let (proto_a, proto_b) = create_pair_versions(&[*version], &both);
+        let next_a = next_event(proto_a);
+        let next_b = next_event(proto_b);
+        let (_, event_a) = next_a.await;
+        let (_, event_b) = next_b.await;
+        assert!(matches!(event_a, Ok(Event::Handshake(_, v)) if v == *version));
+        assert!(matches!(event_b, Ok(Event::Handshake(_, v)) if v == *version));
+    }
+    Ok(())
+}
+
+#[async_std::test]
+async fn initiator_speaks_highest_version() -> anyhow::Result<()> {
+    let (proto_a, proto_b) = create_pair_versions(&[Version::V1, Version::V10], &[Version::V1]);
+    let next_a = next_event(proto_a);
+    let (proto_b, event_b) = next_event(proto_b).await;
+    assert!(matches!(event_b, Err(Error::VersionMismatch)));
+    drop(proto_b);
+    // The handshake of the initiator never completes.
+    let (_, event_a) = next_a.await;
+    assert_eq!(event_a?, Event::Closed(CloseReason::RemoteClosed));
+    Ok(())
+}
+
+#[async_std::test]
+async fn v10_without_noise() -> anyhow::Result<()> {
+    let (ar, bw) = sluice::pipe::pipe();
+    let (br, aw) = sluice::pipe::pipe();
+    let mut proto_a = ProtocolBuilder::new(true)
+        .set_versions(&[Version::V10])
+        .set_noise(false)
+        .connect_rw(ar, aw);
+    let mut proto_b = ProtocolBuilder::new(false)
+        .set_versions(&[Version::V10])
+        .set_noise(false)
+        .connect_rw(br, bw);
+    let key = [6u8; 32];
+    proto_a.open(key).await?;
+    proto_b.open(key).await?;
+    let next_a = drive_until_channel(proto_a);
+    let next_b = drive_until_channel(proto_b);
+    let (proto_a, mut channel_a) = next_a.await?;
+    let (proto_b, mut channel_b) = next_b.await?;
+    task::spawn(drive_for(proto_a, Duration::from_secs(1)));
+    task::spawn(drive_for(proto_b, Duration::from_secs(1)));
+
+    channel_a.send(v10_want(1, 1)).await?;
+    assert_eq!(channel_b.next().await, Some(v10_want(1, 1)));
+    channel_b.send(v10_want(2, 1)).await?;
+    assert_eq!(channel_a.next().await, Some(v10_want(2, 1)));
+    Ok(())
+}